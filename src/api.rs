@@ -1,159 +1,287 @@
-use bitvec::order::Msb0;
-use bitvec::vec::BitVec;
 use color_eyre::owo_colors::OwoColorize;
-use log::debug;
+use log::{debug, warn};
 use serde_bencode::de;
 use std::collections::HashMap;
+use std::sync::Mutex;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::stream;
+use tokio::sync::{watch, Notify};
 use url::form_urlencoded;
 
-use crate::model::{PeerHandshake, TorrentSession};
+use crate::connection_manager::ActiveTorrent;
+use crate::disk::TorrentDisk;
+use crate::identity::{load_identity, Identity, IdentitySettings};
+use crate::model::{Peer, PeerHandshake, PeerState, TorrentSession, TransferStats};
 use crate::parser::parse_peer_response;
+use crate::peer_message::{read_message, PeerMessage};
+use crate::piece::{BanList, DownloadState, PieceOutcome, BLOCK_SIZE};
+use crate::rate_limit::Throttle;
+use crate::state::TorrentState;
+use crate::storage::Storage;
+use crate::tracker::TrackerStatus;
 use crate::{
     database::{self, DbConnection},
-    model::{InfoHash, PeerId, Torrent, TrackerAnnounceResponse},
+    model::{InfoHash, PeerId, TrackerAnnounceResponse},
     parser,
 };
+use chrono::Utc;
 use color_eyre::eyre::Result;
 use eyre::{eyre, Ok};
 
-///The call to the announce url is an HTTP request
-pub fn construct_query_map(
-    torrent: &Torrent,
-    peer_id_cache: &mut HashMap<String, String>,
-) -> Result<HashMap<String, String>> {
-    //here we get the peer_id
-    let torrent_file = &torrent.torrent_file;
-    let name = torrent_file
-        .info
-        .name
-        .clone()
-        .unwrap_or("wrongo".to_owned());
-    let peer_id = parser::get_or_create_peer_id(&name, peer_id_cache)?;
-    //we construct a map of param = > value
-    let mut query_params = HashMap::new();
+///How many block requests we keep in flight with a single peer
+const MAX_PENDING_REQUESTS: usize = 5;
 
-    //let torrent_file_name = torrent_file
-    //    .info
-    //    .name
-    //    .clone()
-    //    .unwrap_or("unknown".to_string());
-    query_params.insert("peer_id".to_string(), peer_id);
+///Seconds until we try a tracker again after it failed us
+const TRACKER_RETRY_SECONDS: i64 = 300;
 
-    let size = parser::get_size(torrent_file);
-    //is this true with size always?
-    let left = size - torrent.downloaded;
+///Trackers asking for announces more often than this, or not at all with 0, get this
+const MIN_ANNOUNCE_SECS: i64 = 60;
+///And we come back at least once a day, whatever they say
+const MAX_ANNOUNCE_SECS: i64 = 24 * 60 * 60;
+
+///Seconds until the next regular announce, for the interval a tracker sent
+fn announce_in(interval: usize) -> i64 {
+    i64::try_from(interval)
+        .unwrap_or(MAX_ANNOUNCE_SECS)
+        .clamp(MIN_ANNOUNCE_SECS, MAX_ANNOUNCE_SECS)
+}
+
+///The query params every announce carries
+fn announce_params(
+    peer_id: &str,
+    key: &str,
+    downloaded: u64,
+    uploaded: u64,
+    left: u64,
+) -> HashMap<String, String> {
+    //we construct a map of param = > value
+    let mut query_params = HashMap::new();
+    query_params.insert("peer_id".to_string(), peer_id.to_string());
+    query_params.insert("key".to_string(), key.to_string());
     //TODO get this from config
     query_params.insert("port".to_string(), "6881".to_string());
-    //TODO this needs to come from DB
-    query_params.insert("downloaded".to_string(), torrent.downloaded.to_string());
-    query_params.insert("uploaded".to_string(), torrent.uploaded.to_string());
+    query_params.insert("downloaded".to_string(), downloaded.to_string());
+    query_params.insert("uploaded".to_string(), uploaded.to_string());
     query_params.insert("left".to_string(), left.to_string());
-
-    Ok(query_params)
+    query_params
 }
 
-pub async fn init_peer_torrent_sessions(
+///Parse and save the torrent files, and give each the peer id and key it had last time,
+///or new ones as the settings say. Nothing is announced yet, so the sessions come back without peers
+pub fn load_torrent_sessions(
     torrent_files: &Vec<String>,
+    identity: &IdentitySettings,
     db: &DbConnection,
 ) -> Result<Vec<TorrentSession>> {
-    //log_init_for_tests::init_logging();
-    let mut peer_id_cache: HashMap<String, String> = HashMap::new();
-    let client = reqwest::Client::new();
     debug!("Going to loop through files: {:?}", torrent_files);
-    //TODO make this a tui and such
-    //once we get the loading of the down working
     let mut torrents: Vec<TorrentSession> = Vec::new();
     for torrent_file_path in torrent_files {
-        let torrent = parser::parse_torrent_file(&torrent_file_path)?;
+        let torrent = parser::parse_torrent_file(torrent_file_path)?;
         database::save_torrent_file(&torrent, db)?;
-        let announce_url = torrent
-            .torrent_file
-            .announce
-            .clone()
-            .ok_or_else(|| eyre!("Did not find the announce url".to_owned()))?;
-        debug!("announce url: {announce_url}");
-
-        let info_hash = &torrent.torrent_file.info_hash;
-        let encoded_info_hash: String = form_urlencoded::byte_serialize(info_hash).collect();
-
-        let query_map = construct_query_map(&torrent, &mut peer_id_cache)?;
-        let peer_id_str = query_map.get("peer_id").ok_or_else(|| {
-            eyre!("Expected peer_id to be assigned in the query_map by now".to_string())
-        })?;
-        let peer_id_bytes = peer_id_str.as_bytes();
-        if peer_id_bytes.len() != 20 {
-            return Err(eyre!("Peer Id must be exactly 20 bytes long"));
-        }
-        let mut peer_id = [0u8; 20];
-        peer_id.copy_from_slice(peer_id_bytes);
-        let encoded_params = serde_urlencoded::to_string(&query_map)?;
-        //create our request
-        let full_announce_url = format!(
-            "{}?{}&info_hash={}",
-            announce_url,
-            encoded_params.clone(),
-            encoded_info_hash,
-        );
-        debug!("full_announce_url={full_announce_url}");
-        let response = client
-            .get(full_announce_url)
-            .send()
-            .await?
-            .error_for_status()?;
-        debug!("Our response: {:?}", response);
-
-        let http_status = response.status();
-        if http_status.is_server_error() {
-            let body = response.text().await?;
-            let err = eyre!(
-                "Server error, {}, with message {}",
-                http_status.to_string(),
-                body
-            );
-            return Err(err);
-        } else if http_status.is_client_error() {
-            let body = response.text().await?;
-            let err = eyre!(
-                "Client error, {}, with message {}",
-                http_status.to_string(),
-                body
-            );
-            return Err(err);
-        }
-        //debug!("Our response text: {}", body);
+        let Identity { peer_id, key } =
+            load_identity(&torrent.torrent_file.info_hash, identity, db)?;
+        torrents.push(TorrentSession {
+            peer_id,
+            key,
+            torrent,
+        });
+    }
+    Ok(torrents)
+}
 
-        let body_bytes = response.bytes().await?;
-        let response: TrackerAnnounceResponse = de::from_bytes(&body_bytes)?;
-        //if we get to here it was successful
-        response
-            .peers
+///Every torrent in the db that isn't stopped or already loaded, so a session carries on
+///with what the last one had. Rows we can't read are reported and left out
+pub fn restore_torrent_sessions(
+    loaded: &[TorrentSession],
+    identity: &IdentitySettings,
+    db: &DbConnection,
+) -> Result<Vec<TorrentSession>> {
+    let list = database::list_torrent_files(db)?;
+    for row in &list.unreadable {
+        warn!(
+            "Could not restore torrent row {} ({}): {}",
+            row.id,
+            row.name.as_deref().unwrap_or("no name"),
+            row.error
+        );
+    }
+    let mut torrents = Vec::new();
+    for torrent in list.torrents {
+        let info_hash = torrent.torrent_file.info_hash;
+        if loaded
             .iter()
-            .for_each(|peer| debug!("Peer! {}", peer));
-        let ts = TorrentSession {
+            .any(|ts| ts.torrent.torrent_file.info_hash == info_hash)
+        {
+            continue;
+        }
+        let (state, _) = database::select_state(&info_hash, db)?;
+        let resumed =
+            database::select_requested_state(&info_hash, db)? == Some(TorrentState::Queued);
+        if state == TorrentState::Stopped && !resumed {
+            continue;
+        }
+        debug!("Restoring {}, it was {state}", torrent.info_name());
+        let Identity { peer_id, key } = load_identity(&info_hash, identity, db)?;
+        torrents.push(TorrentSession {
             peer_id,
-            peers: response.peers,
+            key,
             torrent,
-        };
-        torrents.push(ts);
+        });
     }
     Ok(torrents)
 }
 
-///Connect to a peer and return its possible handshake
-pub async fn connect_and_send_handshake(
-    peer_ip: &str,
-    peer_port: u16,
-    info_hash: &[u8; 20],
-    peer_id: &[u8; 20],
-) -> Result<PeerHandshake> {
-    debug!("connect_and_send_handshake firing...");
+///Make the announce GET request, returning the raw bencoded body
+pub async fn send_announce(
+    client: &reqwest::Client,
+    announce_url: &str,
+    info_hash: &InfoHash,
+    query_map: &HashMap<String, String>,
+) -> Result<Vec<u8>> {
+    let encoded_info_hash: String = form_urlencoded::byte_serialize(info_hash).collect();
+    let encoded_params = serde_urlencoded::to_string(query_map)?;
+    //create our request
+    let full_announce_url = format!(
+        "{}?{}&info_hash={}",
+        announce_url, encoded_params, encoded_info_hash,
+    );
+    debug!("full_announce_url={full_announce_url}");
+    let response = client
+        .get(full_announce_url)
+        .send()
+        .await?
+        .error_for_status()?;
+    debug!("Our response: {:?}", response);
+
+    let http_status = response.status();
+    if http_status.is_server_error() {
+        let body = response.text().await?;
+        let err = eyre!(
+            "Server error, {}, with message {}",
+            http_status.to_string(),
+            body
+        );
+        return Err(err);
+    } else if http_status.is_client_error() {
+        let body = response.text().await?;
+        let err = eyre!(
+            "Client error, {}, with message {}",
+            http_status.to_string(),
+            body
+        );
+        return Err(err);
+    }
+    //debug!("Our response text: {}", body);
+
+    let body_bytes = response.bytes().await?;
+    Ok(body_bytes.to_vec())
+}
+
+///Tell the tracker we are joining the swarm, and get the peers to dial
+pub async fn announce_started(
+    client: &reqwest::Client,
+    torrent: &ActiveTorrent,
+    db: &DbConnection,
+) -> Result<Vec<Peer>> {
+    let response = announce_event(client, torrent, Some("started"), db).await?;
+    Ok(response.peers)
+}
+
+///The regular announce the tracker asks for every interval, while we stay in the swarm
+pub async fn announce_regular(
+    client: &reqwest::Client,
+    torrent: &ActiveTorrent,
+    db: &DbConnection,
+) -> Result<Vec<Peer>> {
+    let response = announce_event(client, torrent, None, db).await?;
+    Ok(response.peers)
+}
+
+///Tell the tracker we are leaving the swarm. We do not care what it answers, as long as it is not an error
+pub async fn announce_stopped(
+    client: &reqwest::Client,
+    torrent: &ActiveTorrent,
+    db: &DbConnection,
+) -> Result<()> {
+    announce_event(client, torrent, Some("stopped"), db).await?;
+    Ok(())
+}
+
+///Announce and note down in the db how it went, whichever way it went
+async fn announce_event(
+    client: &reqwest::Client,
+    torrent: &ActiveTorrent,
+    event: Option<&str>,
+    db: &DbConnection,
+) -> Result<TrackerAnnounceResponse> {
+    let mut query_params = announce_params(
+        &String::from_utf8_lossy(&torrent.peer_id),
+        &torrent.key,
+        torrent.stats.downloaded(),
+        torrent.stats.uploaded(),
+        torrent.left(),
+    );
+    if let Some(event) = event {
+        query_params.insert("event".to_string(), event.to_string());
+    }
+    let now = Utc::now().timestamp();
+    let mut status = TrackerStatus {
+        url: torrent.announce_url.clone(),
+        last_announce: Some(now),
+        ..TrackerStatus::default()
+    };
+    let response = send_announce(
+        client,
+        &torrent.announce_url,
+        &torrent.info_hash,
+        &query_params,
+    )
+    .await
+    .and_then(|body_bytes| {
+        let response: TrackerAnnounceResponse = de::from_bytes(&body_bytes)?;
+        match &response.failure_reason {
+            Some(reason) => Err(eyre!("Tracker refused the announce: {reason}")),
+            None => Ok(response),
+        }
+    });
+    //eyre's Ok is a function here, so it can't be matched on
+    let next_in = match &response {
+        Result::Ok(response) => {
+            status.peers = Some(response.peers.len() as u32);
+            status.seeders = response.complete;
+            status.leechers = response.incomplete;
+            announce_in(response.interval)
+        }
+        Err(e) => {
+            status.last_error = Some(e.to_string());
+            TRACKER_RETRY_SECONDS
+        }
+    };
+    //once stopped the tracker doesn't expect us back
+    if event != Some("stopped") {
+        status.next_announce = Some(now + next_in);
+    }
+    //the peers are worth more than the status line
+    if let Err(e) = database::save_tracker_status(&torrent.info_hash, &status, db) {
+        warn!("Could not save the tracker status of {}: {e}", torrent.name);
+    }
+    response
+}
+
+///Open the tcp connection to a peer, nothing more
+pub async fn connect_to_peer(peer_ip: &str, peer_port: u16) -> Result<TcpStream> {
     let addr = format!("{}:{}", peer_ip, peer_port);
     debug!("Connecting to {addr}.....!!{}", "!!".bold().bright_blue());
-    let mut stream = TcpStream::connect(addr).await?;
+    let stream = TcpStream::connect(addr).await?;
+    Ok(stream)
+}
 
+///Send our handshake down an open connection and read back the peer's
+pub async fn send_handshake(
+    stream: &mut TcpStream,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+) -> Result<PeerHandshake> {
     // Build the handshake
     let handshake = build_handshake(info_hash, peer_id);
 
@@ -163,11 +291,8 @@ pub async fn connect_and_send_handshake(
     // Read the peer's handshake response (68 bytes)
     let mut response = [0u8; 68];
     stream.read_exact(&mut response).await?;
-    if let Some(peer_handshake) = parse_peer_response(&response) {
-        return Ok(peer_handshake);
-    } else {
-        return Err(eyre!("Failed to parse a peer response"));
-    };
+    let peer_handshake = parse_peer_response(&response, info_hash, peer_id)?;
+    Ok(peer_handshake)
 }
 
 pub fn build_handshake(info_hash: &InfoHash, peer_id: &PeerId) -> [u8; 68] {
@@ -184,14 +309,246 @@ pub fn build_handshake(info_hash: &InfoHash, peer_id: &PeerId) -> [u8; 68] {
     handshake
 }
 
+///What a peer connection shares with the rest of its torrent, and with the session
+pub struct PeerContext<'a> {
+    pub download: &'a Mutex<DownloadState>,
+    pub bans: &'a Mutex<BanList>,
+    pub storage: &'a Storage,
+    pub disk: &'a TorrentDisk,
+    pub throttle: Throttle<'a>,
+    pub stats: &'a TransferStats,
+    ///Just what moved with this one peer
+    pub peer_stats: &'a TransferStats,
+    ///Flips to true when the torrent is stopped or paused
+    pub stopped: &'a watch::Sender<bool>,
+    ///Poked once a verified piece is on disk
+    pub verified: &'a Notify,
+}
+
+///Trade pieces with a peer we have handshaken with, until neither of us wants anything or we fall out.
+///Every block is recorded against the peer that sent it, so hash failures can be pinned on someone
 pub async fn peer_loop(
     mut stream: TcpStream,
-    peer_bitfield: BitVec<u8, Msb0>,
-    local_bitfield: &mut BitVec<u8, Msb0>,
-    pieces: &[PieceMetadata],
-    info_hash: &InfoHash,
-    peer_id: &PeerId,
+    peer_addr: &str,
+    ctx: &PeerContext<'_>,
 ) -> Result<()> {
+    let num_pieces = lock(ctx.download)?.pieces.len();
+    let mut peer_state = PeerState::new(num_pieces);
+    let result = talk_to_peer(&mut stream, peer_addr, ctx, &mut peer_state).await;
+    let mut download = lock(ctx.download)?;
+    //whatever we were halfway through, someone else can finish
+    download.release_peer(peer_addr);
+    download.remove_availability(&peer_state.peer_bitfield);
+    result
+}
+
+async fn talk_to_peer(
+    stream: &mut TcpStream,
+    peer_addr: &str,
+    ctx: &PeerContext<'_>,
+    peer_state: &mut PeerState,
+) -> Result<()> {
+    let PeerContext {
+        download,
+        bans,
+        storage,
+        disk,
+        throttle,
+        stats,
+        peer_stats,
+        stopped,
+        verified,
+    } = ctx;
+    let mut stopped = stopped.subscribe();
+    let mut our_bitfield = lock(download)?.have.clone();
+    //pieces that spill into a skipped file are only partly on disk, nothing we can hand out
+    for index in our_bitfield.iter_ones().collect::<Vec<_>>() {
+        if !storage.is_whole_on_disk(index as u32) {
+            our_bitfield.set(index, false);
+        }
+    }
+    if our_bitfield.any() {
+        let bitfield = PeerMessage::Bitfield(our_bitfield.into_vec());
+        send_message(stream, &bitfield, throttle).await?;
+    }
+
+    let mut current_piece: Option<u32> = None;
+    //begin offsets of the blocks we have asked for and not yet received
+    let mut pending: Vec<u32> = Vec::new();
+    loop {
+        if lock(bans)?.is_banned(peer_addr) {
+            return Err(eyre!("Peer {peer_addr} is banned, disconnecting"));
+        }
+        let complete = lock(download)?.is_complete();
+        if complete && peer_state.is_seed() {
+            debug!("We are both seeds, done with {peer_addr}");
+            return Ok(());
+        }
+        //only interested while there is something left to get
+        if complete == peer_state.is_interested {
+            let interest = if complete {
+                PeerMessage::NotInterested
+            } else {
+                PeerMessage::Interested
+            };
+            send_message(stream, &interest, throttle).await?;
+            peer_state.is_interested = !complete;
+        }
+
+        if !complete && !peer_state.is_choked {
+            let requests = {
+                let mut state = lock(download)?;
+                if current_piece.is_none() {
+                    current_piece = state.pick_piece(peer_addr, &peer_state.peer_bitfield);
+                    pending.clear();
+                }
+                match current_piece {
+                    Some(index) => state
+                        .missing_blocks(index)
+                        .into_iter()
+                        .filter(|(begin, _)| !pending.contains(begin))
+                        .take(MAX_PENDING_REQUESTS.saturating_sub(pending.len()))
+                        .map(|(begin, length)| (index, begin, length))
+                        .collect(),
+                    None => Vec::new(),
+                }
+            };
+            for (index, begin, length) in requests {
+                let request = PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                };
+                send_message(stream, &request, throttle).await?;
+                pending.push(begin);
+            }
+        }
+
+        let message = tokio::select! {
+            message = read_message(stream) => message?,
+            _ = async {
+                let _ = stopped.wait_for(|s| *s).await;
+            } => {
+                debug!("Torrent stopped, hanging up on {peer_addr}");
+                return Ok(());
+            }
+        };
+        match message {
+            PeerMessage::Choke => {
+                peer_state.is_choked = true;
+                lock(download)?.release_peer(peer_addr);
+                current_piece = None;
+                pending.clear();
+            }
+            PeerMessage::Unchoke => peer_state.is_choked = false,
+            PeerMessage::Interested => {
+                peer_state.peer_interested = true;
+                //no choking algorithm (yet), everyone who asks gets served
+                if peer_state.peer_choked {
+                    send_message(stream, &PeerMessage::Unchoke, throttle).await?;
+                    peer_state.peer_choked = false;
+                }
+            }
+            PeerMessage::NotInterested => {
+                peer_state.peer_interested = false;
+                if complete {
+                    debug!("{peer_addr} wants nothing from us, and we want nothing from it");
+                    return Ok(());
+                }
+            }
+            PeerMessage::Have(index) => {
+                if !peer_state.has_piece(index as usize) {
+                    peer_state.update_have(index as usize);
+                    lock(download)?.add_have(index as usize);
+                }
+            }
+            PeerMessage::Bitfield(bits) => {
+                let mut state = lock(download)?;
+                state.remove_availability(&peer_state.peer_bitfield);
+                peer_state.update_bitfield(&bits);
+                state.add_availability(&peer_state.peer_bitfield);
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let have_piece = lock(download)?.have.get(index as usize).is_some_and(|b| *b)
+                    && storage.is_whole_on_disk(index);
+                if peer_state.peer_choked || !have_piece || length > BLOCK_SIZE {
+                    debug!("Not serving piece {index} at {begin} to {peer_addr}");
+                    continue;
+                }
+                let block = disk.read(index, begin, length).await?;
+                let piece = PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                };
+                send_message(stream, &piece, throttle).await?;
+                stats.add_uploaded(length as u64);
+                peer_stats.add_uploaded(length as u64);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                throttle.download(block.len()).await;
+                stats.add_downloaded(block.len() as u64);
+                peer_stats.add_downloaded(block.len() as u64);
+                pending.retain(|b| *b != begin);
+                let Some(data) = lock(download)?.store_block(index, begin, &block, peer_addr)?
+                else {
+                    continue;
+                };
+                //hashed on the disk threads, the other peers carry on meanwhile
+                let expected = lock(download)?
+                    .expected_hash(index)
+                    .ok_or_else(|| eyre!("No hash for piece {index}"))?;
+                let (data, hash_ok) = disk.verify(data, expected).await?;
+                let outcome = lock(download)?.finish_piece(index, data, hash_ok)?;
+                match outcome {
+                    PieceOutcome::Verified { index, data } => {
+                        disk.write_piece(index, data).await?;
+                        verified.notify_waiters();
+                        current_piece = None;
+                    }
+                    PieceOutcome::HashFailed {
+                        index,
+                        contributors,
+                        wasted,
+                    } => {
+                        let newly_banned = lock(bans)?.record_hash_failure(&contributors);
+                        warn!(
+                            "Threw away {wasted} bytes of piece {index}, banned {:?}",
+                            newly_banned
+                        );
+                        current_piece = None;
+                    }
+                }
+            }
+            other => debug!("Ignoring {:?} from {peer_addr}", other),
+        }
+    }
+}
+
+///Write a message to the peer, once the upload limits let us
+async fn send_message(
+    stream: &mut TcpStream,
+    message: &PeerMessage,
+    throttle: &Throttle<'_>,
+) -> Result<()> {
+    let bytes = message.to_bytes();
+    throttle.upload(bytes.len()).await;
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|e| eyre!("Shared download state poisoned: {}", e))
 }
 
 #[cfg(test)]
@@ -199,11 +556,69 @@ mod test {
     use rand::Rng;
 
     use super::*;
+
+    //the session announces on its own, only these tests still ask the tracker for peers up front
+    async fn init_peer_torrent_sessions(
+        torrent_files: &Vec<String>,
+        identity: &IdentitySettings,
+        db: &DbConnection,
+    ) -> Result<Vec<(TorrentSession, Vec<Peer>)>> {
+        let client = reqwest::Client::new();
+        //TODO make this a tui and such
+        //once we get the loading of the down working
+        let mut torrents = Vec::new();
+        for ts in load_torrent_sessions(torrent_files, identity, db)? {
+            let torrent = &ts.torrent;
+            let announce_url = torrent
+                .torrent_file
+                .announce
+                .clone()
+                .ok_or_else(|| eyre!("Did not find the announce url".to_owned()))?;
+            debug!("announce url: {announce_url}");
+            let left = parser::get_size(&torrent.torrent_file).saturating_sub(torrent.downloaded);
+            let query_map = announce_params(
+                &String::from_utf8_lossy(&ts.peer_id),
+                &ts.key,
+                torrent.downloaded,
+                torrent.uploaded,
+                left,
+            );
+            let body_bytes = send_announce(
+                &client,
+                &announce_url,
+                &torrent.torrent_file.info_hash,
+                &query_map,
+            )
+            .await?;
+            let response: TrackerAnnounceResponse = de::from_bytes(&body_bytes)?;
+            //if we get to here it was successful
+            response
+                .peers
+                .iter()
+                .for_each(|peer| debug!("Peer! {}", peer));
+            torrents.push((ts, response.peers));
+        }
+        Ok(torrents)
+    }
+
+    ///Connect to a peer and return its possible handshake, along with the stream to keep talking on
+    async fn connect_and_send_handshake(
+        peer_ip: &str,
+        peer_port: u16,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+    ) -> Result<(TcpStream, PeerHandshake)> {
+        debug!("connect_and_send_handshake firing...");
+        let mut stream = connect_to_peer(peer_ip, peer_port).await?;
+        let peer_handshake = send_handshake(&mut stream, info_hash, peer_id).await?;
+        Ok((stream, peer_handshake))
+    }
+
     #[tokio::test]
     async fn test_get_peer_list() {
         let torrent_files = vec!["./Fedora-KDE-Live-x86_64-40.torrent".to_string()];
         let db = database::test::init_test_conn();
-        init_peer_torrent_sessions(&torrent_files, &db)
+        init_peer_torrent_sessions(&torrent_files, &IdentitySettings::default(), &db)
             .await
             .unwrap();
     }
@@ -212,16 +627,17 @@ mod test {
     async fn test_connect_and_send_handshake() {
         let torrent_files = vec!["./Fedora-KDE-Live-x86_64-40.torrent".to_string()];
         let db = database::test::init_test_conn();
-        let torrent_sessions = init_peer_torrent_sessions(&torrent_files, &db)
-            .await
-            .unwrap();
+        let torrent_sessions =
+            init_peer_torrent_sessions(&torrent_files, &IdentitySettings::default(), &db)
+                .await
+                .unwrap();
         //pick a random element
-        for torrent_session in torrent_sessions {
-            let peers = torrent_session.peers;
+        for (torrent_session, peers) in torrent_sessions {
             let rand_idx = rand::rng().random_range(0..peers.len());
             if let Some(rand_peer) = peers.get(rand_idx) {
                 debug!("{:?}", rand_peer);
-                let peer_handshake = connect_and_send_handshake(
+                //a reply for some other torrent fails the handshake
+                let (_stream, peer_handshake) = connect_and_send_handshake(
                     &rand_peer.ip,
                     rand_peer.port,
                     &torrent_session.torrent.torrent_file.info_hash,
@@ -229,14 +645,17 @@ mod test {
                 )
                 .await
                 .unwrap();
-                assert_eq!(
-                    peer_handshake.info_hash,
-                    torrent_session.torrent.torrent_file.info_hash
-                );
                 debug!("{:?}", peer_handshake);
             } else {
                 panic!("Could find test peer for some reason");
             }
         }
     }
+
+    #[test]
+    fn test_announce_in() {
+        assert_eq!(MIN_ANNOUNCE_SECS, announce_in(0));
+        assert_eq!(1800, announce_in(1800));
+        assert_eq!(MAX_ANNOUNCE_SECS, announce_in(usize::MAX));
+    }
 }
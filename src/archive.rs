@@ -0,0 +1,155 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+///Bumped when an older torrentox could no longer make sense of the archive
+pub const ARCHIVE_VERSION: u32 = 1;
+
+///Every torrent of a db, to take to another machine. Bencoded, like the torrents in it,
+///so the raw torrent bytes go in as they are
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    ///Unix seconds
+    pub exported_at: i64,
+    ///In queue order, so importing them one by one keeps it
+    pub torrents: Vec<ArchivedTorrent>,
+}
+
+///One torrent, with what it had got to and how it was set up.
+///Bencode has no floats, so the seed ratio goes in as text
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedTorrent {
+    pub name: String,
+    ///The .torrent file, as it was added
+    #[serde(with = "serde_bytes")]
+    pub torrent: Vec<u8>,
+    pub file_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub save_path: Option<String>,
+    pub state: String,
+    ///Verified pieces, one bit each, empty if we never saved any
+    #[serde(default, with = "serde_bytes")]
+    pub have: Vec<u8>,
+    ///One per line
+    #[serde(default)]
+    pub trackers: String,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    ///Seconds
+    pub seeding_time: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_ratio_limit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_time_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_idle_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed_limit_action: Option<String>,
+    #[serde(with = "bencode_bool")]
+    pub sequential: bool,
+    pub files: Vec<ArchivedFile>,
+}
+
+///The settings of one file of a torrent, matched up by path on import
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    pub path: String,
+    pub priority: String,
+    #[serde(with = "bencode_bool")]
+    pub sequential: bool,
+}
+
+///What happened to an archived torrent on import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Added,
+    ///Already there, it only picked up what it was missing
+    Merged,
+    ///Already there, left alone
+    Skipped,
+}
+
+///Bencode has no booleans either. They go out as 0 or 1, but don't come back as bools
+mod bencode_bool {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(*value as i64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        Ok(i64::deserialize(deserializer)? != 0)
+    }
+}
+
+impl Archive {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).wrap_err("Failed to encode the archive")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Archive> {
+        let archive: Archive =
+            serde_bencode::from_bytes(bytes).wrap_err("Not a torrentox archive")?;
+        if archive.version > ARCHIVE_VERSION {
+            return Err(eyre!(
+                "The archive is version {}, this torrentox reads up to {ARCHIVE_VERSION}",
+                archive.version
+            ));
+        }
+        Ok(archive)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes()?)
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Archive> {
+        let bytes =
+            fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        Archive::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let archive = Archive {
+            version: ARCHIVE_VERSION,
+            exported_at: 100,
+            torrents: vec![ArchivedTorrent {
+                name: "foom".to_owned(),
+                torrent: b"d8:announce3:urle".to_vec(),
+                have: vec![0b1010_0000],
+                seed_ratio_limit: Some("1.5".to_owned()),
+                sequential: true,
+                files: vec![ArchivedFile {
+                    path: "foom/a".to_owned(),
+                    priority: "high".to_owned(),
+                    sequential: false,
+                }],
+                ..ArchivedTorrent::default()
+            }],
+        };
+        assert_eq!(
+            archive,
+            Archive::from_bytes(&archive.to_bytes().unwrap()).unwrap()
+        );
+
+        let newer = Archive {
+            version: ARCHIVE_VERSION + 1,
+            ..Archive::default()
+        };
+        assert!(Archive::from_bytes(&newer.to_bytes().unwrap()).is_err());
+        assert!(Archive::from_bytes(b"not bencode").is_err());
+    }
+}
@@ -1,11 +1,191 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::queue::QueueMove;
+use crate::storage::AllocationMode;
 ///CLI arguments we can pass the application
 #[derive(Debug, Parser)]
-#[command(version, about, long_about = "CLI rust based torrent TUI")]
+#[command(
+    version,
+    about,
+    long_about = "CLI rust based torrent TUI",
+    args_conflicts_with_subcommands = true
+)]
 pub struct AppArgs {
     ///List of arguments we can give
     //the reviled java programmer
     pub torrent_files: Vec<String>,
     #[arg(short, long)]
     pub verbose: bool,
+    ///Config file, see config.rs for what goes in it.
+    ///Defaults to torrentox/torrentox.toml in $XDG_CONFIG_HOME
+    #[arg(short, long, global = true, env = "TORRENTOX_CONFIG")]
+    pub config: Option<PathBuf>,
+    ///Where the db lives. Defaults to torrentox in $XDG_DATA_HOME
+    #[arg(long, global = true, env = "TORRENTOX_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    ///Defaults to torrentox/torrentox.log in $XDG_STATE_HOME
+    #[arg(long, global = true, env = "TORRENTOX_LOG")]
+    pub log_file: Option<PathBuf>,
+    ///Where downloaded files are written
+    #[arg(short, long, default_value = ".")]
+    pub download_dir: String,
+    ///Remember peers banned for sending bad data, and keep them banned in later sessions
+    #[arg(long)]
+    pub persist_bans: bool,
+    ///Most peers we connect to for a single torrent
+    #[arg(long, default_value_t = 30)]
+    pub max_peers_per_torrent: usize,
+    ///Most peers we connect to across all torrents
+    #[arg(long, default_value_t = 100)]
+    pub max_peers: usize,
+    ///Download limit across all torrents, in KiB/s, 0 for unlimited. Overrides the config,
+    ///and is saved like the global-limit command
+    #[arg(long)]
+    pub max_download_rate: Option<u64>,
+    ///Upload limit across all torrents, in KiB/s, 0 for unlimited. Overrides the config,
+    ///and is saved like the global-limit command
+    #[arg(long)]
+    pub max_upload_rate: Option<u64>,
+    ///Serve the files of the torrents over HTTP on this port of 127.0.0.1, as they download.
+    ///Overrides the config
+    #[arg(long)]
+    pub stream_port: Option<u16>,
+    ///Sparse files, or claim all the space before downloading. Overrides the config
+    #[arg(long, value_enum)]
+    pub allocation: Option<AllocationMode>,
+    ///Download into here, moving files to the download dir when complete. Overrides the config
+    #[arg(long)]
+    pub incomplete_dir: Option<PathBuf>,
+    ///Put .part on the end of files in the incomplete dir
+    #[arg(long)]
+    pub part_suffix: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+///Things we can do to the torrents in the db, instead of downloading
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    ///Set the rate limits of a torrent, in KiB/s, 0 for unlimited. Applies to a running torrentox too
+    Limit {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        #[arg(long)]
+        download: Option<u64>,
+        #[arg(long)]
+        upload: Option<u64>,
+    },
+    ///Set the rate limits across all torrents, in KiB/s, 0 for unlimited. They win over the config,
+    ///and apply to a running torrentox too. The alternative speed schedule still takes over when it is on
+    GlobalLimit {
+        #[arg(long)]
+        download: Option<u64>,
+        #[arg(long)]
+        upload: Option<u64>,
+    },
+    ///Set when a torrent is done seeding. Anything left out comes from the [seeding] section of the config
+    SeedPolicy {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        ///Stop once uploaded / downloaded reaches this
+        #[arg(long)]
+        ratio: Option<f64>,
+        ///Stop after seeding this many minutes
+        #[arg(long)]
+        seeding_minutes: Option<u64>,
+        ///Stop after this many minutes without any transfer
+        #[arg(long)]
+        idle_minutes: Option<u64>,
+        ///stop or pause
+        #[arg(long)]
+        action: Option<String>,
+    },
+    ///Show the queue, or move a torrent in it. Applies to a running torrentox too
+    Queue {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        #[arg(requires = "direction")]
+        name: Option<String>,
+        #[arg(value_enum)]
+        direction: Option<QueueMove>,
+    },
+    ///List the files of a torrent, or set the priority of one. Takes effect the next time the torrent starts
+    Files {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        ///Number of the file, as listed
+        #[arg(requires = "priority")]
+        file: Option<usize>,
+        ///skip, normal or high
+        priority: Option<String>,
+    },
+    ///Download a torrent, or one file of it, front to back. Takes effect the next time the torrent starts
+    Sequential {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        ///Number of the file, as listed by files. Leave out for the whole torrent
+        #[arg(long)]
+        file: Option<usize>,
+        ///Turn it back off
+        #[arg(long)]
+        off: bool,
+    },
+    ///Move the files of a torrent somewhere else. A running session picks this up on its next
+    ///tick and carries on from the new place, otherwise it happens the next time we run
+    Move {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        ///The new directory, which takes the place of the download dir for this torrent
+        dir: PathBuf,
+    },
+    ///Sum up the transfer history of the last few hours, for one torrent or all of them
+    History {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash. Leave out for every torrent
+        name: Option<String>,
+        ///How far back to look
+        #[arg(long, default_value_t = 24)]
+        hours: u64,
+    },
+    ///Show how each tracker of a torrent answered its last announce
+    Trackers {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+    },
+    ///Write every torrent, with its progress and settings, to an archive for another machine
+    Export {
+        ///Where the archive goes
+        path: PathBuf,
+    },
+    ///Bring the torrents of an archive into this db. Ones already here are skipped
+    Import {
+        ///The archive, as written by export
+        path: PathBuf,
+        ///Have torrents already here pick up the trackers, settings and progress they are missing
+        #[arg(long)]
+        merge: bool,
+    },
+    ///Pause a torrent until it is resumed. A running session picks this up on its next tick
+    Pause {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+    },
+    ///Put a paused, stopped or errored torrent back in the queue
+    Resume {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+    },
+    ///Leave the swarm for good. A stopped torrent isn't loaded again until it is resumed
+    Stop {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+    },
+    ///Hash what is on disk to find out which pieces we really have. A running session picks
+    ///this up on its next tick, otherwise it happens the next time we run
+    Recheck {
+        ///Name of the torrent, as given in its info dictionary, or its info hash when names clash
+        name: String,
+        ///Stop a recheck that was asked for, or is under way
+        #[arg(long)]
+        cancel: bool,
+    },
 }
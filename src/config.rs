@@ -0,0 +1,206 @@
+use color_eyre::eyre::{Result, WrapErr};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::disk::DiskSettings;
+use crate::history::HistorySettings;
+use crate::identity::IdentitySettings;
+use crate::peer_cache::PeerCacheSettings;
+use crate::piece::DEFAULT_READAHEAD;
+use crate::queue::QueueLimits;
+use crate::schedule::AltSpeedSchedule;
+use crate::seeding::SeedingPolicy;
+use crate::storage::{AllocationMode, Location, SavedLocation};
+
+///Settings read from the config file. Anything left out gets its default
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub limits: GlobalLimits,
+    ///Slower (or faster) limits that kick in on a schedule
+    pub alt_speed: Option<AltSpeedSchedule>,
+    ///When torrents without a policy of their own stop seeding
+    pub seeding: SeedingPolicy,
+    ///How many torrents run at once, the rest wait their turn
+    pub queue: QueueLimits,
+    pub sequential: SequentialSettings,
+    pub stream: StreamSettings,
+    pub storage: StorageSettings,
+    pub disk: DiskSettings,
+    ///How long we keep the same peer id and tracker key
+    pub identity: IdentitySettings,
+    ///Transfer history, for charting how things went
+    pub history: HistorySettings,
+    pub peer_cache: PeerCacheSettings,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    ///Sparse, or full to claim the space before downloading
+    pub allocation: AllocationMode,
+    ///Download here, and move to the download dir once complete
+    pub incomplete_dir: Option<PathBuf>,
+    ///Name files in the incomplete dir with .part on the end
+    pub part_suffix: bool,
+}
+
+impl StorageSettings {
+    ///Where a torrent starts out. Wherever the files were last put wins, as they were put,
+    ///so a finished torrent keeps seeding from the download dir and an unfinished one
+    ///keeps its names even if the config changed since
+    pub fn start_location(&self, saved: Option<SavedLocation>, download_dir: &Path) -> Location {
+        match (saved, &self.incomplete_dir) {
+            (Some(SavedLocation::Known(location)), _) => location,
+            //all we can go by for rows from before we kept track
+            (Some(SavedLocation::Legacy(saved)), Some(incomplete_dir))
+                if saved == *incomplete_dir =>
+            {
+                Location::incomplete(&saved, self.part_suffix)
+            }
+            (Some(SavedLocation::Legacy(saved)), _) => Location::complete(&saved),
+            (None, Some(incomplete_dir)) => Location::incomplete(incomplete_dir, self.part_suffix),
+            (None, None) => Location::complete(download_dir),
+        }
+    }
+}
+
+///The local HTTP server that streams files while they download
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamSettings {
+    ///Port on 127.0.0.1, missing to not run the server at all
+    pub port: Option<u16>,
+}
+
+///For torrents, or files, downloaded front to back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SequentialSettings {
+    ///How many pieces past the first missing one we work on at once
+    pub readahead_pieces: usize,
+}
+
+impl Default for SequentialSettings {
+    fn default() -> Self {
+        Self {
+            readahead_pieces: DEFAULT_READAHEAD,
+        }
+    }
+}
+
+///Limits across all torrents, in KiB/s, 0 or missing for unlimited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GlobalLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+///Read the config file, a missing file is the same as an empty one
+pub fn load_config(path: &Path) -> Result<Config> {
+    if !path.exists() {
+        debug!("No config at {}, using defaults", path.display());
+        return Ok(Config::default());
+    }
+    let text = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read the config {}", path.display()))?;
+    let config = toml::from_str(&text)
+        .wrap_err_with(|| format!("Failed to parse the config {}", path.display()))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::seeding::SeedingAction;
+    use chrono::{NaiveTime, Weekday};
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+            [limits]
+            download = 2048
+
+            [alt_speed]
+            download = 100
+            upload = 20
+            days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+            start = "09:00:00"
+            end = "18:00:00"
+
+            [seeding]
+            ratio = 2.0
+            action = "pause"
+
+            [queue]
+            max_active_downloads = 1
+
+            [storage]
+            allocation = "full"
+
+            [identity]
+            rotation = "every_session"
+        "#;
+        let config: Config = toml::from_str(text).unwrap();
+        assert_eq!(Some(2048), config.limits.download);
+        assert_eq!(None, config.limits.upload);
+        let alt_speed = config.alt_speed.unwrap();
+        assert_eq!(5, alt_speed.days.len());
+        assert_eq!(Weekday::Fri, alt_speed.days[4]);
+        assert_eq!(NaiveTime::from_hms_opt(18, 0, 0).unwrap(), alt_speed.end);
+        assert_eq!(Some(2.0), config.seeding.ratio);
+        assert_eq!(None, config.seeding.idle_minutes);
+        assert_eq!(SeedingAction::Pause, config.seeding.action);
+        assert_eq!(1, config.queue.max_active_downloads);
+        assert_eq!(
+            QueueLimits::default().max_active_seeds,
+            config.queue.max_active_seeds
+        );
+        assert_eq!(AllocationMode::Full, config.storage.allocation);
+        assert_eq!(
+            crate::identity::IdentityRotation::EverySession,
+            config.identity.rotation
+        );
+    }
+
+    #[test]
+    fn test_start_location() {
+        let download_dir = Path::new("done");
+        let mut settings = StorageSettings::default();
+        assert_eq!(
+            Location::complete(download_dir),
+            settings.start_location(None, download_dir)
+        );
+        settings.incomplete_dir = Some(PathBuf::from("partial"));
+        settings.part_suffix = true;
+        let incomplete = settings.start_location(None, download_dir);
+        assert_eq!(Path::new("partial"), incomplete.dir);
+        assert!(incomplete.part_suffix);
+        assert!(!incomplete.complete);
+        let legacy = |dir: &str| Some(SavedLocation::Legacy(PathBuf::from(dir)));
+        assert_eq!(
+            incomplete,
+            settings.start_location(legacy("partial"), download_dir)
+        );
+        //moved there when it finished in an earlier session
+        assert_eq!(
+            Location::complete(Path::new("elsewhere")),
+            settings.start_location(legacy("elsewhere"), download_dir)
+        );
+        //a different incomplete dir in the config doesn't make an unfinished torrent complete
+        settings.incomplete_dir = Some(PathBuf::from("new_partial"));
+        assert_eq!(
+            incomplete,
+            settings.start_location(Some(SavedLocation::Known(incomplete.clone())), download_dir)
+        );
+    }
+
+    #[test]
+    fn test_missing_config_is_default() {
+        let config = load_config(Path::new("no/such/torrentox.toml")).unwrap();
+        assert_eq!(Config::default(), config);
+    }
+}
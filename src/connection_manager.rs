@@ -0,0 +1,443 @@
+use color_eyre::eyre::Result;
+use eyre::eyre;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+use crate::api::{connect_to_peer, peer_loop, send_handshake, PeerContext};
+use crate::disk::{DiskPool, TorrentDisk};
+use crate::error_types::HandshakeError;
+use crate::model::{
+    FileEntry, FilePriority, InfoHash, Peer, PeerId, TorrentSession, TransferStats,
+};
+use crate::parser::{get_piece_metadata, get_size};
+use crate::peer_cache::{client_name, PeerLog, PeerOutcome};
+use crate::piece::{BanList, DownloadState};
+use crate::rate_limit::{Throttle, TransferLimiter};
+use crate::storage::{Location, Storage};
+
+///How many peers we talk to and how patient we are with them
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    pub max_peers_per_torrent: usize,
+    ///Across all torrents
+    pub max_peers: usize,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    ///How many times we try a peer again after the first attempt fails
+    pub max_retries: u32,
+    ///Wait before the first retry, doubled on every retry after that
+    pub retry_backoff: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_peers_per_torrent: 30,
+            max_peers: 100,
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+///Everything the peers of one torrent share
+pub struct ActiveTorrent {
+    pub name: String,
+    pub announce_url: String,
+    pub info_hash: InfoHash,
+    ///The torrentox peer_id
+    pub peer_id: PeerId,
+    ///The tracker key that goes with the peer id
+    pub key: String,
+    ///How the dials went, for the known peers in the db
+    pub peer_log: PeerLog,
+    ///Total size of the torrent's content
+    pub size: u64,
+    pub download: Arc<Mutex<DownloadState>>,
+    pub storage: Arc<Storage>,
+    ///Reads and writes go through here, off the runtime thread
+    pub disk: TorrentDisk,
+    ///Where the files end up once they are complete
+    pub download_dir: PathBuf,
+    pub rate_limits: Arc<TransferLimiter>,
+    pub stats: TransferStats,
+    ///Set to true to hang up on every peer of this torrent.
+    ///Starts out true, the torrent waits in the queue until the session starts it
+    pub stopped: watch::Sender<bool>,
+    ///Woken every time a piece is verified and written
+    pub verified: Notify,
+}
+
+impl ActiveTorrent {
+    ///Files come in the order of the torrent, with their priority and sequential mode.
+    ///Sequential pieces are worked on `readahead` at a time
+    pub fn new(
+        torrent_session: TorrentSession,
+        download_dir: &Path,
+        location: Location,
+        stats: TransferStats,
+        files: &[FileEntry],
+        readahead: usize,
+        disk_pool: Arc<DiskPool>,
+    ) -> Result<Self> {
+        let torrent = torrent_session.torrent;
+        let torrent_file = &torrent.torrent_file;
+        let announce_url = torrent_file
+            .announce
+            .clone()
+            .ok_or_else(|| eyre!("Did not find the announce url"))?;
+        let mut storage = Storage::new(torrent_file, location);
+        let priorities: Vec<_> = files.iter().map(|f| f.priority).collect();
+        let sequential: Vec<_> = files.iter().map(|f| f.sequential).collect();
+        storage.set_priorities(&priorities);
+        storage.set_sequential(&sequential);
+        let mut download = DownloadState::new(get_piece_metadata(torrent_file));
+        let num_pieces = download.pieces.len();
+        download.set_priorities(storage.piece_priorities(num_pieces));
+        download.set_sequential(storage.sequential_pieces(num_pieces), readahead);
+        let storage = Arc::new(storage);
+        Ok(Self {
+            name: torrent.info_name(),
+            announce_url,
+            info_hash: torrent_file.info_hash,
+            peer_id: torrent_session.peer_id,
+            key: torrent_session.key,
+            peer_log: PeerLog::default(),
+            size: get_size(torrent_file),
+            download: Arc::new(Mutex::new(download)),
+            disk: TorrentDisk::new(storage.clone(), disk_pool),
+            storage,
+            download_dir: download_dir.to_path_buf(),
+            rate_limits: Arc::new(TransferLimiter::unlimited()),
+            stats,
+            stopped: watch::Sender::new(true),
+            verified: Notify::new(),
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.download
+            .lock()
+            .map(|d| d.is_complete())
+            .unwrap_or(false)
+    }
+
+    ///Every piece we want that is whole on disk, with the hash it should have
+    pub fn pieces_to_check(&self) -> Vec<(u32, [u8; 20])> {
+        let Ok(download) = self.download.lock() else {
+            return Vec::new();
+        };
+        download
+            .pieces
+            .iter()
+            .filter(|p| {
+                download.priority(p.index as usize) != FilePriority::Skip
+                    && self.storage.is_whole_on_disk(p.index)
+            })
+            .map(|p| (p.index, p.sha1_hash))
+            .collect()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.borrow()
+    }
+
+    ///Bytes we still need, for the tracker. Skipped files are not needed
+    pub fn left(&self) -> u64 {
+        self.download
+            .lock()
+            .map(|d| {
+                d.missing()
+                    .filter_map(|i| d.pieces.get(i))
+                    .map(|p| p.length as u64)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+}
+
+///Dials peers concurrently, within the per torrent and global limits.
+///A peer that fails is retried with back-off, and never takes anyone else down with it
+#[derive(Clone)]
+pub struct ConnectionManager {
+    limits: Arc<ConnectionLimits>,
+    global_slots: Arc<Semaphore>,
+    ///By info hash, shared by every dial of the torrent so redials stay within the limit
+    torrent_slots: Arc<Mutex<HashMap<InfoHash, Arc<Semaphore>>>>,
+    bans: Arc<Mutex<BanList>>,
+    rate_limits: Arc<TransferLimiter>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        limits: ConnectionLimits,
+        bans: Arc<Mutex<BanList>>,
+        rate_limits: Arc<TransferLimiter>,
+    ) -> Self {
+        Self {
+            global_slots: Arc::new(Semaphore::new(limits.max_peers)),
+            torrent_slots: Arc::new(Mutex::new(HashMap::new())),
+            limits: Arc::new(limits),
+            bans,
+            rate_limits,
+        }
+    }
+
+    ///The limits shared by every torrent
+    pub fn rate_limits(&self) -> &TransferLimiter {
+        &self.rate_limits
+    }
+
+    ///The torrent's connection slots, made the first time it dials
+    fn torrent_slots(&self, info_hash: &InfoHash) -> Arc<Semaphore> {
+        //a panic elsewhere can't leave the map half updated, carry on with it
+        let mut slots = self.torrent_slots.lock().unwrap_or_else(|e| e.into_inner());
+        slots
+            .entry(*info_hash)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limits.max_peers_per_torrent)))
+            .clone()
+    }
+
+    ///Spawn a task per peer of the torrent. Each task hands back the torrent's info hash when it is done
+    pub fn dial(
+        &self,
+        torrent: Arc<ActiveTorrent>,
+        peers: Vec<Peer>,
+        tasks: &mut JoinSet<InfoHash>,
+    ) {
+        info!("Dialing {} peers for {}", peers.len(), torrent.name);
+        let torrent_slots = self.torrent_slots(&torrent.info_hash);
+        for peer in peers {
+            let manager = self.clone();
+            let torrent = torrent.clone();
+            let torrent_slots = torrent_slots.clone();
+            tasks.spawn(async move {
+                dial_with_retries(manager, peer, torrent.clone(), torrent_slots).await;
+                torrent.info_hash
+            });
+        }
+    }
+}
+
+async fn dial_with_retries(
+    manager: ConnectionManager,
+    peer: Peer,
+    torrent: Arc<ActiveTorrent>,
+    torrent_slots: Arc<Semaphore>,
+) {
+    let limits = &manager.limits;
+    let peer_addr = peer.to_string();
+    for attempt in 0..=limits.max_retries {
+        if attempt > 0 {
+            let backoff = limits.retry_backoff * 2u32.saturating_pow(attempt - 1);
+            debug!("Retrying {peer_addr} in {:?}", backoff);
+            sleep(backoff).await;
+        }
+        let banned = manager
+            .bans
+            .lock()
+            .map(|b| b.is_banned(&peer_addr))
+            .unwrap_or(true);
+        if banned || torrent.is_stopped() {
+            return;
+        }
+
+        //slots are only held while connected, not while backing off
+        let result = {
+            let (Ok(_torrent_slot), Ok(_global_slot)) = (
+                torrent_slots.acquire().await,
+                manager.global_slots.acquire().await,
+            ) else {
+                return;
+            };
+            dial_peer(&manager, &peer, &torrent).await
+        };
+        match result {
+            Ok(()) => return,
+            Err(e) => {
+                warn!("Attempt {} at {peer_addr} failed: {e}", attempt + 1);
+                if let Some(handshake_error) = e.downcast_ref::<HandshakeError>() {
+                    if handshake_error.is_permanent() {
+                        info!("Dropping {peer_addr} for good: {handshake_error}");
+                        torrent.peer_log.push(PeerOutcome::Failed { peer });
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    warn!(
+        "Giving up on {peer_addr} after {} attempts",
+        limits.max_retries + 1
+    );
+    torrent.peer_log.push(PeerOutcome::Failed { peer });
+}
+
+///Connect, handshake and download, each step with its own time limit
+async fn dial_peer(
+    manager: &ConnectionManager,
+    peer: &Peer,
+    torrent: &ActiveTorrent,
+) -> Result<()> {
+    let limits = &manager.limits;
+    let mut stream = timeout(limits.connect_timeout, connect_to_peer(&peer.ip, peer.port))
+        .await
+        .map_err(|_| eyre!("Timed out connecting to {peer}"))??;
+    let handshake = timeout(
+        limits.handshake_timeout,
+        send_handshake(&mut stream, &torrent.info_hash, &torrent.peer_id),
+    )
+    .await
+    .map_err(|_| eyre!("Timed out waiting for the handshake of {peer}"))??;
+    let peer_stats = TransferStats::new(0, 0, Duration::ZERO);
+    let ctx = PeerContext {
+        download: &torrent.download,
+        bans: &manager.bans,
+        storage: &torrent.storage,
+        disk: &torrent.disk,
+        throttle: Throttle {
+            global: &manager.rate_limits,
+            torrent: &torrent.rate_limits,
+        },
+        stats: &torrent.stats,
+        peer_stats: &peer_stats,
+        stopped: &torrent.stopped,
+        verified: &torrent.verified,
+    };
+    let result = peer_loop(stream, &peer.to_string(), &ctx).await;
+    //we got through, whatever happened after
+    torrent.peer_log.push(PeerOutcome::Connected {
+        peer: peer.clone(),
+        client: client_name(&handshake.peer_id),
+        downloaded: peer_stats.downloaded(),
+        uploaded: peer_stats.uploaded(),
+    });
+    result
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::disk::DiskSettings;
+    use crate::model::PieceMetadata;
+    use tokio::net::TcpListener;
+
+    pub fn test_torrent() -> ActiveTorrent {
+        let pieces = vec![PieceMetadata {
+            index: 0,
+            length: 1,
+            sha1_hash: [0u8; 20],
+        }];
+        let storage = Arc::new(Storage {
+            files: Vec::new(),
+            piece_length: 1,
+            ..Storage::default()
+        });
+        ActiveTorrent {
+            name: "test".to_owned(),
+            announce_url: "http://127.0.0.1/announce".to_owned(),
+            info_hash: [1u8; 20],
+            peer_id: [2u8; 20],
+            key: "key".to_owned(),
+            peer_log: PeerLog::default(),
+            size: 0,
+            download: Arc::new(Mutex::new(DownloadState::new(pieces))),
+            disk: TorrentDisk::new(
+                storage.clone(),
+                Arc::new(DiskPool::new(&DiskSettings::default()).unwrap()),
+            ),
+            storage,
+            download_dir: PathBuf::new(),
+            rate_limits: Arc::new(TransferLimiter::unlimited()),
+            stats: TransferStats::new(0, 0, Duration::ZERO),
+            stopped: watch::Sender::new(false),
+            verified: Notify::new(),
+        }
+    }
+
+    pub fn test_manager() -> ConnectionManager {
+        let limits = ConnectionLimits {
+            connect_timeout: Duration::from_millis(200),
+            handshake_timeout: Duration::from_millis(100),
+            max_retries: 1,
+            retry_backoff: Duration::from_millis(10),
+            ..ConnectionLimits::default()
+        };
+        ConnectionManager::new(
+            limits,
+            Arc::new(Mutex::new(BanList::default())),
+            Arc::new(TransferLimiter::unlimited()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_times_out() {
+        //accepts the connection, then never says a word
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accepting = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+        let peer = Peer {
+            ip: "127.0.0.1".to_owned(),
+            port,
+        };
+        let torrent = test_torrent();
+        let manager = test_manager();
+        let err = dial_peer(&manager, &peer, &torrent).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Timed out waiting for the handshake"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_peers_do_not_stop_the_run() {
+        //grab a free port and let it go again, so nobody is listening on it
+        let closed_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let peers = (0..3)
+            .map(|_| Peer {
+                ip: "127.0.0.1".to_owned(),
+                port: closed_port,
+            })
+            .collect();
+        let manager = test_manager();
+        let torrent = Arc::new(test_torrent());
+        //every task finishes, rather than erroring out on the first bad peer
+        let mut tasks = JoinSet::new();
+        manager.dial(torrent.clone(), peers, &mut tasks);
+        while let Some(joined) = tasks.join_next().await {
+            assert_eq!(torrent.info_hash, joined.unwrap());
+        }
+        //each one given up on, for the known peers
+        let outcomes = torrent.peer_log.take();
+        assert_eq!(3, outcomes.len());
+        assert!(outcomes
+            .iter()
+            .all(|o| matches!(o, PeerOutcome::Failed { .. })));
+    }
+
+    #[test]
+    fn test_torrent_slots_are_shared_between_dials() {
+        let manager = test_manager();
+        let slots = manager.torrent_slots(&[1; 20]);
+        assert!(Arc::ptr_eq(
+            &slots,
+            &manager.clone().torrent_slots(&[1; 20])
+        ));
+        assert!(!Arc::ptr_eq(&slots, &manager.torrent_slots(&[2; 20])));
+    }
+}
@@ -1,40 +1,141 @@
+use crate::archive::{ArchivedFile, ArchivedTorrent, ImportOutcome};
 use crate::error_types::DbError;
-use crate::model::Torrent;
-use color_eyre::eyre::{Result, WrapErr};
-use log::info;
-use rusqlite::params;
-use rusqlite::Connection;
+use crate::history::TransferSample;
+use crate::migrations::migrate;
+use crate::model::{
+    info_hash_from_hex, info_hash_hex, FileEntry, FilePriority, InfoHash, Peer, Torrent,
+    TorrentFile,
+};
+use crate::parser::{get_files, get_size};
+use crate::peer_cache::KnownPeer;
+use crate::queue::QueueMove;
+use crate::seeding::{SeedingAction, SeedingPolicy};
+use crate::state::TorrentState;
+use crate::storage::{Location, SavedLocation};
+use crate::tracker::TrackerStatus;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use log::warn;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{params, OptionalExtension};
+use rusqlite::{Connection, Row};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 ///Holder of the DB Connection information
 pub struct DbConnection {
     pub conn: Connection,
     ///Name of the db on the file
     pub db_name: String,
-    pub name: String,
 }
-///Create necessary torrent tables iff not already created.
+///The torrents we could read, and the rows we couldn't
+#[derive(Debug, Default)]
+pub struct TorrentList {
+    pub torrents: Vec<Torrent>,
+    pub unreadable: Vec<UnreadableRow>,
+}
+
+///A row that didn't make a torrent, say because the raw bytes are corrupt.
+///It stays in the db as it is, for the caller to report or remove
+#[derive(Debug)]
+pub struct UnreadableRow {
+    pub id: i64,
+    pub name: Option<String>,
+    pub error: DbError,
+}
+
+///What torrent_from_row wants, in its order
+const TORRENT_COLUMNS: &str = "name, file_path, announce_url, torrent_file_raw, size, downloaded, uploaded, download_limit, upload_limit";
+
+fn torrent_from_row(row: &Row) -> rusqlite::Result<Torrent> {
+    Ok(Torrent {
+        name: row.get(0)?,
+        file_path: row.get(1)?,
+        announce_url: row.get(2)?,
+        torrent_file: row.get(3)?,
+        raw_bytes: row.get(3)?,
+        size: row.get(4)?,
+        downloaded: row.get(5)?,
+        uploaded: row.get(6)?,
+        download_limit: row.get(7)?,
+        upload_limit: row.get(8)?,
+    })
+}
+
+///The raw bytes decode straight into a torrent file, a bad blob is a DbError::Deserializetion
+impl FromSql for TorrentFile {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_bencode::from_bytes(value.as_blob()?)
+            .map_err(|e| FromSqlError::Other(Box::new(DbError::from(e))))
+    }
+}
+
+///rusqlite boxes what FromSql gave back, so a bad blob comes out as the DbError it was
+fn row_error(e: rusqlite::Error) -> DbError {
+    match e {
+        rusqlite::Error::FromSqlConversionFailure(index, kind, source) => {
+            match source.downcast::<DbError>() {
+                Ok(error) => *error,
+                Err(source) => DbError::Database(rusqlite::Error::FromSqlConversionFailure(
+                    index, kind, source,
+                )),
+            }
+        }
+        e => DbError::Database(e),
+    }
+}
+
+fn no_torrent(info_hash: &InfoHash) -> color_eyre::Report {
+    eyre!("No torrent with info hash {}", info_hash_hex(info_hash))
+}
+
+///The info hash column, back from hex
+fn hash_from_row(row: &Row, index: usize) -> rusqlite::Result<InfoHash> {
+    let hex: String = row.get(index)?;
+    info_hash_from_hex(&hex).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            format!("{hex} is not an info hash").into(),
+        )
+    })
+}
+
+///Create necessary torrent tables iff not already created, and bring older ones up to date
 pub fn init_tables(db: &DbConnection) -> Result<()> {
-    //TODO: add (bytes)downloaded (bytes)left fields
-    let create_table_torrent= "CREATE TABLE IF NOT EXISTS torrent (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, file_path TEXT, announce_url TEXT, torrent_file_raw BLOB, size INTEGER, downloaded INTEGER, uploaded INTEGER) ";
-    let create_table_torrent_file = "CREATE TABLE IF NOT EXISTS torrent_file(id INTEGER PRIMARY KEY AUTOINCREMENT, torrent_id INTEGER, path TEXT, size INTEGER, downloaded INTEGER, uploaded INTEGER, FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE) ";
-    db.conn.execute(create_table_torrent, [])?;
-    db.conn.execute(create_table_torrent_file, [])?;
-    Ok(())
+    migrate(&db.conn, &db.db_name)
 }
 
 ///We save a torrent file, recording a few attributes,
-///but otherwise storing the raw bytes so as not to lose any info during the coding process
+///but otherwise storing the raw bytes so as not to lose any info during the coding process.
+///A torrent we have not seen before goes to the back of the queue, one we have keeps its row,
+///settings and files, and picks up any tracker it didn't have
 pub fn save_torrent_file(torrent: &Torrent, db: &DbConnection) -> Result<()> {
-    let sql = "INSERT INTO torrent (name, file_path, announce_url, torrent_file_raw, size, downloaded, uploaded) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) ";
-    db.conn.execute(
+    let tx = db.conn.unchecked_transaction()?;
+    upsert_torrent(torrent, &tx)?;
+    tx.commit()?;
+    Ok(())
+}
+
+///save_torrent_file, within whatever transaction the caller has going
+fn upsert_torrent(torrent: &Torrent, tx: &Connection) -> Result<()> {
+    let info_hash = torrent.info_hash_hex();
+    let known: Option<(i64, Option<String>)> = tx
+        .query_row(
+            "SELECT id, trackers FROM torrent WHERE info_hash = ?1",
+            params![info_hash],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .wrap_err("Failed to look up the torrent by info hash")?;
+    let trackers = merge_trackers(
+        known.as_ref().and_then(|(_, t)| t.as_deref()),
+        torrent.torrent_file.announce.as_deref(),
+    );
+    let sql = "INSERT INTO torrent (name, file_path, announce_url, torrent_file_raw, size, downloaded, uploaded, download_limit, upload_limit, info_hash, trackers, queue_position) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, (SELECT COALESCE(MAX(queue_position), 0) + 1 FROM torrent)) ON CONFLICT(info_hash) DO UPDATE SET file_path = excluded.file_path, trackers = excluded.trackers";
+    tx.execute(
         sql,
-        (
-            torrent
-                .torrent_file
-                .info
-                .name
-                .clone()
-                .unwrap_or("None".to_owned()),
+        params![
+            torrent.info_name(),
             torrent.file_path.clone(),
             torrent
                 .torrent_file
@@ -42,72 +143,1088 @@ pub fn save_torrent_file(torrent: &Torrent, db: &DbConnection) -> Result<()> {
                 .clone()
                 .unwrap_or("None".to_owned()),
             torrent.raw_bytes.clone(),
-            torrent.size.clone(),
-            torrent.downloaded.clone(),
+            torrent.size,
+            torrent.downloaded,
             torrent.uploaded,
-        ),
+            torrent.download_limit,
+            torrent.upload_limit,
+            info_hash,
+            trackers,
+        ],
     )?;
+    if known.is_none() {
+        let torrent_id = tx.last_insert_rowid();
+        let sql = "INSERT INTO torrent_file (torrent_id, path, size, downloaded, uploaded, priority, sequential) VALUES (?1, ?2, ?3, 0, 0, 'normal', 0)";
+        for file in get_files(&torrent.torrent_file) {
+            tx.execute(
+                sql,
+                params![torrent_id, file.path.join("/"), file.length as u64],
+            )
+            .wrap_err("Failed to save the files of the torrent")?;
+        }
+    }
+    Ok(())
+}
+
+///One tracker per line, the ones we already had first
+fn merge_trackers(known: Option<&str>, announce: Option<&str>) -> String {
+    let mut trackers: Vec<&str> = known.map(|k| k.lines().collect()).unwrap_or_default();
+    if let Some(announce) = announce {
+        if !trackers.contains(&announce) {
+            trackers.push(announce);
+        }
+    }
+    trackers.join("\n")
+}
+
+///Every tracker we have been given for the torrent, over all the times it was added
+pub fn list_trackers(info_hash: &InfoHash, db: &DbConnection) -> Result<Vec<String>> {
+    let sql = "SELECT trackers FROM torrent WHERE info_hash = ?1";
+    let trackers: Option<String> = db
+        .conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| row.get(0))
+        .optional()
+        .wrap_err("Error retrieving the trackers by info hash")?
+        .flatten();
+    Ok(trackers
+        .map(|t| t.lines().map(str::to_owned).collect())
+        .unwrap_or_default())
+}
+
+///The torrent with this info hash, if we have it
+pub fn select_torrent_by_info_hash(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<Option<Torrent>> {
+    let sql = format!("SELECT {TORRENT_COLUMNS} FROM torrent WHERE info_hash = ?1");
+    db.conn
+        .query_row(&sql, params![info_hash_hex(info_hash)], torrent_from_row)
+        .optional()
+        .map_err(row_error)
+        .wrap_err("Error retrieving the torrent by info hash")
+}
+
+///The torrent the user means, by name or by info hash. Two torrents can share a name,
+///then only the info hash will do
+pub fn find_torrent(name_or_hash: &str, db: &DbConnection) -> Result<InfoHash> {
+    let mut stmt = db
+        .conn
+        .prepare("SELECT info_hash FROM torrent WHERE info_hash IS NOT NULL AND (name = ?1 OR info_hash = lower(?1)) ORDER BY id")
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the find torrent statement")?;
+    let found = stmt
+        .query_map(params![name_or_hash], |row| hash_from_row(row, 0))?
+        .collect::<rusqlite::Result<Vec<InfoHash>>>()
+        .wrap_err("Failed to look up the torrent")?;
+    match found.as_slice() {
+        [] => Err(eyre!("No torrent named {name_or_hash}")),
+        [info_hash] => Ok(*info_hash),
+        many => Err(eyre!(
+            "More than one torrent is named {name_or_hash}, give the info hash instead: {}",
+            many.iter()
+                .map(info_hash_hex)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+///Remember the peer id and tracker key we announce the torrent with, and since when
+pub fn save_identity(
+    info_hash: &InfoHash,
+    peer_id: &str,
+    key: &str,
+    created_at: i64,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "UPDATE torrent SET peer_id = ?1, tracker_key = ?2, identity_created_at = ?3 WHERE info_hash = ?4";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![peer_id, key, created_at, info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the identity")?;
+    if updated == 0 {
+        return Err(eyre!(
+            "No torrent with info hash {}",
+            info_hash_hex(info_hash)
+        ));
+    }
+    Ok(())
+}
+
+///The (peer id, tracker key, created at) of the torrent, if it has been announced before
+pub fn select_identity(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<Option<(String, String, i64)>> {
+    let sql = "SELECT peer_id, tracker_key, identity_created_at FROM torrent WHERE info_hash = ?1 AND peer_id IS NOT NULL AND tracker_key IS NOT NULL";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let created_at: Option<i64> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, created_at.unwrap_or(0)))
+        })
+        .optional()
+        .wrap_err("Error retrieving the identity by info hash")
+}
+
+///Every file of a torrent, in torrent order
+pub fn list_files(info_hash: &InfoHash, db: &DbConnection) -> Result<Vec<FileEntry>> {
+    let sql = "SELECT path, size, priority, sequential FROM torrent_file WHERE torrent_id = (SELECT id FROM torrent WHERE info_hash = ?1) ORDER BY id";
+    let mut stmt = db
+        .conn
+        .prepare(sql)
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list files statement")?;
+    let files = stmt
+        .query_map(params![info_hash_hex(info_hash)], |row| {
+            let priority: Option<String> = row.get(2)?;
+            let sequential: Option<bool> = row.get(3)?;
+            Ok(FileEntry {
+                path: row.get(0)?,
+                size: row.get(1)?,
+                priority: priority
+                    .and_then(|p| FilePriority::parse(&p))
+                    .unwrap_or_default(),
+                sequential: sequential.unwrap_or(false),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<FileEntry>>>()
+        .wrap_err("Failed to read the files of the torrent")?;
+    if files.is_empty() {
+        return Err(eyre!(
+            "No files for a torrent with info hash {}",
+            info_hash_hex(info_hash)
+        ));
+    }
+    Ok(files)
+}
+
+///Set the priority of a file, by its place in the torrent, counting from 0
+pub fn save_file_priority(
+    info_hash: &InfoHash,
+    index: usize,
+    priority: FilePriority,
+    db: &DbConnection,
+) -> Result<String> {
+    let path = file_path(info_hash, index, db)?;
+    let sql = "UPDATE torrent_file SET priority = ?1 WHERE path = ?2 AND torrent_id IN (SELECT id FROM torrent WHERE info_hash = ?3)";
+    db.conn
+        .execute(
+            sql,
+            params![priority.as_str(), path, info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the file priority")?;
+    Ok(path)
+}
+
+fn file_path(info_hash: &InfoHash, index: usize, db: &DbConnection) -> Result<String> {
+    let files = list_files(info_hash, db)?;
+    files.get(index).map(|f| f.path.clone()).ok_or_else(|| {
+        eyre!(
+            "The torrent has {} files, there is no file {index}",
+            files.len()
+        )
+    })
+}
+
+///Turn sequential mode on or off, for the whole torrent or for the file at index
+pub fn save_sequential(
+    info_hash: &InfoHash,
+    file_index: Option<usize>,
+    sequential: bool,
+    db: &DbConnection,
+) -> Result<()> {
+    let updated = match file_index {
+        Some(index) => {
+            let path = file_path(info_hash, index, db)?;
+            let sql = "UPDATE torrent_file SET sequential = ?1 WHERE path = ?2 AND torrent_id IN (SELECT id FROM torrent WHERE info_hash = ?3)";
+            db.conn.execute(sql, params![sequential, path, info_hash_hex(info_hash)])
+        }
+        None => {
+            let sql = "UPDATE torrent SET sequential = ?1 WHERE info_hash = ?2";
+            db.conn.execute(sql, params![sequential, info_hash_hex(info_hash)])
+        }
+    }
+    .wrap_err("Failed to save sequential mode")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+///Whether the whole torrent is downloaded sequentially
+pub fn select_sequential(info_hash: &InfoHash, db: &DbConnection) -> Result<bool> {
+    let sql = "SELECT sequential FROM torrent WHERE info_hash = ?1";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let sequential: Option<bool> = row.get(0)?;
+            Ok(sequential.unwrap_or(false))
+        })
+        .wrap_err("Error retrieving sequential mode by info hash")
+}
+
+///Record where the files of the torrent are now, and whether they are done moving
+pub fn save_location(info_hash: &InfoHash, location: &Location, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET save_path = ?1, save_path_complete = ?2, save_path_part_suffix = ?3 WHERE info_hash = ?4";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![
+                location.dir.to_string_lossy(),
+                location.complete,
+                location.part_suffix,
+                info_hash_hex(info_hash)
+            ],
+        )
+        .wrap_err("Failed to save the location")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+///Ask for the files of the torrent to be moved, by whichever session has it
+pub fn request_move(info_hash: &InfoHash, dir: &Path, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET move_to = ?1 WHERE info_hash = ?2";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![dir.to_string_lossy(), info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the move")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+pub fn select_pending_move(info_hash: &InfoHash, db: &DbConnection) -> Result<Option<PathBuf>> {
+    let sql = "SELECT move_to FROM torrent WHERE info_hash = ?1 AND move_to IS NOT NULL";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()
+        .map(|path| path.map(PathBuf::from))
+        .wrap_err("Error retrieving the pending move by info hash")
+}
+
+///Done with the move, whether it worked or not
+pub fn clear_pending_move(info_hash: &InfoHash, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET move_to = NULL WHERE info_hash = ?1";
+    db.conn
+        .execute(sql, params![info_hash_hex(info_hash)])
+        .wrap_err("Failed to clear the move")?;
     Ok(())
 }
 
-pub fn list_torrent_files(db: &DbConnection) -> Result<Vec<Torrent>> {
+///Ask for the torrent to be rechecked, or call it off
+pub fn request_recheck(info_hash: &InfoHash, recheck: bool, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET recheck = ?1 WHERE info_hash = ?2";
+    let updated = db
+        .conn
+        .execute(sql, params![recheck, info_hash_hex(info_hash)])
+        .wrap_err("Failed to save the recheck")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+pub fn select_recheck_requested(info_hash: &InfoHash, db: &DbConnection) -> Result<bool> {
+    let sql = "SELECT recheck FROM torrent WHERE info_hash = ?1";
+    let recheck: Option<bool> = db
+        .conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| row.get(0))
+        .optional()
+        .wrap_err("Error retrieving the recheck by info hash")?
+        .flatten();
+    Ok(recheck.unwrap_or(false))
+}
+
+///Where the files of the torrent were last put, if anywhere
+pub fn select_location(info_hash: &InfoHash, db: &DbConnection) -> Result<Option<SavedLocation>> {
+    let sql = "SELECT save_path, save_path_complete, save_path_part_suffix FROM torrent WHERE info_hash = ?1 AND save_path IS NOT NULL";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let dir = PathBuf::from(row.get::<_, String>(0)?);
+            let complete: Option<bool> = row.get(1)?;
+            let part_suffix: Option<bool> = row.get(2)?;
+            Ok(match complete {
+                Some(complete) => SavedLocation::Known(Location {
+                    dir,
+                    part_suffix: part_suffix.unwrap_or(false),
+                    complete,
+                }),
+                None => SavedLocation::Legacy(dir),
+            })
+        })
+        .optional()
+        .wrap_err("Error retrieving the location by info hash")
+}
+
+pub fn list_torrent_files(db: &DbConnection) -> Result<TorrentList> {
     //are we going to have to split this up by file IN the torrent?
     //do we need a child table that has the actual files in it?
     //yes we do
-    let sql = "SELECT name, file_path, announce_url, torrent_file_raw, size, downloaded, uploaded FROM torrent";
+    let sql = format!("SELECT {TORRENT_COLUMNS}, id FROM torrent ORDER BY id");
     let mut stmt = db
         .conn
-        .prepare(sql)
+        .prepare(&sql)
         .map_err(DbError::from)
         .wrap_err("Failed to prepare the list torrent file statement")?;
-    let torrent_file_list = stmt
+    let rows = stmt
         .query_map([], |row| {
-            let torrent_file_raw: Vec<u8> = row.get(3)?;
-            let torrent_file = serde_bencode::from_bytes(&torrent_file_raw).unwrap();
-            Ok(Torrent {
-                name: row.get(0)?,
-                file_path: row.get(1)?,
-                announce_url: row.get(2)?,
-                torrent_file,
-                raw_bytes: torrent_file_raw,
-                size: row.get(4)?,
-                downloaded: row.get(5)?,
-                uploaded: row.get(6)?,
-            })
+            Ok((row.get(9)?, row.get(0)?, torrent_from_row(row)))
         })
         .wrap_err("Failed to map query result")?;
-    let mut torrent_vec = Vec::new();
+    let mut list = TorrentList::default();
+    for row in rows {
+        let (id, name, torrent) = row.wrap_err("Could not retrieve the torrent row from db")?;
+        match torrent {
+            Ok(torrent) => list.torrents.push(torrent),
+            Err(e) => {
+                warn!("Torrent row {id} is unreadable, leaving it out: {e}");
+                list.unreadable.push(UnreadableRow {
+                    id,
+                    name,
+                    error: row_error(e),
+                });
+            }
+        }
+    }
+    Ok(list)
+}
+
+///Record the state the torrent is in, and what went wrong if it errored.
+///The last error sticks around after the torrent recovers
+pub fn save_state(
+    info_hash: &InfoHash,
+    state: TorrentState,
+    error: Option<&str>,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql =
+        "UPDATE torrent SET state = ?1, last_error = COALESCE(?2, last_error) WHERE info_hash = ?3";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![state.as_str(), error, info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the state")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
 
-    for torrent in torrent_file_list {
-        let tr = torrent.wrap_err("Could not retrieve the torrent bytes from db")?;
-        torrent_vec.push(tr);
+///The state the torrent was last in, and the last error it had
+pub fn select_state(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<(TorrentState, Option<String>)> {
+    let sql = "SELECT state, last_error FROM torrent WHERE info_hash = ?1";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let state: Option<String> = row.get(0)?;
+            Ok((
+                state
+                    .as_deref()
+                    .and_then(TorrentState::parse)
+                    .unwrap_or_default(),
+                row.get(1)?,
+            ))
+        })
+        .wrap_err("Error retrieving the state by info hash")
+}
+
+///Ask whichever session has the torrent to pause, resume (queued) or stop it
+pub fn request_state(info_hash: &InfoHash, state: TorrentState, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET requested_state = ?1 WHERE info_hash = ?2";
+    let updated = db
+        .conn
+        .execute(sql, params![state.as_str(), info_hash_hex(info_hash)])
+        .wrap_err("Failed to save the state request")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
     }
+    Ok(())
+}
 
-    Ok(torrent_vec)
+pub fn select_requested_state(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<Option<TorrentState>> {
+    let sql = "SELECT requested_state FROM torrent WHERE info_hash = ?1";
+    let state: Option<String> = db
+        .conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| row.get(0))
+        .optional()
+        .wrap_err("Error retrieving the state request by info hash")?
+        .flatten();
+    Ok(state.as_deref().and_then(TorrentState::parse))
 }
 
-///Use the name. Get the file
-pub fn select_torrent_file(name: &str, db: &DbConnection) -> Result<Torrent> {
-    let sql = "SELECT name, file_path, announce_url, torrent_file_raw, downloaded, uploaded, size FROM torrent where name = ?1";
-    info!("Our select statment: {sql}");
-    info!("The name we will use: {name}");
+pub fn clear_requested_state(info_hash: &InfoHash, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE torrent SET requested_state = NULL WHERE info_hash = ?1";
     db.conn
-        .query_row(sql, params![name], |row| {
-            let torrent_file_raw: Vec<u8> = row.get(3)?;
-            let torrent_file = serde_bencode::from_bytes(&torrent_file_raw).unwrap();
+        .execute(sql, params![info_hash_hex(info_hash)])
+        .wrap_err("Failed to clear the state request")?;
+    Ok(())
+}
 
-            Ok(Torrent {
-                name: row.get(0)?,
-                file_path: row.get(1)?,
-                announce_url: row.get(2)?,
-                torrent_file,
-                raw_bytes: torrent_file_raw,
-                downloaded: row.get(4)?,
-                uploaded: row.get(5)?,
-                size: row.get(5)?,
-            })
+///Set the rate limits of a torrent, in bytes per second, None for unlimited
+pub fn save_torrent_rate_limits(
+    info_hash: &InfoHash,
+    download_limit: Option<u64>,
+    upload_limit: Option<u64>,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "UPDATE torrent SET download_limit = ?1, upload_limit = ?2 WHERE info_hash = ?3";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![download_limit, upload_limit, info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the rate limits")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+///The (download, upload) limits of a torrent, in bytes per second
+pub fn select_torrent_rate_limits(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<(Option<u64>, Option<u64>)> {
+    let sql = "SELECT download_limit, upload_limit FROM torrent WHERE info_hash = ?1";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .wrap_err("Error retrieving the rate limits by info hash")
+}
+
+///Set the limits across all torrents, in KiB/s, 0 for unlimited. None keeps what was there
+pub fn save_global_rate_limits(
+    download: Option<u64>,
+    upload: Option<u64>,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "INSERT INTO global_limit (id, download, upload) VALUES (1, ?1, ?2) ON CONFLICT(id) DO UPDATE SET download = COALESCE(excluded.download, download), upload = COALESCE(excluded.upload, upload)";
+    db.conn
+        .execute(sql, params![download, upload])
+        .wrap_err("Failed to save the global rate limits")?;
+    Ok(())
+}
+
+///The (download, upload) limits across all torrents, in KiB/s, 0 for unlimited.
+///None if never set, the config decides then
+pub fn select_global_rate_limits(db: &DbConnection) -> Result<(Option<u64>, Option<u64>)> {
+    db.conn
+        .query_row(
+            "SELECT download, upload FROM global_limit WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map(Option::unwrap_or_default)
+        .wrap_err("Error retrieving the global rate limits")
+}
+
+///Set the seeding goals of a torrent. None keeps whatever that goal was,
+///the torrent's own or the default policy's
+pub fn save_seeding_policy(
+    info_hash: &InfoHash,
+    ratio: Option<f64>,
+    seeding_minutes: Option<u64>,
+    idle_minutes: Option<u64>,
+    action: Option<SeedingAction>,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "UPDATE torrent SET seed_ratio_limit = COALESCE(?1, seed_ratio_limit), seed_time_limit = COALESCE(?2, seed_time_limit), seed_idle_limit = COALESCE(?3, seed_idle_limit), seed_limit_action = COALESCE(?4, seed_limit_action) WHERE info_hash = ?5";
+    let updated = db
+        .conn
+        .execute(
+            sql,
+            params![
+                ratio,
+                seeding_minutes,
+                idle_minutes,
+                action.map(|a| a.as_str()),
+                info_hash_hex(info_hash)
+            ],
+        )
+        .wrap_err("Failed to save the seeding policy")?;
+    if updated == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+///The seeding goals of a torrent, filled in from the default where it has none of its own
+pub fn select_seeding_policy(
+    info_hash: &InfoHash,
+    default: &SeedingPolicy,
+    db: &DbConnection,
+) -> Result<SeedingPolicy> {
+    let sql = "SELECT seed_ratio_limit, seed_time_limit, seed_idle_limit, seed_limit_action FROM torrent WHERE info_hash = ?1";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let action: Option<String> = row.get(3)?;
+            Ok(SeedingPolicy::or_default(
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                action.as_deref().and_then(SeedingAction::parse),
+                default,
+            ))
         })
-        .wrap_err("Error retrieving the torrent by name")
+        .wrap_err("Error retrieving the seeding policy by info hash")
+}
+
+///Keep the running totals, so ratios and seeding time carry over between sessions
+pub fn save_torrent_progress(
+    info_hash: &InfoHash,
+    downloaded: u64,
+    uploaded: u64,
+    seeding_time: Duration,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql =
+        "UPDATE torrent SET downloaded = ?1, uploaded = ?2, seeding_time = ?3 WHERE info_hash = ?4";
+    db.conn
+        .execute(
+            sql,
+            params![
+                downloaded,
+                uploaded,
+                seeding_time.as_secs(),
+                info_hash_hex(info_hash)
+            ],
+        )
+        .wrap_err("Failed to save the torrent progress")?;
+    Ok(())
+}
+
+///The (downloaded, uploaded, seeding time) totals of a torrent
+pub fn select_torrent_progress(
+    info_hash: &InfoHash,
+    db: &DbConnection,
+) -> Result<(u64, u64, Duration)> {
+    let sql = "SELECT downloaded, uploaded, seeding_time FROM torrent WHERE info_hash = ?1";
+    db.conn
+        .query_row(sql, params![info_hash_hex(info_hash)], |row| {
+            let seeding_time: Option<u64> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                Duration::from_secs(seeding_time.unwrap_or(0)),
+            ))
+        })
+        .wrap_err("Error retrieving the torrent progress by info hash")
+}
+
+///Note down what the torrent is doing now
+pub fn save_sample(sample: &TransferSample, db: &DbConnection) -> Result<()> {
+    let sql = "INSERT INTO transfer_sample (torrent_id, sampled_at, downloaded, uploaded, peers, download_rate, upload_rate, samples) SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8 FROM torrent WHERE info_hash = ?1";
+    let inserted = db
+        .conn
+        .execute(
+            sql,
+            params![
+                info_hash_hex(&sample.info_hash),
+                sample.sampled_at,
+                sample.downloaded,
+                sample.uploaded,
+                sample.peers,
+                sample.download_rate,
+                sample.upload_rate,
+                sample.samples
+            ],
+        )
+        .wrap_err("Failed to save the transfer sample")?;
+    if inserted == 0 {
+        return Err(no_torrent(&sample.info_hash));
+    }
+    Ok(())
+}
+
+///Samples from `since` (unix seconds) on, oldest first, for one torrent or all of them
+pub fn list_samples(
+    info_hash: Option<&InfoHash>,
+    since: i64,
+    db: &DbConnection,
+) -> Result<Vec<TransferSample>> {
+    let sql = "SELECT t.info_hash, t.name, s.sampled_at, s.downloaded, s.uploaded, s.peers, s.download_rate, s.upload_rate, s.samples FROM transfer_sample s JOIN torrent t ON s.torrent_id = t.id WHERE s.sampled_at >= ?1 AND (?2 IS NULL OR t.info_hash = ?2) ORDER BY s.sampled_at, s.id";
+    let mut stmt = db
+        .conn
+        .prepare(sql)
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list samples statement")?;
+    let samples = stmt
+        .query_map(params![since, info_hash.map(info_hash_hex)], |row| {
+            Ok(TransferSample {
+                info_hash: hash_from_row(row, 0)?,
+                name: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                sampled_at: row.get(2)?,
+                downloaded: row.get(3)?,
+                uploaded: row.get(4)?,
+                peers: row.get(5)?,
+                download_rate: row.get(6)?,
+                upload_rate: row.get(7)?,
+                samples: row.get(8)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<TransferSample>>>()
+        .wrap_err("Failed to read the transfer history")?;
+    Ok(samples)
+}
+
+///Merge the samples from before `older_than` into one per `bucket_seconds`, and drop the
+///ones from before `drop_before`. Rates and peers are averaged, weighted by how many samples
+///went into each, so merging a bucket again as more of it gets old enough comes out the same
+pub fn downsample_history(
+    older_than: i64,
+    bucket_seconds: i64,
+    drop_before: Option<i64>,
+    db: &DbConnection,
+) -> Result<()> {
+    let tx = db.conn.unchecked_transaction()?;
+    if let Some(drop_before) = drop_before {
+        tx.execute(
+            "DELETE FROM transfer_sample WHERE sampled_at < ?1",
+            params![drop_before],
+        )
+        .wrap_err("Failed to drop old history")?;
+    }
+    let last_id: i64 = tx.query_row(
+        "SELECT COALESCE(MAX(id), 0) FROM transfer_sample",
+        [],
+        |row| row.get(0),
+    )?;
+    tx.execute(
+        "INSERT INTO transfer_sample (torrent_id, sampled_at, downloaded, uploaded, peers, download_rate, upload_rate, samples) SELECT torrent_id, MAX(sampled_at), MAX(downloaded), MAX(uploaded), SUM(peers * samples) / SUM(samples), SUM(download_rate * samples) / SUM(samples), SUM(upload_rate * samples) / SUM(samples), SUM(samples) FROM transfer_sample WHERE sampled_at < ?1 GROUP BY torrent_id, sampled_at / ?2 HAVING COUNT(*) > 1",
+        params![older_than, bucket_seconds.max(1)],
+    )
+    .wrap_err("Failed to merge old history")?;
+    tx.execute(
+        "DELETE FROM transfer_sample WHERE id <= ?1 AND sampled_at < ?2 AND EXISTS (SELECT 1 FROM transfer_sample m WHERE m.id > ?1 AND m.torrent_id = transfer_sample.torrent_id AND m.sampled_at / ?3 = transfer_sample.sampled_at / ?3)",
+        params![last_id, older_than, bucket_seconds.max(1)],
+    )
+    .wrap_err("Failed to clear merged history")?;
+    tx.commit()?;
+    Ok(())
+}
+
+///We got through to the peer, so it goes to the front of the line next time
+pub fn save_peer_connected(
+    info_hash: &InfoHash,
+    peer: &Peer,
+    client: Option<&str>,
+    downloaded: u64,
+    uploaded: u64,
+    seen_at: i64,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "INSERT INTO known_peer (torrent_id, ip, port, last_seen, downloaded, uploaded, failures, client) SELECT id, ?2, ?3, ?4, ?5, ?6, 0, ?7 FROM torrent WHERE info_hash = ?1 ON CONFLICT(torrent_id, ip, port) DO UPDATE SET last_seen = excluded.last_seen, downloaded = downloaded + excluded.downloaded, uploaded = uploaded + excluded.uploaded, failures = 0, client = COALESCE(excluded.client, client)";
+    db.conn
+        .execute(
+            sql,
+            params![
+                info_hash_hex(info_hash),
+                peer.ip,
+                peer.port,
+                seen_at,
+                downloaded,
+                uploaded,
+                client
+            ],
+        )
+        .wrap_err("Failed to save the known peer")?;
+    Ok(())
+}
+
+///We gave up on the peer. Only counts against peers we already know,
+///we don't remember ones we never got through to
+pub fn save_peer_failed(info_hash: &InfoHash, peer: &Peer, db: &DbConnection) -> Result<()> {
+    let sql = "UPDATE known_peer SET failures = failures + 1 WHERE ip = ?2 AND port = ?3 AND torrent_id IN (SELECT id FROM torrent WHERE info_hash = ?1)";
+    db.conn
+        .execute(sql, params![info_hash_hex(info_hash), peer.ip, peer.port])
+        .wrap_err("Failed to save the peer failure")?;
+    Ok(())
+}
+
+///The peers of a torrent worth dialling, most reliable and recent first
+pub fn list_known_peers(
+    info_hash: &InfoHash,
+    limit: usize,
+    db: &DbConnection,
+) -> Result<Vec<KnownPeer>> {
+    let sql = "SELECT p.ip, p.port, p.last_seen, p.downloaded, p.uploaded, p.failures, p.client FROM known_peer p JOIN torrent t ON p.torrent_id = t.id WHERE t.info_hash = ?1 ORDER BY p.failures, p.last_seen DESC LIMIT ?2";
+    let mut stmt = db
+        .conn
+        .prepare(sql)
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list known peers statement")?;
+    let peers = stmt
+        .query_map(params![info_hash_hex(info_hash), limit], |row| {
+            Ok(KnownPeer {
+                ip: row.get(0)?,
+                port: row.get(1)?,
+                last_seen: row.get(2)?,
+                downloaded: row.get(3)?,
+                uploaded: row.get(4)?,
+                failures: row.get(5)?,
+                client: row.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<KnownPeer>>>()
+        .wrap_err("Failed to read the known peers")?;
+    Ok(peers)
+}
+
+///Forget the peers not seen since `seen_before`, or that failed us too often. How many went
+pub fn prune_known_peers(seen_before: i64, max_failures: u32, db: &DbConnection) -> Result<usize> {
+    let sql = "DELETE FROM known_peer WHERE last_seen < ?1 OR failures >= ?2";
+    db.conn
+        .execute(sql, params![seen_before, max_failures])
+        .wrap_err("Failed to prune the known peers")
+}
+
+///How the last announce to the tracker went
+pub fn save_tracker_status(
+    info_hash: &InfoHash,
+    status: &TrackerStatus,
+    db: &DbConnection,
+) -> Result<()> {
+    let sql = "INSERT OR REPLACE INTO tracker (torrent_id, url, last_announce, last_error, peers, next_announce, seeders, leechers) SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8 FROM torrent WHERE info_hash = ?1";
+    let saved = db
+        .conn
+        .execute(
+            sql,
+            params![
+                info_hash_hex(info_hash),
+                status.url,
+                status.last_announce,
+                status.last_error,
+                status.peers,
+                status.next_announce,
+                status.seeders,
+                status.leechers
+            ],
+        )
+        .wrap_err("Failed to save the tracker status")?;
+    if saved == 0 {
+        return Err(no_torrent(info_hash));
+    }
+    Ok(())
+}
+
+///Every tracker of the torrent, the ones we never announced to included
+pub fn list_tracker_status(info_hash: &InfoHash, db: &DbConnection) -> Result<Vec<TrackerStatus>> {
+    let sql = "SELECT r.url, r.last_announce, r.last_error, r.peers, r.next_announce, r.seeders, r.leechers FROM tracker r JOIN torrent t ON r.torrent_id = t.id WHERE t.info_hash = ?1";
+    let mut stmt = db
+        .conn
+        .prepare(sql)
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list trackers statement")?;
+    let mut announced = stmt
+        .query_map(params![info_hash_hex(info_hash)], |row| {
+            Ok(TrackerStatus {
+                url: row.get(0)?,
+                last_announce: row.get(1)?,
+                last_error: row.get(2)?,
+                peers: row.get(3)?,
+                next_announce: row.get(4)?,
+                seeders: row.get(5)?,
+                leechers: row.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<TrackerStatus>>>()
+        .wrap_err("Failed to read the trackers")?;
+    let trackers: Option<String> = db
+        .conn
+        .query_row(
+            "SELECT trackers FROM torrent WHERE info_hash = ?1",
+            params![info_hash_hex(info_hash)],
+            |row| row.get(0),
+        )
+        .optional()
+        .wrap_err("Error retrieving the trackers by info hash")?
+        .ok_or_else(|| no_torrent(info_hash))?;
+    //in the order the torrent lists them, anything else after
+    let mut statuses = Vec::new();
+    for url in trackers.as_deref().unwrap_or("").lines() {
+        match announced.iter().position(|s| s.url == url) {
+            Some(index) => statuses.push(announced.remove(index)),
+            None => statuses.push(TrackerStatus {
+                url: url.to_owned(),
+                ..TrackerStatus::default()
+            }),
+        }
+    }
+    statuses.append(&mut announced);
+    Ok(statuses)
+}
+
+///The pieces we had verified, as a bitfield, so the next session doesn't start from nothing
+pub fn save_have(info_hash: &InfoHash, have: &[u8], db: &DbConnection) -> Result<()> {
+    db.conn
+        .execute(
+            "UPDATE torrent SET have = ?1 WHERE info_hash = ?2",
+            params![have, info_hash_hex(info_hash)],
+        )
+        .wrap_err("Failed to save the verified pieces")?;
+    Ok(())
+}
+
+pub fn select_have(info_hash: &InfoHash, db: &DbConnection) -> Result<Option<Vec<u8>>> {
+    db.conn
+        .query_row(
+            "SELECT have FROM torrent WHERE info_hash = ?1",
+            params![info_hash_hex(info_hash)],
+            |row| row.get(0),
+        )
+        .optional()
+        .map(Option::flatten)
+        .wrap_err("Error retrieving the verified pieces by info hash")
+}
+
+///Every torrent, in queue order, as it goes into an archive. The raw bytes go as they are,
+///so even a row we can't read any more makes it across
+pub fn export_torrents(db: &DbConnection) -> Result<Vec<ArchivedTorrent>> {
+    let sql = "SELECT id, name, torrent_file_raw, file_path, save_path, state, have, trackers, downloaded, uploaded, seeding_time, download_limit, upload_limit, seed_ratio_limit, seed_time_limit, seed_idle_limit, seed_limit_action, sequential FROM torrent ORDER BY queue_position, id";
+    let mut stmt = db
+        .conn
+        .prepare(sql)
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the export statement")?;
+    let torrents = stmt
+        .query_map([], |row| {
+            let id: i64 = row.get(0)?;
+            let ratio: Option<f64> = row.get(13)?;
+            let have: Option<Vec<u8>> = row.get(6)?;
+            let trackers: Option<String> = row.get(7)?;
+            let state: Option<String> = row.get(5)?;
+            let seeding_time: Option<u64> = row.get(10)?;
+            let sequential: Option<bool> = row.get(17)?;
+            Ok((
+                id,
+                ArchivedTorrent {
+                    name: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    torrent: row.get(2)?,
+                    file_path: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    save_path: row.get(4)?,
+                    state: state.unwrap_or_else(|| TorrentState::default().to_string()),
+                    have: have.unwrap_or_default(),
+                    trackers: trackers.unwrap_or_default(),
+                    downloaded: row.get::<_, Option<u64>>(8)?.unwrap_or(0),
+                    uploaded: row.get::<_, Option<u64>>(9)?.unwrap_or(0),
+                    seeding_time: seeding_time.unwrap_or(0),
+                    download_limit: row.get(11)?,
+                    upload_limit: row.get(12)?,
+                    seed_ratio_limit: ratio.map(|r| r.to_string()),
+                    seed_time_limit: row.get(14)?,
+                    seed_idle_limit: row.get(15)?,
+                    seed_limit_action: row.get(16)?,
+                    sequential: sequential.unwrap_or(false),
+                    files: Vec::new(),
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<(i64, ArchivedTorrent)>>>()
+        .wrap_err("Failed to read the torrents to export")?;
+    let mut stmt = db
+        .conn
+        .prepare(
+            "SELECT path, priority, sequential FROM torrent_file WHERE torrent_id = ?1 ORDER BY id",
+        )
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the export files statement")?;
+    let mut archived = Vec::new();
+    for (id, mut torrent) in torrents {
+        torrent.files = stmt
+            .query_map(params![id], |row| {
+                let priority: Option<String> = row.get(1)?;
+                let sequential: Option<bool> = row.get(2)?;
+                Ok(ArchivedFile {
+                    path: row.get(0)?,
+                    priority: priority
+                        .unwrap_or_else(|| FilePriority::default().as_str().to_owned()),
+                    sequential: sequential.unwrap_or(false),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ArchivedFile>>>()
+            .wrap_err("Failed to read the files to export")?;
+        archived.push(torrent);
+    }
+    Ok(archived)
+}
+
+///Bring an archived torrent into this db. A new one comes in as it was, at the back of the
+///queue. One we already have, by info hash, is skipped, or with `merge` keeps what it has
+///and only picks up the trackers, settings and progress it is missing
+pub fn import_torrent(
+    archived: &ArchivedTorrent,
+    merge: bool,
+    db: &DbConnection,
+) -> Result<ImportOutcome> {
+    let torrent_file: TorrentFile = serde_bencode::from_bytes(&archived.torrent)
+        .map_err(DbError::from)
+        .wrap_err_with(|| format!("The torrent bytes of {} are unreadable", archived.name))?;
+    let info_hash = torrent_file.info_hash;
+    let known = select_torrent_by_info_hash(&info_hash, db)?.is_some();
+    if known && !merge {
+        return Ok(ImportOutcome::Skipped);
+    }
+    //all or nothing, a half imported torrent would be skipped as already here next time
+    let tx = db.conn.unchecked_transaction()?;
+    if !known {
+        let torrent = Torrent {
+            name: archived.name.clone(),
+            file_path: archived.file_path.clone(),
+            announce_url: torrent_file.announce.clone(),
+            raw_bytes: archived.torrent.clone(),
+            size: get_size(&torrent_file),
+            downloaded: 0,
+            uploaded: 0,
+            download_limit: None,
+            upload_limit: None,
+            torrent_file,
+        };
+        upsert_torrent(&torrent, &tx)?;
+    }
+    let mut trackers = list_trackers(&info_hash, db)?.join("\n");
+    for tracker in archived.trackers.lines() {
+        trackers = merge_trackers(Some(&trackers), Some(tracker));
+    }
+    //whatever the row has already wins, a new row has nothing but its progress.
+    //The files are likely not on this machine yet, so pieces that come with the archive
+    //are only trusted once a recheck has found them
+    let sql = "UPDATE torrent SET trackers = ?1, save_path = COALESCE(save_path, ?2), recheck = CASE WHEN have IS NULL AND ?3 IS NOT NULL THEN 1 ELSE recheck END, have = COALESCE(have, ?3), downloaded = MAX(COALESCE(downloaded, 0), ?4), uploaded = MAX(COALESCE(uploaded, 0), ?5), seeding_time = MAX(COALESCE(seeding_time, 0), ?6), download_limit = COALESCE(download_limit, ?7), upload_limit = COALESCE(upload_limit, ?8), seed_ratio_limit = COALESCE(seed_ratio_limit, ?9), seed_time_limit = COALESCE(seed_time_limit, ?10), seed_idle_limit = COALESCE(seed_idle_limit, ?11), seed_limit_action = COALESCE(seed_limit_action, ?12), sequential = MAX(COALESCE(sequential, 0), ?13) WHERE info_hash = ?14";
+    let have = (!archived.have.is_empty()).then_some(&archived.have);
+    let ratio = archived
+        .seed_ratio_limit
+        .as_deref()
+        .and_then(|r| r.parse::<f64>().ok());
+    let info_hash = info_hash_hex(&info_hash);
+    tx.execute(
+        sql,
+        params![
+            trackers,
+            archived.save_path,
+            have,
+            archived.downloaded,
+            archived.uploaded,
+            archived.seeding_time,
+            archived.download_limit,
+            archived.upload_limit,
+            ratio,
+            archived.seed_time_limit,
+            archived.seed_idle_limit,
+            archived.seed_limit_action,
+            archived.sequential,
+            info_hash
+        ],
+    )
+    .wrap_err("Failed to import the torrent settings")?;
+    if known {
+        tx.commit()?;
+        return Ok(ImportOutcome::Merged);
+    }
+    let state = TorrentState::parse(&archived.state).unwrap_or_default();
+    tx.execute(
+        "UPDATE torrent SET state = ?1 WHERE info_hash = ?2",
+        params![state.as_str(), info_hash],
+    )
+    .wrap_err("Failed to import the torrent state")?;
+    let sql = "UPDATE torrent_file SET priority = ?1, sequential = ?2 WHERE path = ?3 AND torrent_id = (SELECT id FROM torrent WHERE info_hash = ?4)";
+    for file in &archived.files {
+        let priority = FilePriority::parse(&file.priority).unwrap_or_default();
+        tx.execute(
+            sql,
+            params![priority.as_str(), file.sequential, file.path, info_hash],
+        )
+        .wrap_err("Failed to import the file settings")?;
+    }
+    tx.commit()?;
+    Ok(ImportOutcome::Added)
+}
+
+///Remember a banned peer for future sessions
+pub fn save_banned_peer(ip: &str, reason: &str, db: &DbConnection) -> Result<()> {
+    let sql = "INSERT OR REPLACE INTO banned_peer (ip, reason) VALUES (?1, ?2)";
+    db.conn
+        .execute(sql, params![ip, reason])
+        .wrap_err("Failed to save the banned peer")?;
+    Ok(())
+}
+
+///The ips of every peer we have banned before
+pub fn list_banned_peers(db: &DbConnection) -> Result<Vec<String>> {
+    let mut stmt = db
+        .conn
+        .prepare("SELECT ip FROM banned_peer")
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list banned peers statement")?;
+    let ips = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .wrap_err("Failed to read the banned peers")?;
+    Ok(ips)
+}
+
+///(info hash, name) of the torrents, front of the queue first
+pub fn list_queue(db: &DbConnection) -> Result<Vec<(InfoHash, String)>> {
+    let mut stmt = db
+        .conn
+        .prepare("SELECT info_hash, name FROM torrent WHERE info_hash IS NOT NULL ORDER BY queue_position, id")
+        .map_err(DbError::from)
+        .wrap_err("Failed to prepare the list queue statement")?;
+    let queue = stmt
+        .query_map([], |row| {
+            Ok((
+                hash_from_row(row, 0)?,
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<(InfoHash, String)>>>()
+        .wrap_err("Failed to read the queue")?;
+    Ok(queue)
+}
+
+///Move a torrent in the queue, renumbering the whole queue as we go
+pub fn move_in_queue(
+    info_hash: &InfoHash,
+    queue_move: QueueMove,
+    db: &DbConnection,
+) -> Result<Vec<(InfoHash, String)>> {
+    let mut queue = list_queue(db)?;
+    let index = queue
+        .iter()
+        .position(|(hash, _)| hash == info_hash)
+        .ok_or_else(|| no_torrent(info_hash))?;
+    let moved = queue.remove(index);
+    queue.insert(queue_move.apply(index, queue.len() + 1), moved);
+
+    let tx = db.conn.unchecked_transaction()?;
+    for (position, (hash, _)) in queue.iter().enumerate() {
+        tx.execute(
+            "UPDATE torrent SET queue_position = ?1 WHERE info_hash = ?2",
+            params![position + 1, info_hash_hex(hash)],
+        )
+        .wrap_err("Failed to save the queue position")?;
+    }
+    tx.commit()?;
+    Ok(queue)
 }
 
 #[cfg(test)]
@@ -119,6 +1236,8 @@ pub mod test {
 
     use super::*;
 
+    const NO_SUCH_TORRENT: InfoHash = [0xee; 20];
+
     #[test]
     fn test_init_tables() {
         let db = init_test_conn();
@@ -142,13 +1261,8 @@ pub mod test {
 
     pub fn init_test_conn() -> DbConnection {
         let conn = Connection::open_in_memory().unwrap();
-        let name = String::from("Foom test name");
         let db_name = String::from("Foom db name but it is in memory tee and, indeed, hee");
-        let db = DbConnection {
-            conn,
-            name,
-            db_name,
-        };
+        let db = DbConnection { conn, db_name };
         init_tables(&db).unwrap();
         db
     }
@@ -161,15 +1275,594 @@ pub mod test {
 
         save_torrent_file(&torrent, &db).unwrap();
 
-        let torrents = list_torrent_files(&db).unwrap();
+        let torrents = list_torrent_files(&db).unwrap().torrents;
         assert_eq!(1, torrents.len());
 
         torrents
             .iter()
             .for_each(|torrent| info!("This is the name of the torrent: {}", torrent.name));
 
-        let torrent_name_to_test = file_name.replace(".torrent", "");
-        let retrieved_torrent = select_torrent_file(&torrent_name_to_test, &db).unwrap();
+        let retrieved_torrent = select_torrent_by_info_hash(&torrent.torrent_file.info_hash, &db)
+            .unwrap()
+            .unwrap();
         assert_eq!(torrent.announce_url, retrieved_torrent.announce_url);
+        assert_eq!(torrent.size, retrieved_torrent.size);
+        assert_eq!(torrent.torrent_file, retrieved_torrent.torrent_file);
+    }
+
+    #[test]
+    fn test_corrupted_torrent_row() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let mut broken = torrent.clone();
+        broken.torrent_file.info.name = Some("broken".to_owned());
+        broken.torrent_file.info_hash[0] ^= 1;
+        save_torrent_file(&broken, &db).unwrap();
+        db.conn
+            .execute(
+                "UPDATE torrent SET torrent_file_raw = x'64343a6e6f6f6d' WHERE name = 'broken'",
+                [],
+            )
+            .unwrap();
+
+        let list = list_torrent_files(&db).unwrap();
+        assert_eq!(1, list.torrents.len());
+        assert_eq!(torrent.info_name(), list.torrents[0].name);
+        assert_eq!(1, list.unreadable.len());
+        assert_eq!(Some("broken".to_owned()), list.unreadable[0].name);
+        assert!(matches!(
+            list.unreadable[0].error,
+            DbError::Deserializetion(_)
+        ));
+
+        let err = select_torrent_by_info_hash(&broken.torrent_file.info_hash, &db).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Deserializetion(_))
+        ));
+        //the rest carry on as normal
+        assert!(
+            select_torrent_by_info_hash(&torrent.torrent_file.info_hash, &db)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_save_torrent_again() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        save_torrent_rate_limits(&torrent.torrent_file.info_hash, Some(1024), None, &db).unwrap();
+
+        let mut other_tracker = torrent.clone();
+        other_tracker.torrent_file.announce = Some("http://tracker.example/announce".to_owned());
+        save_torrent_file(&other_tracker, &db).unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        assert_eq!(1, list_torrent_files(&db).unwrap().torrents.len());
+        assert_eq!(
+            2,
+            list_files(&torrent.torrent_file.info_hash, &db)
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            vec![
+                torrent.torrent_file.announce.clone().unwrap(),
+                "http://tracker.example/announce".to_owned()
+            ],
+            list_trackers(&torrent.torrent_file.info_hash, &db).unwrap()
+        );
+        let found = select_torrent_by_info_hash(&torrent.torrent_file.info_hash, &db)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(1024), found.download_limit);
+    }
+
+    #[test]
+    fn test_identity() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert!(save_identity(&info_hash, "-OX0-1-0-abcdefghijk", "key", 5, &db).is_err());
+        save_torrent_file(&torrent, &db).unwrap();
+        assert_eq!(None, select_identity(&info_hash, &db).unwrap());
+
+        save_identity(&info_hash, "-OX0-1-0-abcdefghijk", "key", 5, &db).unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        assert_eq!(
+            Some(("-OX0-1-0-abcdefghijk".to_owned(), "key".to_owned(), 5)),
+            select_identity(&info_hash, &db).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_states() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert_eq!(
+            (TorrentState::Queued, None),
+            select_state(&info_hash, &db).unwrap()
+        );
+
+        save_state(&info_hash, TorrentState::Errored, Some("disk full"), &db).unwrap();
+        save_state(&info_hash, TorrentState::Queued, None, &db).unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        assert_eq!(
+            (TorrentState::Queued, Some("disk full".to_owned())),
+            select_state(&info_hash, &db).unwrap()
+        );
+        assert!(save_state(&NO_SUCH_TORRENT, TorrentState::Paused, None, &db).is_err());
+
+        assert_eq!(None, select_requested_state(&info_hash, &db).unwrap());
+        request_state(&info_hash, TorrentState::Paused, &db).unwrap();
+        assert_eq!(
+            Some(TorrentState::Paused),
+            select_requested_state(&info_hash, &db).unwrap()
+        );
+        clear_requested_state(&info_hash, &db).unwrap();
+        assert_eq!(None, select_requested_state(&info_hash, &db).unwrap());
+        assert!(request_state(&NO_SUCH_TORRENT, TorrentState::Paused, &db).is_err());
+    }
+
+    #[test]
+    fn test_transfer_history() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        let sample = |sampled_at: i64, download_rate: u64| TransferSample {
+            info_hash,
+            name: torrent.info_name(),
+            sampled_at,
+            downloaded: sampled_at as u64 * 10,
+            download_rate,
+            peers: 2,
+            samples: 1,
+            ..TransferSample::default()
+        };
+        //a minute apart, the first four in one quarter hour
+        for (minute, rate) in [(0, 10), (1, 20), (2, 30), (14, 40), (15, 50), (30, 60)] {
+            save_sample(&sample(minute * 60, rate), &db).unwrap();
+        }
+        assert!(save_sample(
+            &TransferSample {
+                info_hash: NO_SUCH_TORRENT,
+                ..sample(0, 0)
+            },
+            &db
+        )
+        .is_err());
+        assert_eq!(6, list_samples(Some(&info_hash), 0, &db).unwrap().len());
+        assert_eq!(2, list_samples(None, 15 * 60, &db).unwrap().len());
+        assert!(list_samples(Some(&NO_SUCH_TORRENT), 0, &db)
+            .unwrap()
+            .is_empty());
+
+        //the first two get merged, then the next two join them
+        downsample_history(2 * 60, 15 * 60, None, &db).unwrap();
+        let samples = list_samples(Some(&info_hash), 0, &db).unwrap();
+        assert_eq!(5, samples.len());
+        assert_eq!(
+            (60, 15, 2),
+            (
+                samples[0].sampled_at,
+                samples[0].download_rate,
+                samples[0].samples
+            )
+        );
+        downsample_history(15 * 60, 15 * 60, None, &db).unwrap();
+        let samples = list_samples(Some(&info_hash), 0, &db).unwrap();
+        assert_eq!(3, samples.len());
+        assert_eq!(14 * 60, samples[0].sampled_at);
+        assert_eq!(14 * 600, samples[0].downloaded);
+        assert_eq!(25, samples[0].download_rate);
+        assert_eq!(4, samples[0].samples);
+        //nothing new to merge
+        downsample_history(15 * 60, 15 * 60, None, &db).unwrap();
+        assert_eq!(3, list_samples(Some(&info_hash), 0, &db).unwrap().len());
+
+        downsample_history(0, 15 * 60, Some(30 * 60), &db).unwrap();
+        assert_eq!(1, list_samples(Some(&info_hash), 0, &db).unwrap().len());
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        let peer = |port: u16| Peer {
+            ip: "10.0.0.1".to_owned(),
+            port,
+        };
+        //never got through, so nothing to count it against
+        save_peer_failed(&info_hash, &peer(1), &db).unwrap();
+        assert!(list_known_peers(&info_hash, 10, &db).unwrap().is_empty());
+
+        save_peer_connected(
+            &info_hash,
+            &peer(1),
+            Some("qBittorrent 4250"),
+            10,
+            5,
+            100,
+            &db,
+        )
+        .unwrap();
+        save_peer_connected(&info_hash, &peer(2), None, 0, 0, 200, &db).unwrap();
+        save_peer_connected(&info_hash, &peer(1), None, 10, 0, 150, &db).unwrap();
+        save_peer_failed(&info_hash, &peer(2), &db).unwrap();
+        let known = list_known_peers(&info_hash, 10, &db).unwrap();
+        assert_eq!(
+            vec![
+                KnownPeer {
+                    ip: "10.0.0.1".to_owned(),
+                    port: 1,
+                    last_seen: 150,
+                    downloaded: 20,
+                    uploaded: 5,
+                    failures: 0,
+                    client: Some("qBittorrent 4250".to_owned()),
+                },
+                KnownPeer {
+                    ip: "10.0.0.1".to_owned(),
+                    port: 2,
+                    last_seen: 200,
+                    failures: 1,
+                    ..KnownPeer::default()
+                }
+            ],
+            known
+        );
+        assert_eq!(1, list_known_peers(&info_hash, 1, &db).unwrap().len());
+
+        save_peer_failed(&info_hash, &peer(2), &db).unwrap();
+        assert_eq!(1, prune_known_peers(0, 2, &db).unwrap());
+        assert_eq!(1, prune_known_peers(151, 2, &db).unwrap());
+        assert!(list_known_peers(&info_hash, 10, &db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        save_state(&info_hash, TorrentState::Paused, None, &db).unwrap();
+        save_have(&info_hash, &[0b1100_0000], &db).unwrap();
+        save_torrent_progress(&info_hash, 500, 20, Duration::from_secs(60), &db).unwrap();
+        save_seeding_policy(&info_hash, Some(1.5), None, None, None, &db).unwrap();
+        save_file_priority(&info_hash, 0, FilePriority::High, &db).unwrap();
+        let exported = export_torrents(&db).unwrap();
+        assert_eq!(1, exported.len());
+        assert_eq!(Some("1.5".to_owned()), exported[0].seed_ratio_limit);
+
+        let other = init_test_conn();
+        assert_eq!(
+            ImportOutcome::Added,
+            import_torrent(&exported[0], false, &other).unwrap()
+        );
+        assert_eq!(exported, export_torrents(&other).unwrap());
+        //the pieces have to be found on this machine before they count
+        assert!(select_recheck_requested(&info_hash, &other).unwrap());
+        assert_eq!(
+            ImportOutcome::Skipped,
+            import_torrent(&exported[0], false, &other).unwrap()
+        );
+
+        //a merge keeps what is there and adds what isn't
+        let mut newer = exported[0].clone();
+        newer.trackers.push_str("\nhttp://other/announce");
+        newer.uploaded = 80;
+        newer.state = "seeding".to_owned();
+        assert_eq!(
+            ImportOutcome::Merged,
+            import_torrent(&newer, true, &other).unwrap()
+        );
+        let merged = &export_torrents(&other).unwrap()[0];
+        assert_eq!(newer.trackers, merged.trackers);
+        assert_eq!(80, merged.uploaded);
+        assert_eq!("paused", merged.state);
+
+        let mut broken = newer;
+        broken.torrent = b"not a torrent".to_vec();
+        assert!(import_torrent(&broken, true, &other).is_err());
+    }
+
+    #[test]
+    fn test_global_rate_limits() {
+        let db = init_test_conn();
+        assert_eq!((None, None), select_global_rate_limits(&db).unwrap());
+        save_global_rate_limits(Some(512), None, &db).unwrap();
+        assert_eq!((Some(512), None), select_global_rate_limits(&db).unwrap());
+        save_global_rate_limits(None, Some(0), &db).unwrap();
+        assert_eq!(
+            (Some(512), Some(0)),
+            select_global_rate_limits(&db).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tracker_status() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        let url = torrent.torrent_file.announce.clone().unwrap();
+        let statuses = list_tracker_status(&info_hash, &db).unwrap();
+        assert_eq!(1, statuses.len());
+        assert_eq!(None, statuses[0].last_announce);
+
+        let status = TrackerStatus {
+            url: url.clone(),
+            last_announce: Some(100),
+            peers: Some(30),
+            next_announce: Some(1900),
+            seeders: Some(10),
+            leechers: Some(2),
+            ..TrackerStatus::default()
+        };
+        save_tracker_status(&info_hash, &status, &db).unwrap();
+        let failed = TrackerStatus {
+            url: url.clone(),
+            last_announce: Some(200),
+            last_error: Some("Timed out".to_owned()),
+            ..TrackerStatus::default()
+        };
+        save_tracker_status(&info_hash, &failed, &db).unwrap();
+        assert_eq!(vec![failed], list_tracker_status(&info_hash, &db).unwrap());
+        assert!(save_tracker_status(&NO_SUCH_TORRENT, &status, &db).is_err());
+        assert!(list_tracker_status(&NO_SUCH_TORRENT, &db).is_err());
+    }
+
+    #[test]
+    fn test_save_banned_peer() {
+        let db = init_test_conn();
+        save_banned_peer("6.6.6.6", "3 hash failures", &db).unwrap();
+        //banning twice does not duplicate
+        save_banned_peer("6.6.6.6", "4 hash failures", &db).unwrap();
+        assert_eq!(vec!["6.6.6.6".to_owned()], list_banned_peers(&db).unwrap());
+    }
+
+    #[test]
+    fn test_save_torrent_rate_limits() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert_eq!(
+            (None, None),
+            select_torrent_rate_limits(&info_hash, &db).unwrap()
+        );
+
+        save_torrent_rate_limits(&info_hash, Some(1024), None, &db).unwrap();
+        assert_eq!(
+            (Some(1024), None),
+            select_torrent_rate_limits(&info_hash, &db).unwrap()
+        );
+        assert!(save_torrent_rate_limits(&NO_SUCH_TORRENT, None, None, &db).is_err());
+    }
+
+    #[test]
+    fn test_seeding_policy_and_progress() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        let default = SeedingPolicy {
+            ratio: Some(1.0),
+            idle_minutes: Some(30),
+            ..SeedingPolicy::default()
+        };
+        assert_eq!(
+            default,
+            select_seeding_policy(&info_hash, &default, &db).unwrap()
+        );
+
+        save_seeding_policy(
+            &info_hash,
+            Some(2.5),
+            None,
+            None,
+            Some(SeedingAction::Pause),
+            &db,
+        )
+        .unwrap();
+        let policy = select_seeding_policy(&info_hash, &default, &db).unwrap();
+        assert_eq!(Some(2.5), policy.ratio);
+        assert_eq!(Some(30), policy.idle_minutes);
+        assert_eq!(SeedingAction::Pause, policy.action);
+        //setting one goal leaves the others be
+        save_seeding_policy(&info_hash, None, Some(60), None, None, &db).unwrap();
+        let policy = select_seeding_policy(&info_hash, &default, &db).unwrap();
+        assert_eq!(Some(2.5), policy.ratio);
+        assert_eq!(Some(60), policy.seeding_minutes);
+        assert_eq!(SeedingAction::Pause, policy.action);
+
+        save_torrent_progress(&info_hash, 100, 250, Duration::from_secs(90), &db).unwrap();
+        assert_eq!(
+            (100, 250, Duration::from_secs(90)),
+            select_torrent_progress(&info_hash, &db).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        let files = list_files(&info_hash, &db).unwrap();
+        assert_eq!(2, files.len());
+        assert!(files.iter().all(|f| f.priority == FilePriority::Normal));
+        let total: u64 = files.iter().map(|f| f.size).sum();
+        assert_eq!(torrent.size, total);
+
+        let path = save_file_priority(&info_hash, 1, FilePriority::Skip, &db).unwrap();
+        assert_eq!(files[1].path, path);
+        //saving the torrent again keeps the priority
+        save_torrent_file(&torrent, &db).unwrap();
+        let files = list_files(&info_hash, &db).unwrap();
+        assert_eq!(FilePriority::Skip, files[1].priority);
+        assert_eq!(FilePriority::Normal, files[0].priority);
+        assert!(save_file_priority(&info_hash, 2, FilePriority::High, &db).is_err());
+    }
+
+    #[test]
+    fn test_sequential_mode() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert!(!select_sequential(&info_hash, &db).unwrap());
+
+        save_sequential(&info_hash, None, true, &db).unwrap();
+        assert!(select_sequential(&info_hash, &db).unwrap());
+        save_sequential(&info_hash, Some(0), true, &db).unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let files = list_files(&info_hash, &db).unwrap();
+        assert!(files[0].sequential);
+        assert!(!files[1].sequential);
+        assert!(save_sequential(&NO_SUCH_TORRENT, None, true, &db).is_err());
+    }
+
+    #[test]
+    fn test_location() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert_eq!(None, select_location(&info_hash, &db).unwrap());
+
+        let incomplete = Location::incomplete(Path::new("/downloads/incomplete"), true);
+        save_location(&info_hash, &incomplete, &db).unwrap();
+        assert_eq!(
+            Some(SavedLocation::Known(incomplete)),
+            select_location(&info_hash, &db).unwrap()
+        );
+        let complete = Location::complete(Path::new("/downloads"));
+        save_location(&info_hash, &complete, &db).unwrap();
+        //still there after the torrent gets saved again on the next run
+        save_torrent_file(&torrent, &db).unwrap();
+        assert_eq!(
+            Some(SavedLocation::Known(complete.clone())),
+            select_location(&info_hash, &db).unwrap()
+        );
+        assert!(save_location(&NO_SUCH_TORRENT, &complete, &db).is_err());
+        //saved before completeness was kept
+        db.conn
+            .execute("UPDATE torrent SET save_path_complete = NULL", [])
+            .unwrap();
+        assert_eq!(
+            Some(SavedLocation::Legacy(PathBuf::from("/downloads"))),
+            select_location(&info_hash, &db).unwrap()
+        );
+
+        assert_eq!(None, select_pending_move(&info_hash, &db).unwrap());
+        request_move(&info_hash, Path::new("/bigger/disk"), &db).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/bigger/disk")),
+            select_pending_move(&info_hash, &db).unwrap()
+        );
+        clear_pending_move(&info_hash, &db).unwrap();
+        assert_eq!(None, select_pending_move(&info_hash, &db).unwrap());
+        assert!(request_move(&NO_SUCH_TORRENT, Path::new("/"), &db).is_err());
+    }
+
+    #[test]
+    fn test_recheck_requests() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert!(!select_recheck_requested(&info_hash, &db).unwrap());
+        request_recheck(&info_hash, true, &db).unwrap();
+        save_torrent_file(&torrent, &db).unwrap();
+        assert!(select_recheck_requested(&info_hash, &db).unwrap());
+        request_recheck(&info_hash, false, &db).unwrap();
+        assert!(!select_recheck_requested(&info_hash, &db).unwrap());
+        assert!(!select_recheck_requested(&NO_SUCH_TORRENT, &db).unwrap());
+    }
+
+    #[test]
+    fn test_queue_positions() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+            let mut renamed = torrent.clone();
+            renamed.torrent_file.info.name = Some(name.to_owned());
+            renamed.torrent_file.info_hash[0] = i as u8;
+            save_torrent_file(&renamed, &db).unwrap();
+        }
+        let names = |db: &DbConnection| -> Vec<String> {
+            list_queue(db)
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect()
+        };
+        let hash = |i: u8| {
+            let mut info_hash = torrent.torrent_file.info_hash;
+            info_hash[0] = i;
+            info_hash
+        };
+        assert_eq!(vec!["a", "b", "c"], names(&db));
+
+        move_in_queue(&hash(2), QueueMove::Top, &db).unwrap();
+        move_in_queue(&hash(0), QueueMove::Down, &db).unwrap();
+        assert_eq!(vec!["c", "b", "a"], names(&db));
+
+        //adding a torrent again keeps its place
+        let mut again = torrent.clone();
+        again.torrent_file.info.name = Some("c".to_owned());
+        again.torrent_file.info_hash[0] = 2;
+        save_torrent_file(&again, &db).unwrap();
+        assert_eq!(vec!["c", "b", "a"], names(&db));
+        assert!(move_in_queue(&NO_SUCH_TORRENT, QueueMove::Up, &db).is_err());
+    }
+
+    #[test]
+    fn test_torrents_sharing_a_name() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        let mut twin = torrent.clone();
+        twin.torrent_file.info_hash[0] ^= 1;
+        save_torrent_file(&torrent, &db).unwrap();
+        save_torrent_file(&twin, &db).unwrap();
+        let (first, second) = (torrent.torrent_file.info_hash, twin.torrent_file.info_hash);
+        assert_eq!(2, list_queue(&db).unwrap().len());
+
+        save_state(&first, TorrentState::Paused, None, &db).unwrap();
+        save_location(&second, &Location::complete(Path::new("/elsewhere")), &db).unwrap();
+        request_recheck(&second, true, &db).unwrap();
+        save_file_priority(&first, 0, FilePriority::Skip, &db).unwrap();
+        assert_eq!(TorrentState::Paused, select_state(&first, &db).unwrap().0);
+        assert_eq!(TorrentState::Queued, select_state(&second, &db).unwrap().0);
+        assert_eq!(None, select_location(&first, &db).unwrap());
+        assert!(select_location(&second, &db).unwrap().is_some());
+        assert!(!select_recheck_requested(&first, &db).unwrap());
+        assert!(select_recheck_requested(&second, &db).unwrap());
+        assert_eq!(
+            FilePriority::Skip,
+            list_files(&first, &db).unwrap()[0].priority
+        );
+        assert_eq!(
+            FilePriority::default(),
+            list_files(&second, &db).unwrap()[0].priority
+        );
+
+        //the name is no good to tell them apart, the info hash is
+        let name = torrent.info_name();
+        assert!(find_torrent(&name, &db).is_err());
+        assert_eq!(second, find_torrent(&info_hash_hex(&second), &db).unwrap());
+        assert_eq!(
+            first,
+            find_torrent(&info_hash_hex(&first).to_uppercase(), &db).unwrap()
+        );
+        assert!(find_torrent("no such torrent", &db).is_err());
     }
 }
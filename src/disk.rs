@@ -0,0 +1,402 @@
+use color_eyre::eyre::{eyre, Result};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+use crate::hashing::{hash_pieces, piece_matches, HashProgress};
+use crate::storage::Storage;
+
+const MIB: u64 = 1024 * 1024;
+
+///The runtime has a single thread, so anything slow on the disk, or hashing, happens over here
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiskSettings {
+    pub threads: usize,
+    ///Threads a recheck hashes on, on top of the one reading
+    pub hash_threads: usize,
+    ///Verified pieces held back per torrent before they have to go to disk
+    pub write_cache_mib: u64,
+    ///Recently read pieces kept around per torrent, for peers that want the same ones
+    pub read_cache_mib: u64,
+}
+
+impl Default for DiskSettings {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            hash_threads: thread::available_parallelism().map_or(2, |n| n.get()),
+            write_cache_mib: 16,
+            read_cache_mib: 32,
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+///Worker threads that do the blocking work and hand the result back to the runtime
+pub struct DiskPool {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    write_cache_bytes: u64,
+    read_cache_bytes: u64,
+    hash_threads: usize,
+}
+
+impl DiskPool {
+    pub fn new(settings: &DiskSettings) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..settings.threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("disk-{i}"))
+                .spawn(move || loop {
+                    //the lock is only held while waiting, never while working
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        //the pool is gone
+                        Err(_) => return,
+                    }
+                })?;
+        }
+        Ok(Self {
+            jobs: Mutex::new(sender),
+            write_cache_bytes: settings.write_cache_mib * MIB,
+            read_cache_bytes: settings.read_cache_mib * MIB,
+            hash_threads: settings.hash_threads,
+        })
+    }
+
+    ///Run the job on one of the threads, waiting on it without blocking the runtime
+    pub async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.jobs
+            .lock()
+            .map_err(|_| eyre!("Disk pool poisoned"))?
+            .send(Box::new(move || {
+                let _ = sender.send(job());
+            }))
+            .map_err(|_| eyre!("The disk threads are gone"))?;
+        receiver
+            .await
+            .map_err(|_| eyre!("A disk job fell over before finishing"))
+    }
+}
+
+///Verified pieces not on disk yet
+#[derive(Default)]
+struct WriteCache {
+    pieces: BTreeMap<u32, Arc<Vec<u8>>>,
+    bytes: u64,
+}
+
+///Pieces read lately, the oldest goes first when it is full
+#[derive(Default)]
+struct ReadCache {
+    pieces: HashMap<u32, Arc<Vec<u8>>>,
+    order: VecDeque<u32>,
+    bytes: u64,
+}
+
+impl ReadCache {
+    fn get(&mut self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let piece = self.pieces.get(&index)?.clone();
+        self.order.retain(|i| *i != index);
+        self.order.push_back(index);
+        Some(piece)
+    }
+
+    fn insert(&mut self, index: u32, piece: Arc<Vec<u8>>, limit: u64) {
+        if piece.len() as u64 > limit || self.pieces.contains_key(&index) {
+            return;
+        }
+        self.bytes += piece.len() as u64;
+        self.pieces.insert(index, piece);
+        self.order.push_back(index);
+        while self.bytes > limit {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.pieces.remove(&oldest) {
+                self.bytes -= evicted.len() as u64;
+            }
+        }
+    }
+}
+
+///Disk access for one torrent, through the pool and its caches
+pub struct TorrentDisk {
+    storage: Arc<Storage>,
+    pool: Arc<DiskPool>,
+    write_cache: Mutex<WriteCache>,
+    read_cache: Mutex<ReadCache>,
+}
+
+impl TorrentDisk {
+    pub fn new(storage: Arc<Storage>, pool: Arc<DiskPool>) -> Self {
+        Self {
+            storage,
+            pool,
+            write_cache: Mutex::new(WriteCache::default()),
+            read_cache: Mutex::new(ReadCache::default()),
+        }
+    }
+
+    ///Check a whole piece against its hash, handing the data back either way
+    pub async fn verify(&self, data: Vec<u8>, expected: [u8; 20]) -> Result<(Vec<u8>, bool)> {
+        self.pool
+            .run(move || {
+                let hash_ok = piece_matches(&data, &expected);
+                (data, hash_ok)
+            })
+            .await
+    }
+
+    ///Hash what is on disk, on several threads. Hands back the pieces that check out
+    pub async fn recheck(
+        &self,
+        pieces: Vec<(u32, [u8; 20])>,
+        progress: Arc<HashProgress>,
+    ) -> Result<Vec<u32>> {
+        //whatever is still cached counts too
+        self.flush().await?;
+        let storage = self.storage.clone();
+        let threads = self.pool.hash_threads;
+        self.pool
+            .run(move || hash_pieces(&storage, &pieces, threads, &progress))
+            .await?
+    }
+
+    ///Hold on to a verified piece, it goes to disk with its neighbours once the cache fills up
+    ///or the session flushes
+    pub async fn write_piece(&self, index: u32, data: Vec<u8>) -> Result<()> {
+        let full = {
+            let mut cache = lock(&self.write_cache)?;
+            cache.bytes += data.len() as u64;
+            if let Some(old) = cache.pieces.insert(index, Arc::new(data)) {
+                cache.bytes -= old.len() as u64;
+            }
+            cache.bytes > self.pool.write_cache_bytes
+        };
+        if full {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    ///Write out everything in the write cache, runs of neighbouring pieces in a single write.
+    ///Pieces stay readable from the cache until they are on disk, and stay cached if the write fails
+    pub async fn flush(&self) -> Result<()> {
+        let pieces: Vec<(u32, Arc<Vec<u8>>)> = lock(&self.write_cache)?
+            .pieces
+            .iter()
+            .map(|(index, data)| (*index, data.clone()))
+            .collect();
+        if pieces.is_empty() {
+            return Ok(());
+        }
+        let runs = coalesce(&pieces);
+        let storage = self.storage.clone();
+        let piece_length = storage.piece_length;
+        self.pool
+            .run(move || -> Result<()> {
+                for (first, data) in runs {
+                    storage.write(first as u64 * piece_length, &data)?;
+                }
+                Ok(())
+            })
+            .await??;
+        let mut cache = lock(&self.write_cache)?;
+        for (index, data) in &pieces {
+            //unless it was replaced in the meantime
+            if cache
+                .pieces
+                .get(index)
+                .is_some_and(|d| Arc::ptr_eq(d, data))
+            {
+                cache.pieces.remove(index);
+                cache.bytes -= data.len() as u64;
+            }
+        }
+        debug!("Flushed {} pieces", pieces.len());
+        Ok(())
+    }
+
+    ///Read part of a piece, from the caches if we can
+    pub async fn read(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let slice = |piece: &[u8]| -> Result<Vec<u8>> {
+            piece
+                .get(begin as usize..begin as usize + length as usize)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| eyre!("Read past the end of piece {index}"))
+        };
+        if let Some(piece) = lock(&self.write_cache)?.pieces.get(&index) {
+            return slice(piece);
+        }
+        if let Some(piece) = lock(&self.read_cache)?.get(index) {
+            return slice(&piece);
+        }
+        let storage = self.storage.clone();
+        if !storage.is_whole_on_disk(index) {
+            //part of it was never kept, so read just what was asked for
+            return self
+                .pool
+                .run(move || storage.read(index, begin, length))
+                .await?;
+        }
+        let size = storage.piece_size(index) as u32;
+        let piece = Arc::new(
+            self.pool
+                .run(move || storage.read(index, 0, size))
+                .await??,
+        );
+        let limit = self.pool.read_cache_bytes;
+        lock(&self.read_cache)?.insert(index, piece.clone(), limit);
+        slice(&piece)
+    }
+
+    ///Verified pieces that are only in the write cache, not on disk yet
+    pub fn cached_pieces(&self) -> Result<Vec<u32>> {
+        Ok(lock(&self.write_cache)?.pieces.keys().copied().collect())
+    }
+}
+
+///Glue neighbouring pieces together, every piece but the last is full length so they line up
+fn coalesce(pieces: &[(u32, Arc<Vec<u8>>)]) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, u32, Vec<u8>)> = Vec::new();
+    for (index, data) in pieces {
+        match runs.last_mut() {
+            Some((_, last, run)) if *last + 1 == *index => {
+                run.extend_from_slice(data);
+                *last = *index;
+            }
+            _ => runs.push((*index, *index, data.to_vec())),
+        }
+    }
+    runs.into_iter()
+        .map(|(first, _, data)| (first, data))
+        .collect()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+    mutex.lock().map_err(|e| eyre!("Disk cache poisoned: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{FilePriority, PieceMetadata};
+    use crate::piece::{DownloadState, PieceOutcome};
+    use crate::storage::StorageFile;
+    use bitvec::prelude::*;
+    use sha1::{Digest, Sha1};
+    use std::fs;
+
+    fn test_disk(dir: &std::path::Path, write_cache_mib: u64) -> TorrentDisk {
+        let storage = Storage {
+            files: vec![StorageFile {
+                path: dir.join("a"),
+                length: 10,
+                offset: 0,
+                priority: FilePriority::Normal,
+                sequential: false,
+            }],
+            piece_length: 4,
+            ..Storage::default()
+        };
+        let settings = DiskSettings {
+            threads: 2,
+            hash_threads: 2,
+            write_cache_mib,
+            read_cache_mib: 1,
+        };
+        TorrentDisk::new(
+            Arc::new(storage),
+            Arc::new(DiskPool::new(&settings).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let piece = |i: u32, byte: u8| (i, Arc::new(vec![byte; 2]));
+        let runs = coalesce(&[piece(0, 1), piece(1, 2), piece(3, 3)]);
+        assert_eq!(vec![(0, vec![1, 1, 2, 2]), (3, vec![3, 3])], runs);
+    }
+
+    #[tokio::test]
+    async fn test_write_back_and_read_cache() {
+        let dir = std::env::temp_dir().join("torrentox_disk_test");
+        let _ = fs::remove_dir_all(&dir);
+        let disk = test_disk(&dir, 1);
+        disk.write_piece(1, vec![5, 6, 7, 8]).await.unwrap();
+        disk.write_piece(0, vec![1, 2, 3, 4]).await.unwrap();
+        disk.write_piece(2, vec![9, 10]).await.unwrap();
+        //held back, but readable
+        assert!(!dir.join("a").exists());
+        let mut cached = disk.cached_pieces().unwrap();
+        cached.sort();
+        assert_eq!(vec![0, 1, 2], cached);
+        assert_eq!(vec![4, 5], {
+            let mut bytes = disk.read(0, 3, 1).await.unwrap();
+            bytes.extend(disk.read(1, 0, 1).await.unwrap());
+            bytes
+        });
+
+        disk.flush().await.unwrap();
+        assert!(disk.cached_pieces().unwrap().is_empty());
+        assert_eq!(
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            fs::read(dir.join("a")).unwrap()
+        );
+        assert_eq!(vec![6, 7], disk.read(1, 1, 2).await.unwrap());
+        //the second read of the piece never gets to the disk
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec![8], disk.read(1, 3, 1).await.unwrap());
+        assert!(disk.read(0, 0, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_cache_flushes() {
+        let dir = std::env::temp_dir().join("torrentox_disk_flush_test");
+        let _ = fs::remove_dir_all(&dir);
+        let disk = test_disk(&dir, 0);
+        disk.write_piece(0, vec![1, 2, 3, 4]).await.unwrap();
+        assert!(disk.cached_pieces().unwrap().is_empty());
+        assert_eq!(vec![1, 2, 3, 4], fs::read(dir.join("a")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let dir = std::env::temp_dir().join("torrentox_disk_verify_test");
+        let disk = test_disk(&dir, 1);
+        let data = vec![1, 2, 3];
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&Sha1::digest(&data));
+        assert!(disk.verify(data.clone(), hash).await.unwrap().1);
+        assert!(!disk.verify(vec![3, 2, 1], hash).await.unwrap().1);
+        //the way the peers do it, the piece is put together, hashed here and then recorded
+        let mut state = DownloadState::new(vec![PieceMetadata {
+            index: 0,
+            length: data.len(),
+            sha1_hash: hash,
+        }]);
+        state.pick_piece("1.1.1.1:6881", &bitvec![u8, Msb0; 1; 1]);
+        let whole = state.store_block(0, 0, &data, "1.1.1.1:6881").unwrap();
+        let (whole, hash_ok) = disk.verify(whole.unwrap(), hash).await.unwrap();
+        let outcome = state.finish_piece(0, whole, hash_ok).unwrap();
+        assert!(matches!(outcome, PieceOutcome::Verified { index: 0, .. }));
+        assert!(state.is_complete());
+    }
+}
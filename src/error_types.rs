@@ -1,31 +1,32 @@
-use rusqlite::Error as RusqliteError;
-use serde_bencode::Error as BencodeError;
-use thiserror::Error;
-
-#[derive(Debug, thiserror::Error)]
-pub enum TorrentParseError {
-    #[error("Failed to decode the torrent file")]
-    BenCode(#[from] BencodeError),
-    #[error("Failed to read the torrent file")]
-    CannotOpenFile(#[from] std::io::Error),
-}
-
 #[derive(Debug, thiserror::Error)]
 pub enum DbError {
     #[error("Database error: {0}")]
     Database(#[from] rusqlite::Error),
     #[error("Deserializetion error: {0}")]
     Deserializetion(#[from] serde_bencode::Error),
+    #[error("Database is at schema version {found}, made by a newer version of torrentox that knows up to {known}")]
+    NewerSchema { found: u32, known: u32 },
 }
 
-#[derive(Debug, Error)]
-pub enum AppError {
-    #[error("Database error: {0}")]
-    Database(#[from] RusqliteError),
-
-    #[error("Application error: {0}")]
-    App(#[from] DbError),
+///Why we refused a peer's handshake
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("Handshake should be 68 bytes, got {0}")]
+    WrongLength(usize),
+    #[error("Peer does not speak the BitTorrent protocol")]
+    WrongProtocol,
+    #[error("Peer answered for a different torrent")]
+    InfoHashMismatch,
+    #[error("Connected to ourselves")]
+    SelfConnection,
+}
 
-    #[error("Other error: {0}")]
-    Other(#[from] color_eyre::Report),
+impl HandshakeError {
+    ///No point trying this peer again, it will answer the same way
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            HandshakeError::InfoHashMismatch | HandshakeError::SelfConnection
+        )
+    }
 }
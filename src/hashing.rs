@@ -0,0 +1,169 @@
+use color_eyre::eyre::{eyre, Result};
+use log::{debug, info};
+use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::storage::Storage;
+
+///Does the data hash to what the torrent says it should
+pub fn piece_matches(data: &[u8], expected: &[u8; 20]) -> bool {
+    Sha1::digest(data)[..] == expected[..]
+}
+
+///How far a run of the pipeline got, and a way to stop it
+#[derive(Debug, Default)]
+pub struct HashProgress {
+    checked: AtomicU32,
+    total: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl HashProgress {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    ///Pieces checked so far, out of how many
+    pub fn checked(&self) -> (u32, u32) {
+        (
+            self.checked.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn percent(&self) -> f64 {
+        let (checked, total) = self.checked();
+        if total == 0 {
+            return 100.0;
+        }
+        checked as f64 * 100.0 / total as f64
+    }
+}
+
+///Check the pieces on disk against their hashes, handing back the indices that match.
+///One thread reads, in piece order so each file is read front to back, the others hash
+///whatever it has read. Pieces we can't read, say because the file isn't there, just don't match
+pub fn hash_pieces(
+    storage: &Storage,
+    pieces: &[(u32, [u8; 20])],
+    threads: usize,
+    progress: &HashProgress,
+) -> Result<Vec<u32>> {
+    progress.checked.store(0, Ordering::Relaxed);
+    progress.total.store(pieces.len() as u32, Ordering::Relaxed);
+    let threads = threads.max(1);
+    //a few pieces read ahead of the hashers, no more, so a big torrent doesn't end up in memory
+    let (read, to_hash) = mpsc::sync_channel::<(u32, [u8; 20], Vec<u8>)>(threads * 2);
+    let to_hash = Mutex::new(to_hash);
+    let matched = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let next = match to_hash.lock() {
+                    Ok(to_hash) => to_hash.recv(),
+                    Err(_) => return,
+                };
+                let Ok((index, expected, data)) = next else {
+                    return;
+                };
+                if piece_matches(&data, &expected) {
+                    if let Ok(mut matched) = matched.lock() {
+                        matched.push(index);
+                    }
+                }
+                progress.checked.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        for (index, expected) in pieces {
+            if progress.is_cancelled() {
+                break;
+            }
+            match storage.read(*index, 0, storage.piece_size(*index) as u32) {
+                Ok(data) => {
+                    if read.send((*index, *expected, data)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not read piece {index}: {e}");
+                    progress.checked.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        //hangs up on the hashers once they have emptied the channel
+        drop(read);
+    });
+    if progress.is_cancelled() {
+        return Err(eyre!("Cancelled"));
+    }
+    let mut matched = matched
+        .into_inner()
+        .map_err(|_| eyre!("A hashing thread fell over"))?;
+    matched.sort_unstable();
+    info!("{} of {} pieces check out", matched.len(), pieces.len());
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::FilePriority;
+    use crate::storage::StorageFile;
+    use std::fs;
+
+    fn hash_of(data: &[u8]) -> [u8; 20] {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&Sha1::digest(data));
+        hash
+    }
+
+    #[test]
+    fn test_hash_pieces() {
+        let dir = std::env::temp_dir().join("torrentox_hashing_test");
+        let _ = fs::remove_dir_all(&dir);
+        let file = |name: &str, offset: u64| StorageFile {
+            path: dir.join(name),
+            length: 6,
+            offset,
+            priority: FilePriority::Normal,
+            sequential: false,
+        };
+        let storage = Storage {
+            files: vec![file("a", 0), file("b", 6)],
+            piece_length: 4,
+            ..Storage::default()
+        };
+        let content: Vec<u8> = (0..12).collect();
+        let pieces: Vec<(u32, [u8; 20])> = content
+            .chunks(4)
+            .enumerate()
+            .map(|(i, chunk)| (i as u32, hash_of(chunk)))
+            .collect();
+        //nothing on disk yet
+        let progress = HashProgress::default();
+        assert!(hash_pieces(&storage, &pieces, 3, &progress)
+            .unwrap()
+            .is_empty());
+        assert_eq!((3, 3), progress.checked());
+
+        storage.write(0, &content).unwrap();
+        //piece 1 straddles both files, break the second half of it
+        storage.write(6, &[0, 0]).unwrap();
+        let progress = HashProgress::default();
+        assert_eq!(
+            vec![0, 2],
+            hash_pieces(&storage, &pieces, 3, &progress).unwrap()
+        );
+        assert_eq!(100.0, progress.percent());
+
+        progress.cancel();
+        assert!(hash_pieces(&storage, &pieces, 3, &progress).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::model::InfoHash;
+use serde_derive::{Deserialize, Serialize};
+
+///How often we note down what each torrent is doing, and how long we keep it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistorySettings {
+    ///Seconds between samples, 0 to keep no history
+    pub sample_seconds: u64,
+    ///Samples younger than this are kept as they are
+    pub full_resolution_hours: u64,
+    ///Older samples get merged into one per this many minutes
+    pub downsample_minutes: u64,
+    ///Anything older than this goes, 0 to keep it all
+    pub keep_days: u64,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            sample_seconds: 60,
+            full_resolution_hours: 24,
+            downsample_minutes: 15,
+            keep_days: 90,
+        }
+    }
+}
+
+///One torrent at one point in time. Totals are as of then, rates are over the time since
+///the sample before. A merged sample stands in for `samples` of them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferSample {
+    pub info_hash: InfoHash,
+    ///Only for showing, two torrents can share a name
+    pub name: String,
+    ///Unix seconds
+    pub sampled_at: i64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub peers: u32,
+    ///Bytes per second
+    pub download_rate: u64,
+    ///Bytes per second
+    pub upload_rate: u64,
+    pub samples: u32,
+}
+
+///What a torrent did over a window
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistorySummary {
+    pub name: String,
+    pub samples: u32,
+    ///Bytes moved within the window
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub average_download_rate: u64,
+    pub average_upload_rate: u64,
+    pub peak_download_rate: u64,
+    pub peak_upload_rate: u64,
+    pub average_peers: u32,
+}
+
+///One summary per torrent, in the order they first turn up. The samples come oldest first
+pub fn summarise(samples: &[TransferSample]) -> Vec<HistorySummary> {
+    let mut torrents: Vec<&InfoHash> = Vec::new();
+    for sample in samples {
+        if !torrents.contains(&&sample.info_hash) {
+            torrents.push(&sample.info_hash);
+        }
+    }
+    torrents
+        .into_iter()
+        .map(|info_hash| {
+            let torrent: Vec<&TransferSample> = samples
+                .iter()
+                .filter(|s| &s.info_hash == info_hash)
+                .collect();
+            let count: u64 = torrent.iter().map(|s| s.samples as u64).sum::<u64>().max(1);
+            let weighted = |value: fn(&TransferSample) -> u64| {
+                torrent
+                    .iter()
+                    .map(|s| value(s) * s.samples as u64)
+                    .sum::<u64>()
+                    / count
+            };
+            let (first, last) = (torrent[0], torrent[torrent.len() - 1]);
+            HistorySummary {
+                name: first.name.clone(),
+                samples: count as u32,
+                //totals carry over between sessions, so the difference is what moved
+                downloaded: last.downloaded.saturating_sub(first.downloaded),
+                uploaded: last.uploaded.saturating_sub(first.uploaded),
+                average_download_rate: weighted(|s| s.download_rate),
+                average_upload_rate: weighted(|s| s.upload_rate),
+                peak_download_rate: torrent.iter().map(|s| s.download_rate).max().unwrap_or(0),
+                peak_upload_rate: torrent.iter().map(|s| s.upload_rate).max().unwrap_or(0),
+                average_peers: weighted(|s| s.peers as u64) as u32,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summarise() {
+        let sample =
+            |name: &str, sampled_at, downloaded, download_rate, peers, samples| TransferSample {
+                info_hash: [name.as_bytes()[0]; 20],
+                name: name.to_owned(),
+                sampled_at,
+                downloaded,
+                download_rate,
+                peers,
+                samples,
+                ..TransferSample::default()
+            };
+        let samples = vec![
+            //a merged sample counts for three
+            sample("a", 0, 100, 10, 4, 3),
+            sample("b", 0, 0, 0, 1, 1),
+            sample("a", 60, 700, 50, 8, 1),
+        ];
+        let summaries = summarise(&samples);
+        assert_eq!(2, summaries.len());
+        let a = &summaries[0];
+        assert_eq!("a", a.name);
+        assert_eq!(4, a.samples);
+        assert_eq!(600, a.downloaded);
+        assert_eq!(20, a.average_download_rate);
+        assert_eq!(50, a.peak_download_rate);
+        assert_eq!(5, a.average_peers);
+        assert_eq!(0, summaries[1].downloaded);
+        assert!(summarise(&[]).is_empty());
+    }
+}
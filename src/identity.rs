@@ -0,0 +1,128 @@
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Result};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::database::{save_identity, select_identity, DbConnection};
+use crate::model::{InfoHash, PeerId};
+use crate::parser::{new_peer_id, new_tracker_key};
+
+///When we give a torrent a new peer id and tracker key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityRotation {
+    ///Keep them, so the tracker knows us across restarts
+    #[default]
+    Never,
+    ///New ones each time torrentox starts
+    EverySession,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdentitySettings {
+    pub rotation: IdentityRotation,
+    ///Rotate anyway once they are this old, missing to keep them for good
+    pub max_age_days: Option<u64>,
+}
+
+impl IdentitySettings {
+    ///Whether an identity made at created_at (unix seconds) can still be used
+    pub fn keeps(&self, created_at: i64, now: i64) -> bool {
+        if self.rotation == IdentityRotation::EverySession {
+            return false;
+        }
+        match self.max_age_days {
+            Some(days) => now.saturating_sub(created_at) < days as i64 * 24 * 60 * 60,
+            None => true,
+        }
+    }
+}
+
+///Who we say we are to the tracker and the peers of one torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub peer_id: PeerId,
+    pub key: String,
+}
+
+///The identity the torrent had last time, or a new one if it had none or the policy says it is
+///time for a change. The torrent must already be saved
+pub fn load_identity(
+    info_hash: &InfoHash,
+    settings: &IdentitySettings,
+    db: &DbConnection,
+) -> Result<Identity> {
+    let now = Utc::now().timestamp();
+    if let Some((peer_id, key, created_at)) = select_identity(info_hash, db)? {
+        if settings.keeps(created_at, now) {
+            return Ok(Identity {
+                peer_id: to_peer_id(&peer_id)?,
+                key,
+            });
+        }
+        info!("Rotating the peer id {peer_id}");
+    }
+    let peer_id = new_peer_id();
+    let key = new_tracker_key();
+    save_identity(info_hash, &peer_id, &key, now, db)?;
+    Ok(Identity {
+        peer_id: to_peer_id(&peer_id)?,
+        key,
+    })
+}
+
+fn to_peer_id(peer_id: &str) -> Result<PeerId> {
+    peer_id
+        .as_bytes()
+        .try_into()
+        .map_err(|_| eyre!("Peer Id must be exactly 20 bytes long, not {peer_id}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::save_torrent_file;
+    use crate::database::test::init_test_conn;
+    use crate::parser::parse_torrent_file;
+
+    #[test]
+    fn test_keeps() {
+        let day = 24 * 60 * 60;
+        let mut settings = IdentitySettings::default();
+        assert!(settings.keeps(0, 1000 * day));
+        settings.max_age_days = Some(7);
+        assert!(settings.keeps(0, 6 * day));
+        assert!(!settings.keeps(0, 7 * day));
+        settings.rotation = IdentityRotation::EverySession;
+        assert!(!settings.keeps(0, 0));
+    }
+
+    #[test]
+    fn test_load_identity() {
+        let db = init_test_conn();
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        let info_hash = torrent.torrent_file.info_hash;
+        assert!(load_identity(&info_hash, &IdentitySettings::default(), &db).is_err());
+        save_torrent_file(&torrent, &db).unwrap();
+
+        let first = load_identity(&info_hash, &IdentitySettings::default(), &db).unwrap();
+        assert!(first.peer_id.starts_with(b"-OX"));
+        assert_eq!(8, first.key.len());
+        //next run, same us
+        assert_eq!(
+            first,
+            load_identity(&info_hash, &IdentitySettings::default(), &db).unwrap()
+        );
+        let rotating = IdentitySettings {
+            rotation: IdentityRotation::EverySession,
+            ..IdentitySettings::default()
+        };
+        let second = load_identity(&info_hash, &rotating, &db).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            second,
+            load_identity(&info_hash, &IdentitySettings::default(), &db).unwrap()
+        );
+    }
+}
@@ -1,30 +1,73 @@
 mod api;
+mod archive;
 mod args;
+mod config;
+mod connection_manager;
 mod database;
+mod disk;
 mod error_types;
+mod hashing;
+mod history;
+mod identity;
 mod log_init_for_tests;
+mod migrations;
 mod model;
 mod parser;
+mod paths;
+mod peer_cache;
+mod peer_message;
+mod piece;
+mod queue;
+mod rate_limit;
+mod schedule;
+mod seeding;
+mod session;
+mod state;
+mod storage;
+mod stream;
+mod tracker;
 
-use api::connect_and_send_handshake;
-use api::init_peer_torrent_sessions;
+use api::{load_torrent_sessions, restore_torrent_sessions};
 use clap::Parser;
 
 //use anyhow::Result;
-use args::AppArgs;
-use color_eyre::eyre::Result;
-use database::{init_tables, DbConnection};
-use log::debug;
+use archive::{Archive, ImportOutcome, ARCHIVE_VERSION};
+use args::{AppArgs, Command};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use config::load_config;
+use connection_manager::{ActiveTorrent, ConnectionLimits, ConnectionManager};
+use database::{
+    export_torrents, find_torrent, import_torrent, init_tables, list_banned_peers, list_files,
+    list_queue, list_samples, list_tracker_status, move_in_queue, request_move, request_recheck,
+    request_state, save_banned_peer, save_file_priority, save_global_rate_limits, save_location,
+    save_seeding_policy, save_sequential, save_torrent_rate_limits, select_global_rate_limits,
+    select_have, select_location, select_recheck_requested, select_sequential, select_state,
+    select_torrent_progress, select_torrent_rate_limits, DbConnection,
+};
+use disk::DiskPool;
+use history::summarise;
 use log::LevelFilter;
+use log::{debug, info, warn};
 use log4rs::{
     append::file::FileAppender,
     config::{runtime::Appender, Logger, Root},
     encode::pattern::PatternEncoder,
     Config,
 };
+use model::{FileEntry, FilePriority, InfoHash, TransferStats};
+use paths::{PathOverrides, Paths, DB_FILE};
+use piece::BanList;
+use rate_limit::{kib_to_rate, TransferLimiter};
 use rusqlite::Connection;
+use seeding::SeedingAction;
+use session::Session;
+use state::TorrentState;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use stream::StreamServer;
+use tokio::net::TcpListener;
 
-fn init(verbose: bool) -> Result<()> {
+fn init(verbose: bool, log_file: &Path) -> Result<()> {
     //pretty error messages
     color_eyre::install()?;
 
@@ -40,7 +83,7 @@ fn init(verbose: bool) -> Result<()> {
         .encoder(Box::new(PatternEncoder::new(
             "{d} {l} - [{f}]>>{M}:{L} {m}{n}",
         )))
-        .build("torrentox.log")?;
+        .build(log_file)?;
 
     let file_appender_name = "file_log";
     let config = Config::builder()
@@ -73,79 +116,373 @@ fn init(verbose: bool) -> Result<()> {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let args = AppArgs::parse();
-    init(args.verbose)?;
+    let overrides = PathOverrides {
+        data_dir: args.data_dir.clone(),
+        config_file: args.config.clone(),
+        log_file: args.log_file.clone(),
+    };
+    let paths = Paths::resolve(overrides, |name| std::env::var(name).ok())?;
+    paths.create_dirs()?;
+    init(args.verbose, &paths.log_file)?;
 
-    let db = init_db()?;
+    let working_dir = Path::new(".");
+    if args.config.is_none() && working_dir.join("torrentox.toml").exists() {
+        warn!(
+            "./torrentox.toml isn't read any more, move it to {}",
+            paths.config_file.display()
+        );
+    }
+    let legacy_db = working_dir.join(DB_FILE);
+    //held until we exit, the commands don't need it
+    let (_lock, db_file) = match args.command {
+        //the session moves the old db over, until then the commands keep using it
+        Some(_) if legacy_db.is_file() && !paths.db_file().exists() => (None, legacy_db),
+        Some(_) => (None, paths.db_file()),
+        None => {
+            let lock = paths.lock()?;
+            //only with the lock, so two sessions starting at once don't both move it
+            if paths.adopt_legacy_db(working_dir)? {
+                println!("Moved ./{DB_FILE} to {}", paths.db_file().display());
+            }
+            (Some(lock), paths.db_file())
+        }
+    };
+    let db = init_db(&db_file)?;
     init_tables(&db)?;
 
+    if let Some(command) = args.command {
+        return run_command(command, &db);
+    }
+
+    let mut bans = BanList::default();
+    if args.persist_bans {
+        list_banned_peers(&db)?.iter().for_each(|ip| bans.ban(ip));
+    }
+    let bans = Arc::new(Mutex::new(bans));
+
+    let mut config = load_config(&paths.config_file)?;
     let torrent_files = args.torrent_files;
-    let peer_torrent = init_peer_torrent_sessions(&torrent_files, &db).await?;
+    let mut peer_torrent = load_torrent_sessions(&torrent_files, &config.identity, &db)?;
+    //and whatever we had going last time, without needing the torrent files again
+    peer_torrent.extend(restore_torrent_sessions(
+        &peer_torrent,
+        &config.identity,
+        &db,
+    )?);
+    if args.max_download_rate.is_some() || args.max_upload_rate.is_some() {
+        save_global_rate_limits(args.max_download_rate, args.max_upload_rate, &db)?;
+    }
+    config.stream.port = args.stream_port.or(config.stream.port);
+    if let Some(allocation) = args.allocation {
+        config.storage.allocation = allocation;
+    }
+    config.storage.incomplete_dir = args.incomplete_dir.or(config.storage.incomplete_dir);
+    config.storage.part_suffix |= args.part_suffix;
 
-    let client = reqwest::Client::new();
+    let disk_pool = Arc::new(DiskPool::new(&config.disk)?);
+    let mut active_torrents: Vec<Arc<ActiveTorrent>> = Vec::new();
     for torrent_session in peer_torrent {
-        //get a response from the peer
-        for peer in torrent_session.peers {
-            let peer_url = format!("http://{}:{}", peer.ip, peer.port);
-            let peer_response = connect_and_send_handshake(
-                &peer.ip,
-                peer.port,
-                &torrent_session.torrent.torrent_file.info_hash,
-                &torrent_session.peer_id,
-            )
-            .await?;
-            // let response = client.get(peer_url).send().await?;
-            // let http_status = response.status();
-            // //handle error status
-            // if http_status.is_server_error() {
-            //     let body = response.text().await?;
-            //     let err = eyre!(
-            //         "Server error, {}, with message {}",
-            //         http_status.to_string(),
-            //         body
-            //     );
-            //     return Err(err);
-            // } else if http_status.is_client_error() {
-            //     let body = response.text().await?;
-            //     let err = eyre!(
-            //         "Client error, {}, with message {}",
-            //         http_status.to_string(),
-            //         body
-            //     );
-            //     return Err(err);
-            // }
-
-            // let txt = response.text().await.error
+        //carry on counting from where the last session left off
+        let info_hash = torrent_session.torrent.torrent_file.info_hash;
+        let (downloaded, uploaded, seeding_time) = select_torrent_progress(&info_hash, &db)?;
+        let stats = TransferStats::new(downloaded, uploaded, seeding_time);
+        //sequential for the torrent means sequential for every file in it
+        let all_sequential = select_sequential(&info_hash, &db)?;
+        let files: Vec<FileEntry> = list_files(&info_hash, &db)?
+            .into_iter()
+            .map(|f| FileEntry {
+                sequential: f.sequential || all_sequential,
+                ..f
+            })
+            .collect();
+        let download_dir = Path::new(&args.download_dir);
+        let location = config
+            .storage
+            .start_location(select_location(&info_hash, &db)?, download_dir);
+        save_location(&info_hash, &location, &db)?;
+        let recheck_requested = select_recheck_requested(&info_hash, &db)?;
+        let torrent = ActiveTorrent::new(
+            torrent_session,
+            download_dir,
+            location,
+            stats,
+            &files,
+            config.sequential.readahead_pieces,
+            disk_pool.clone(),
+        )?;
+        //what we had verified last time, the recheck command is there if the files changed since.
+        //One already asked for, say by an import, finds out for itself
+        if let Some(have) = select_have(&info_hash, &db)?.filter(|_| !recheck_requested) {
+            if let Ok(mut download) = torrent.download.lock() {
+                download.restore_have(&have);
+            }
+        }
+        active_torrents.push(Arc::new(torrent));
+    }
+
+    if let Some(port) = config.stream.port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let server = StreamServer::new(active_torrents.clone());
+        tokio::spawn(async move {
+            if let Err(e) = server.run(listener).await {
+                warn!("Streaming server stopped: {e}");
+            }
+        });
+    }
+
+    let limits = ConnectionLimits {
+        max_peers_per_torrent: args.max_peers_per_torrent,
+        max_peers: args.max_peers,
+        ..ConnectionLimits::default()
+    };
+    //the session sets the actual rates on its first tick, once it knows which profile applies
+    let global_rate_limits = Arc::new(TransferLimiter::unlimited());
+    let connection_manager = ConnectionManager::new(limits, bans.clone(), global_rate_limits);
+    let mut session = Session::new(db, active_torrents, connection_manager, config)?;
+    session.run().await?;
+    let db = &session.db;
+
+    for torrent in &session.torrents {
+        let wasted_bytes = torrent.download.lock().map(|d| d.wasted_bytes).unwrap_or(0);
+        info!(
+            "{} wasted {wasted_bytes} bytes on pieces that failed their hash check",
+            torrent.name
+        );
+    }
+    if args.persist_bans {
+        let bans = bans.lock().map_err(|e| eyre!("Ban list poisoned: {}", e))?;
+        for ip in bans.banned() {
+            save_banned_peer(ip, "Sent pieces that failed their hash check", db)?;
         }
     }
-    //TODO these should come from the db and be stored there
-    //let mut peer_id_cache: HashMap<String, String> = HashMap::new();
-    //
-    //let client = reqwest::Client::new();
-    //for torrent_file_path in torrent_files {
-    //    let torrent = parse_torrent_file(&torrent_file_path)?;
-    //    save_torrent_file(&torrent, &db)?;
-    //    let announce_url = torrent
-    //        .torrent_file
-    //        .announce
-    //        .clone()
-    //        .ok_or_else(|| eyre!("Did not find the announce url"))?;
-    //    debug!("announce url: {announce_url}");
-    //    let query_map = construct_query_map(&torrent, &mut peer_id_cache)?;
-    //    //create our request
-    //    let response = client.get(announce_url).query(&query_map).send().await?;
-    //    debug!("Our response: {:?}", response);
-    //}
-    //connect to the announce url
 
     Ok(())
 }
 
-fn init_db() -> Result<DbConnection> {
-    let conn = Connection::open("./torrentox.db")?;
+///Subcommands work on the db only, no peers involved
+fn run_command(command: Command, db: &DbConnection) -> Result<()> {
+    match command {
+        Command::Limit {
+            name,
+            download,
+            upload,
+        } => {
+            let info_hash = find_torrent(&name, db)?;
+            let (current_download, current_upload) = select_torrent_rate_limits(&info_hash, db)?;
+            let download_limit = download.map_or(current_download, |d| kib_to_rate(Some(d)));
+            let upload_limit = upload.map_or(current_upload, |u| kib_to_rate(Some(u)));
+            save_torrent_rate_limits(&info_hash, download_limit, upload_limit, db)?;
+            println!(
+                "{name}: download {}, upload {}",
+                describe_rate(download_limit),
+                describe_rate(upload_limit)
+            );
+        }
+        Command::GlobalLimit { download, upload } => {
+            save_global_rate_limits(download, upload, db)?;
+            let (download, upload) = select_global_rate_limits(db)?;
+            let describe = |kib: Option<u64>| match kib {
+                Some(kib) => describe_rate(kib_to_rate(Some(kib))),
+                None => "from the config".to_owned(),
+            };
+            println!(
+                "All torrents: download {}, upload {}",
+                describe(download),
+                describe(upload)
+            );
+        }
+        Command::SeedPolicy {
+            name,
+            ratio,
+            seeding_minutes,
+            idle_minutes,
+            action,
+        } => {
+            let info_hash = find_torrent(&name, db)?;
+            let action = action
+                .map(|a| SeedingAction::parse(&a).ok_or_else(|| eyre!("Unknown action {a}")))
+                .transpose()?;
+            save_seeding_policy(&info_hash, ratio, seeding_minutes, idle_minutes, action, db)?;
+            //only what was given changed
+            let mut changed = Vec::new();
+            if let Some(ratio) = ratio {
+                changed.push(format!("ratio {ratio}"));
+            }
+            if let Some(minutes) = seeding_minutes {
+                changed.push(format!("seeding {minutes}m"));
+            }
+            if let Some(minutes) = idle_minutes {
+                changed.push(format!("idle {minutes}m"));
+            }
+            if let Some(action) = action {
+                changed.push(format!("then {}", action.as_str()));
+            }
+            if changed.is_empty() {
+                println!("{name}: nothing to change");
+            } else {
+                println!("{name}: {}", changed.join(", "));
+            }
+        }
+        Command::Queue { name, direction } => {
+            let queue = match (name, direction) {
+                (Some(name), Some(direction)) => {
+                    move_in_queue(&find_torrent(&name, db)?, direction, db)?
+                }
+                _ => list_queue(db)?,
+            };
+            for (position, (info_hash, name)) in queue.iter().enumerate() {
+                let (state, error) = select_state(info_hash, db)?;
+                match error.filter(|_| state == TorrentState::Errored) {
+                    Some(error) => println!("{:>3}. {name} [{state}: {error}]", position + 1),
+                    None => println!("{:>3}. {name} [{state}]", position + 1),
+                }
+            }
+        }
+        Command::Files {
+            name,
+            file,
+            priority,
+        } => {
+            let info_hash = find_torrent(&name, db)?;
+            if let (Some(file), Some(priority)) = (file, priority) {
+                let priority = FilePriority::parse(&priority)
+                    .ok_or_else(|| eyre!("Unknown priority {priority}"))?;
+                let index = file
+                    .checked_sub(1)
+                    .ok_or_else(|| eyre!("Files are numbered from 1"))?;
+                save_file_priority(&info_hash, index, priority, db)?;
+            }
+            print_files(&info_hash, db)?;
+        }
+        Command::Sequential { name, file, off } => {
+            let info_hash = find_torrent(&name, db)?;
+            let index = file
+                .map(|f| {
+                    f.checked_sub(1)
+                        .ok_or_else(|| eyre!("Files are numbered from 1"))
+                })
+                .transpose()?;
+            save_sequential(&info_hash, index, !off, db)?;
+            if select_sequential(&info_hash, db)? {
+                println!("{name}: sequential");
+            }
+            print_files(&info_hash, db)?;
+        }
+        Command::Move { name, dir } => {
+            let info_hash = find_torrent(&name, db)?;
+            //the session may well be running somewhere else
+            let dir = std::path::absolute(dir)?;
+            request_move(&info_hash, &dir, db)?;
+            println!("{name}: moving to {}", dir.display());
+        }
+        Command::History { name, hours } => {
+            let since = chrono::Utc::now().timestamp() - hours as i64 * 60 * 60;
+            let info_hash = name.map(|name| find_torrent(&name, db)).transpose()?;
+            let summaries = summarise(&list_samples(info_hash.as_ref(), since, db)?);
+            if summaries.is_empty() {
+                println!("Nothing recorded in the last {hours}h");
+            }
+            for s in summaries {
+                println!(
+                    "{}: down {} bytes, up {} bytes over {} samples",
+                    s.name, s.downloaded, s.uploaded, s.samples
+                );
+                println!(
+                    "    down avg {}, peak {}, up avg {}, peak {}, {} peers on average",
+                    describe_rate(Some(s.average_download_rate)),
+                    describe_rate(Some(s.peak_download_rate)),
+                    describe_rate(Some(s.average_upload_rate)),
+                    describe_rate(Some(s.peak_upload_rate)),
+                    s.average_peers
+                );
+            }
+        }
+        Command::Trackers { name } => {
+            let info_hash = find_torrent(&name, db)?;
+            let now = chrono::Utc::now().timestamp();
+            for tracker in list_tracker_status(&info_hash, db)? {
+                println!("{}", tracker.describe(now));
+            }
+        }
+        Command::Export { path } => {
+            let archive = Archive {
+                version: ARCHIVE_VERSION,
+                exported_at: chrono::Utc::now().timestamp(),
+                torrents: export_torrents(db)?,
+            };
+            archive.write(&path)?;
+            println!(
+                "Exported {} torrents to {}",
+                archive.torrents.len(),
+                path.display()
+            );
+        }
+        Command::Import { path, merge } => {
+            let archive = Archive::read(&path)?;
+            for torrent in &archive.torrents {
+                match import_torrent(torrent, merge, db) {
+                    Ok(ImportOutcome::Added) => println!("{}: added", torrent.name),
+                    Ok(ImportOutcome::Merged) => println!("{}: merged", torrent.name),
+                    Ok(ImportOutcome::Skipped) => {
+                        println!("{}: already here, skipped", torrent.name)
+                    }
+                    //one bad torrent doesn't hold up the rest
+                    Err(e) => println!("{}: not imported, {e}", torrent.name),
+                }
+            }
+        }
+        Command::Pause { name } => {
+            let info_hash = find_torrent(&name, db)?;
+            request_state(&info_hash, TorrentState::Paused, db)?;
+            println!("{name}: pausing");
+        }
+        Command::Resume { name } => {
+            let info_hash = find_torrent(&name, db)?;
+            request_state(&info_hash, TorrentState::Queued, db)?;
+            println!("{name}: back in the queue");
+        }
+        Command::Stop { name } => {
+            let info_hash = find_torrent(&name, db)?;
+            request_state(&info_hash, TorrentState::Stopped, db)?;
+            println!("{name}: stopping");
+        }
+        Command::Recheck { name, cancel } => {
+            let info_hash = find_torrent(&name, db)?;
+            request_recheck(&info_hash, !cancel, db)?;
+            if cancel {
+                println!("{name}: recheck cancelled");
+            } else {
+                println!("{name}: rechecking");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_files(info_hash: &InfoHash, db: &DbConnection) -> Result<()> {
+    for (index, file) in list_files(info_hash, db)?.iter().enumerate() {
+        println!(
+            "{:>3}. [{}{}] {} ({} bytes)",
+            index + 1,
+            file.priority.as_str(),
+            if file.sequential { ", sequential" } else { "" },
+            file.path,
+            file.size
+        );
+    }
+    Ok(())
+}
+
+fn describe_rate(rate: Option<u64>) -> String {
+    rate.map_or("unlimited".to_owned(), |r| format!("{} KiB/s", r / 1024))
+}
+
+fn init_db(path: &Path) -> Result<DbConnection> {
+    let conn = Connection::open(path)
+        .wrap_err_with(|| format!("Failed to open the db {}", path.display()))?;
     let db = DbConnection {
         conn,
-        name: "TorrentOx".to_owned(),
-        db_name: "torrentox.db".to_owned(),
+        db_name: path.display().to_string(),
     };
     Ok(db)
 }
@@ -0,0 +1,374 @@
+use color_eyre::eyre::{Result, WrapErr};
+use log::{info, warn};
+use rusqlite::{params, Connection, Transaction};
+
+use crate::error_types::DbError;
+use crate::model::{info_hash_hex, TorrentFile};
+
+///One step of the schema. The user_version of a db is how many of them it has had
+pub struct Migration {
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+///In order, and never edited once released, add a new one instead
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "torrent and torrent_file tables",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS torrent (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, file_path TEXT, announce_url TEXT, torrent_file_raw BLOB, size INTEGER, downloaded INTEGER, uploaded INTEGER);
+                CREATE TABLE IF NOT EXISTS torrent_file(id INTEGER PRIMARY KEY AUTOINCREMENT, torrent_id INTEGER, path TEXT, size INTEGER, downloaded INTEGER, uploaded INTEGER, FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE);",
+            )
+        },
+    },
+    Migration {
+        description: "banned peers",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS banned_peer(ip TEXT PRIMARY KEY, reason TEXT, banned_at INTEGER DEFAULT (strftime('%s', 'now')))",
+            )
+        },
+    },
+    Migration {
+        description: "per torrent rate limits",
+        apply: |tx| {
+            add_column(tx, "torrent", "download_limit", "INTEGER")?;
+            add_column(tx, "torrent", "upload_limit", "INTEGER")
+        },
+    },
+    Migration {
+        description: "seeding policies",
+        apply: |tx| {
+            add_column(tx, "torrent", "seed_ratio_limit", "REAL")?;
+            add_column(tx, "torrent", "seed_time_limit", "INTEGER")?;
+            add_column(tx, "torrent", "seed_idle_limit", "INTEGER")?;
+            add_column(tx, "torrent", "seed_limit_action", "TEXT")?;
+            add_column(tx, "torrent", "seeding_time", "INTEGER DEFAULT 0")
+        },
+    },
+    Migration {
+        description: "queue positions",
+        apply: |tx| {
+            add_column(tx, "torrent", "queue_position", "INTEGER")?;
+            //torrents from before the queue line up in the order we first saw them
+            tx.execute(
+                "UPDATE torrent SET queue_position = (SELECT MIN(t.id) FROM torrent t WHERE t.name = torrent.name) WHERE queue_position IS NULL",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "file priorities and sequential downloads",
+        apply: |tx| {
+            add_column(tx, "torrent_file", "priority", "TEXT DEFAULT 'normal'")?;
+            add_column(tx, "torrent_file", "sequential", "INTEGER DEFAULT 0")?;
+            add_column(tx, "torrent", "sequential", "INTEGER DEFAULT 0")
+        },
+    },
+    Migration {
+        description: "save paths, moves and rechecks",
+        apply: |tx| {
+            add_column(tx, "torrent", "save_path", "TEXT")?;
+            add_column(tx, "torrent", "move_to", "TEXT")?;
+            add_column(tx, "torrent", "recheck", "INTEGER DEFAULT 0")
+        },
+    },
+    Migration {
+        description: "one row per torrent, by info hash",
+        apply: |tx| {
+            add_column(tx, "torrent", "info_hash", "TEXT")?;
+            add_column(tx, "torrent", "trackers", "TEXT")?;
+            backfill_info_hashes(tx)?;
+            //every run used to add another row, keep the newest, which has the settings anyway
+            tx.execute_batch(
+                "UPDATE torrent SET save_path = COALESCE(save_path, (SELECT t.save_path FROM torrent t WHERE t.info_hash = torrent.info_hash AND t.save_path IS NOT NULL ORDER BY t.id DESC LIMIT 1)) WHERE info_hash IS NOT NULL;
+                DELETE FROM torrent_file WHERE torrent_id IN (SELECT id FROM torrent t WHERE t.id < (SELECT MAX(d.id) FROM torrent d WHERE d.info_hash = t.info_hash));
+                DELETE FROM torrent WHERE id < (SELECT MAX(d.id) FROM torrent d WHERE d.info_hash = torrent.info_hash);
+                UPDATE torrent SET trackers = announce_url WHERE trackers IS NULL AND announce_url IS NOT NULL AND announce_url != 'None';
+                CREATE UNIQUE INDEX IF NOT EXISTS torrent_info_hash ON torrent(info_hash);",
+            )
+        },
+    },
+    Migration {
+        description: "peer ids and tracker keys",
+        apply: |tx| {
+            add_column(tx, "torrent", "peer_id", "TEXT")?;
+            add_column(tx, "torrent", "tracker_key", "TEXT")?;
+            add_column(tx, "torrent", "identity_created_at", "INTEGER")
+        },
+    },
+    Migration {
+        description: "torrent states",
+        apply: |tx| {
+            add_column(tx, "torrent", "state", "TEXT DEFAULT 'queued'")?;
+            add_column(tx, "torrent", "last_error", "TEXT")?;
+            add_column(tx, "torrent", "requested_state", "TEXT")
+        },
+    },
+    Migration {
+        description: "transfer history",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS transfer_sample(id INTEGER PRIMARY KEY AUTOINCREMENT, torrent_id INTEGER, sampled_at INTEGER, downloaded INTEGER, uploaded INTEGER, peers INTEGER, download_rate INTEGER, upload_rate INTEGER, samples INTEGER DEFAULT 1, FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE);
+                CREATE INDEX IF NOT EXISTS transfer_sample_time ON transfer_sample(sampled_at, torrent_id);",
+            )
+        },
+    },
+    Migration {
+        description: "known peers",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS known_peer(torrent_id INTEGER, ip TEXT, port INTEGER, last_seen INTEGER, downloaded INTEGER DEFAULT 0, uploaded INTEGER DEFAULT 0, failures INTEGER DEFAULT 0, client TEXT, PRIMARY KEY (torrent_id, ip, port), FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE)",
+            )
+        },
+    },
+    Migration {
+        description: "tracker status",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS tracker(torrent_id INTEGER, url TEXT, last_announce INTEGER, last_error TEXT, peers INTEGER, next_announce INTEGER, seeders INTEGER, leechers INTEGER, PRIMARY KEY (torrent_id, url), FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE)",
+            )
+        },
+    },
+    Migration {
+        description: "completion bitfield",
+        apply: |tx| add_column(tx, "torrent", "have", "BLOB"),
+    },
+    Migration {
+        description: "global rate limits",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS global_limit(id INTEGER PRIMARY KEY CHECK (id = 1), download INTEGER, upload INTEGER)",
+            )
+        },
+    },
+    Migration {
+        description: "whether the saved location is where the torrent stays",
+        apply: |tx| {
+            //left null for the locations already saved, nothing here knows the incomplete dir
+            add_column(tx, "torrent", "save_path_complete", "INTEGER")?;
+            add_column(tx, "torrent", "save_path_part_suffix", "INTEGER")
+        },
+    },
+];
+
+///Bring the db up to date, all in one transaction so a failure leaves it as it was.
+///A db from a newer torrentox is left alone, we would only make a mess of it
+pub fn migrate(conn: &Connection, db_name: &str) -> Result<()> {
+    apply_migrations(conn, db_name, MIGRATIONS)
+}
+
+fn apply_migrations(conn: &Connection, db_name: &str, migrations: &[Migration]) -> Result<()> {
+    let found: u32 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(DbError::from)?;
+    let known = migrations.len() as u32;
+    if found > known {
+        return Err(DbError::NewerSchema { found, known })
+            .wrap_err_with(|| format!("Refusing to open {db_name}"));
+    }
+    if found == known {
+        return Ok(());
+    }
+    let tx = conn.unchecked_transaction().map_err(DbError::from)?;
+    for (version, migration) in migrations.iter().enumerate().skip(found as usize) {
+        info!(
+            "Migrating {db_name} to version {}: {}",
+            version + 1,
+            migration.description
+        );
+        (migration.apply)(&tx)
+            .map_err(DbError::from)
+            .wrap_err_with(|| format!("Migration to version {} failed", version + 1))?;
+    }
+    tx.pragma_update(None, "user_version", known)
+        .map_err(DbError::from)?;
+    tx.commit().map_err(DbError::from)?;
+    Ok(())
+}
+
+///The info hash is in the raw torrent file, so this can't be done in sql.
+///Rows we can't make sense of are left without one
+fn backfill_info_hashes(tx: &Transaction) -> rusqlite::Result<()> {
+    let rows = tx
+        .prepare("SELECT id, torrent_file_raw FROM torrent WHERE info_hash IS NULL AND torrent_file_raw IS NOT NULL")?
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, raw) in rows {
+        match serde_bencode::from_bytes::<TorrentFile>(&raw) {
+            Ok(torrent_file) => {
+                tx.execute(
+                    "UPDATE torrent SET info_hash = ?1 WHERE id = ?2",
+                    params![info_hash_hex(&torrent_file.info_hash), id],
+                )?;
+            }
+            Err(e) => warn!("Torrent row {id} does not parse, leaving it be: {e}"),
+        }
+    }
+    Ok(())
+}
+
+///Dbs from before the migrations got their columns as they came along,
+///so a column may well be there already
+fn add_column(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        tx.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    ///The schema as it was before there were any migrations
+    fn baseline_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS torrent (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, file_path TEXT, announce_url TEXT, torrent_file_raw BLOB, size INTEGER, downloaded INTEGER, uploaded INTEGER);
+            CREATE TABLE IF NOT EXISTS torrent_file(id INTEGER PRIMARY KEY AUTOINCREMENT, torrent_id INTEGER, path TEXT, size INTEGER, downloaded INTEGER, uploaded INTEGER, FOREIGN KEY (torrent_id) REFERENCES torrent(id) ON DELETE CASCADE);
+            INSERT INTO torrent (name, size, downloaded, uploaded) VALUES ('b', 10, 1, 2), ('a', 20, 0, 0), ('b', 10, 3, 4);
+            INSERT INTO torrent_file (torrent_id, path, size) VALUES (1, 'b/one', 10);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn version(conn: &Connection) -> u32 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_from_baseline() {
+        let conn = baseline_db();
+        migrate(&conn, "test").unwrap();
+        assert_eq!(MIGRATIONS.len() as u32, version(&conn));
+        let (uploaded, queue_position, priority): (u64, u32, String) = conn
+            .query_row(
+                "SELECT t.uploaded, t.queue_position, f.priority FROM torrent t JOIN torrent_file f ON f.torrent_id = t.id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (2, 1, "normal".to_owned()),
+            (uploaded, queue_position, priority)
+        );
+        let positions: Vec<u32> = conn
+            .prepare("SELECT queue_position FROM torrent ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(vec![1, 2, 1], positions);
+        conn.execute("INSERT INTO banned_peer (ip) VALUES ('1.2.3.4')", [])
+            .unwrap();
+
+        //nothing left to do the second time round
+        migrate(&conn, "test").unwrap();
+        assert_eq!(MIGRATIONS.len() as u32, version(&conn));
+    }
+
+    #[test]
+    fn test_duplicate_rows_merged() {
+        let conn = baseline_db();
+        let raw = std::fs::read("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        for _ in 0..2 {
+            conn.execute(
+                "INSERT INTO torrent (name, announce_url, torrent_file_raw) VALUES ('fedora', 'http://tracker/announce', ?1)",
+                params![raw],
+            )
+            .unwrap();
+        }
+        let newest = conn.last_insert_rowid();
+        migrate(&conn, "test").unwrap();
+        let rows: Vec<(i64, Option<String>)> = conn
+            .prepare("SELECT id, info_hash FROM torrent WHERE name = 'fedora'")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!(newest, rows[0].0);
+        assert_eq!(Some(40), rows[0].1.as_ref().map(|h| h.len()));
+        //the rows without torrent files are not touched
+        let others: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM torrent WHERE info_hash IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(3, others);
+        assert!(conn
+            .execute(
+                "INSERT INTO torrent (name, info_hash) SELECT name, info_hash FROM torrent WHERE id = ?1",
+                params![newest],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_columns_from_before_migrations() {
+        let conn = baseline_db();
+        conn.execute_batch(
+            "ALTER TABLE torrent ADD COLUMN download_limit INTEGER; ALTER TABLE torrent ADD COLUMN sequential INTEGER DEFAULT 0;",
+        )
+        .unwrap();
+        migrate(&conn, "test").unwrap();
+        assert_eq!(MIGRATIONS.len() as u32, version(&conn));
+    }
+
+    #[test]
+    fn test_refuses_newer_db() {
+        let conn = baseline_db();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as u32 + 1)
+            .unwrap();
+        let err = migrate(&conn, "test").unwrap_err();
+        assert!(err
+            .chain()
+            .any(|e| e.to_string().contains("newer version of torrentox")));
+    }
+
+    #[test]
+    fn test_failed_migration_changes_nothing() {
+        let conn = baseline_db();
+        let migrations = [
+            Migration {
+                description: "fine",
+                apply: |tx| add_column(tx, "torrent", "fine", "INTEGER"),
+            },
+            Migration {
+                description: "broken",
+                apply: |tx| tx.execute_batch("ALTER TABLE no_such_table ADD COLUMN x INTEGER"),
+            },
+        ];
+        assert!(apply_migrations(&conn, "test", &migrations).is_err());
+        assert_eq!(0, version(&conn));
+        let fine_exists: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('torrent') WHERE name = 'fine'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!fine_exists);
+    }
+}
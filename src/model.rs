@@ -6,6 +6,9 @@ use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{collections::BTreeMap, fmt::Display};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -21,6 +24,26 @@ pub struct Torrent {
     pub size: u64,
     pub downloaded: u64,
     pub uploaded: u64,
+    ///Bytes per second, None for unlimited
+    pub download_limit: Option<u64>,
+    ///Bytes per second, None for unlimited
+    pub upload_limit: Option<u64>,
+}
+
+impl Torrent {
+    ///Name from the info dictionary, which is what the torrent is known by in the db
+    pub fn info_name(&self) -> String {
+        self.torrent_file
+            .info
+            .name
+            .clone()
+            .unwrap_or("None".to_owned())
+    }
+
+    ///The info hash as hex, which is how the db tells torrents apart
+    pub fn info_hash_hex(&self) -> String {
+        info_hash_hex(&self.torrent_file.info_hash)
+    }
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
@@ -100,6 +123,47 @@ pub struct FileInfo {
     pub path: Vec<String>,
 }
 
+///How much we want a file of the torrent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    ///Never download it, nor create it on disk
+    Skip,
+    #[default]
+    Normal,
+    ///Its pieces get picked before any normal ones
+    High,
+}
+
+impl FilePriority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        }
+    }
+
+    pub fn parse(priority: &str) -> Option<FilePriority> {
+        match priority {
+            "skip" => Some(FilePriority::Skip),
+            "normal" => Some(FilePriority::Normal),
+            "high" => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+}
+
+///A file of a torrent, with the settings we keep for it in the torrent_file table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    ///Path inside the torrent, split by /
+    pub path: String,
+    pub size: u64,
+    pub priority: FilePriority,
+    ///Download it front to back, so it can be used before it is done
+    pub sequential: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Info {
     pub name: Option<String>,
@@ -114,43 +178,26 @@ pub struct Info {
     //pub possible_files: Option<Vec<File>>,
     //#[serde(rename = "length")]
     //pub possible_length: Option<u64>,
-    //pub pieces: ByteBuf,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct File {
-    pub length: u64,
-    pub possible_path: Option<Vec<String>>,
-}
-
-///Request to the announce url. Note it will be used in an Http GET request
-///Question do we need to implement serde for this as we are using this for http...
-#[derive(Serialize, Deserialize)]
-pub struct TrackerAnnounceRequest {
-    ///The 20 byte sha1 hash of the bencoded form of the info value from the metainfo file.
-    ///Note that this is a substring of the metainfo file. Don't forget to URL-encode this.
-    info_hash: Vec<u8>,
-    peer_id: String,
-    ip: Option<String>,
-    port_number: u64,
-    uploaded: u128,
-    downloaded: u128,
-    left: u128,
-    event: Option<String>,
-    numwant: Option<u32>,
+    ///Concatenated 20 byte sha1 hashes, one per piece
+    pub pieces: ByteBuf,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TrackerAnnounceResponse {
     ///Number of seconds the downloader should wait between regular rerequests.
+    #[serde(default)]
     pub interval: usize,
-    #[serde(deserialize_with = "deserialize_peer")]
+    #[serde(default, deserialize_with = "deserialize_peer")]
     pub peers: Vec<Peer>,
+    ///Seeders in the swarm, if the tracker says
+    pub complete: Option<u64>,
+    ///Leechers in the swarm, if the tracker says
+    pub incomplete: Option<u64>,
+    ///Set instead of everything else when the tracker turned us down
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PeerResponse {}
-
 fn deserialize_peer<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -173,7 +220,7 @@ where
     Ok(peers)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Peer {
     // pub id: String,
     pub ip: String,
@@ -187,6 +234,22 @@ impl Display for Peer {
 }
 
 pub type InfoHash = [u8; 20];
+
+pub fn info_hash_hex(info_hash: &InfoHash) -> String {
+    info_hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+///The other way round from info_hash_hex, None unless it is 40 hex digits
+pub fn info_hash_from_hex(hex: &str) -> Option<InfoHash> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut info_hash = [0; 20];
+    for (i, byte) in info_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(info_hash)
+}
 pub type PeerId = [u8; 20];
 // pub type Handshake = [u8; 68];
 
@@ -194,13 +257,13 @@ pub struct TorrentSession {
     pub torrent: Torrent,
     ///The torrentox peer_id
     pub peer_id: PeerId,
-    pub peers: Vec<Peer>,
+    ///Sent with every announce, so the tracker knows us whatever our ip
+    pub key: String,
 }
 
 ///Handshake returned from the peer
 #[derive(Debug)]
 pub struct PeerHandshake {
-    pub info_hash: InfoHash,
     pub peer_id: PeerId,
 }
 
@@ -208,6 +271,10 @@ pub struct PeerHandshake {
 pub struct PeerState {
     pub is_choked: bool,
     pub is_interested: bool,
+    ///Whether we are choking the peer
+    pub peer_choked: bool,
+    ///Whether the peer wants something from us
+    pub peer_interested: bool,
     pub peer_bitfield: BitVec<u8, Msb0>,
     pub num_pieces: usize,
 }
@@ -217,11 +284,18 @@ impl PeerState {
         Self {
             is_choked: true,
             is_interested: false,
+            peer_choked: true,
+            peer_interested: false,
             num_pieces,
             peer_bitfield: bitvec![u8, Msb0; 0; num_pieces],
         }
     }
 
+    ///The peer has every piece, so it will never want anything from us
+    pub fn is_seed(&self) -> bool {
+        self.peer_bitfield.all()
+    }
+
     pub fn has_piece(&self, index: usize) -> bool {
         self.peer_bitfield.get(index).map(|b| *b).unwrap_or(false)
     }
@@ -255,3 +329,67 @@ pub struct PieceMetadata {
     pub length: usize,
     pub sha1_hash: [u8; 20],
 }
+
+///Bytes moved for a torrent, kept up to date by its peers as they go
+#[derive(Debug)]
+pub struct TransferStats {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    last_activity: Mutex<Instant>,
+    ///Time spent complete and sharing, counted up by the session
+    seeding_time: Mutex<Duration>,
+}
+
+impl TransferStats {
+    ///Carry on from the totals of earlier sessions
+    pub fn new(downloaded: u64, uploaded: u64, seeding_time: Duration) -> Self {
+        Self {
+            downloaded: AtomicU64::new(downloaded),
+            uploaded: AtomicU64::new(uploaded),
+            last_activity: Mutex::new(Instant::now()),
+            seeding_time: Mutex::new(seeding_time),
+        }
+    }
+
+    pub fn seeding_time(&self) -> Duration {
+        self.seeding_time.lock().map(|s| *s).unwrap_or_default()
+    }
+
+    pub fn add_seeding_time(&self, time: Duration) {
+        if let Ok(mut seeding_time) = self.seeding_time.lock() {
+            *seeding_time += time;
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        if let Ok(mut last_activity) = self.last_activity.lock() {
+            *last_activity = Instant::now();
+        }
+    }
+
+    ///How long since a byte went either way
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity
+            .lock()
+            .map(|l| l.elapsed())
+            .unwrap_or_default()
+    }
+}
@@ -5,9 +5,12 @@ use log::debug;
 #[allow(unused_imports)]
 use log::info;
 use rand::Rng;
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{fs::File, io::Read};
 
-use crate::model::{InfoHash, PeerHandshake, Torrent, TorrentFile, TorrentFileInfo};
+use crate::error_types::HandshakeError;
+use crate::model::{
+    FileInfo, InfoHash, PeerHandshake, PeerId, PieceMetadata, Torrent, TorrentFile, TorrentFileInfo,
+};
 
 pub fn parse_torrent_file(file_name: &str) -> Result<Torrent> {
     debug!("Parsing {file_name}");
@@ -35,18 +38,31 @@ pub fn parse_torrent_file(file_name: &str) -> Result<Torrent> {
     let size = get_size(&torrent_file);
 
     let torrent = Torrent {
+        announce_url: torrent_file.announce.clone(),
         torrent_file,
         name,
         file_path: file_name.to_owned(),
         raw_bytes: file_bytes,
-        announce_url: None,
         downloaded: 0,
         uploaded: 0,
         size,
+        download_limit: None,
+        upload_limit: None,
     };
     Ok(torrent)
 }
 
+///Every file of the torrent, in order. A single file torrent is one file named after the torrent
+pub fn get_files(torrent_file: &TorrentFile) -> Vec<FileInfo> {
+    match &torrent_file.info.file {
+        TorrentFileInfo::SingleFile { length } => vec![FileInfo {
+            length: *length,
+            path: vec![torrent_file.info.name.clone().unwrap_or("None".to_owned())],
+        }],
+        TorrentFileInfo::MultipleFiles { files } => files.clone(),
+    }
+}
+
 ///Get either the size (single file mode) or sume of sizes (multi filed mode)
 pub fn get_size(torrent_file: &TorrentFile) -> u64 {
     match torrent_file.info.file.clone() {
@@ -65,6 +81,30 @@ pub fn get_size(torrent_file: &TorrentFile) -> u64 {
     //}
 }
 
+///Split the concatenated piece hashes into one entry per piece.
+///Every piece is `piece length` long, except the last, which gets whatever is left over
+pub fn get_piece_metadata(torrent_file: &TorrentFile) -> Vec<PieceMetadata> {
+    let piece_length = torrent_file.info.piece_length;
+    let total_size = get_size(torrent_file);
+    torrent_file
+        .info
+        .pieces
+        .chunks_exact(20)
+        .enumerate()
+        .map(|(index, hash)| {
+            let start = index as u64 * piece_length;
+            let length = piece_length.min(total_size.saturating_sub(start)) as usize;
+            let mut sha1_hash = [0u8; 20];
+            sha1_hash.copy_from_slice(hash);
+            PieceMetadata {
+                index: index as u32,
+                length,
+                sha1_hash,
+            }
+        })
+        .collect()
+}
+
 //pub fn parse_info_hash(metadata_info: &Info) -> Result<InfoHash> {
 //    let info_bytes = serde_bencode::to_bytes(metadata_info)?;
 //    let info_digest = Sha1::digest(info_bytes);
@@ -74,16 +114,8 @@ pub fn get_size(torrent_file: &TorrentFile) -> u64 {
 //}
 
 const UNIVERSE_OF_CHARS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890";
-///If the torrentfile has a peer_id in the map, return it.
-///Otherwise create a peer id and put it in the map, with the torrentfile name as the key
-pub fn get_or_create_peer_id(
-    torrent_file_name: &str,
-    peer_id_cach: &mut HashMap<String, String>,
-) -> Result<String> {
-    if let Some(peer_id) = peer_id_cach.get(torrent_file_name) {
-        return Ok(peer_id.to_owned());
-    }
-    //https://www.bittorrent.org/beps/bep_0020.html
+///A fresh peer id, saying who we are up front, https://www.bittorrent.org/beps/bep_0020.html
+pub fn new_peer_id() -> String {
     //first we identify ourselves - THE OX RIDES AGAIN!!
     //get our version numbers
     let version = crate_version!();
@@ -104,30 +136,51 @@ pub fn get_or_create_peer_id(
         let the_char = UNIVERSE_OF_CHARS.chars().nth(char_num).unwrap_or('?');
         peer_id.push(the_char);
     }
-    //never again will we , the free, be subjected to the unfree freeing of the overfree
-    peer_id_cach.insert(torrent_file_name.to_string(), peer_id.to_string());
+    peer_id
+}
 
-    Ok(peer_id)
+///The key trackers use to know us when our ip changes, random and not part of the peer id
+pub fn new_tracker_key() -> String {
+    let mut rnd = rand::rng();
+    (0..8)
+        .map(|_| {
+            let char_num = rnd.random_range(0..UNIVERSE_OF_CHARS.len());
+            UNIVERSE_OF_CHARS.chars().nth(char_num).unwrap_or('?')
+        })
+        .collect()
 }
 
-pub fn parse_peer_response(peer_response_bytes: &[u8]) -> Option<PeerHandshake> {
+///Check a peer's handshake reply, byte for byte.
+///The info hash must be the one we asked for, and the peer id must not be our own
+pub fn parse_peer_response(
+    peer_response_bytes: &[u8],
+    expected_info_hash: &InfoHash,
+    our_peer_id: &PeerId,
+) -> Result<PeerHandshake, HandshakeError> {
     if peer_response_bytes.len() != 68 {
-        return None;
+        return Err(HandshakeError::WrongLength(peer_response_bytes.len()));
     }
-    if peer_response_bytes[0] != 19 && &peer_response_bytes[1..20] != b"BitTorrent protocol" {
-        return None;
+    if peer_response_bytes[0] != 19 || &peer_response_bytes[1..20] != b"BitTorrent protocol" {
+        return Err(HandshakeError::WrongProtocol);
     }
     let mut info_hash = [0u8; 20];
     info_hash.copy_from_slice(&peer_response_bytes[28..48]);
+    if &info_hash != expected_info_hash {
+        return Err(HandshakeError::InfoHashMismatch);
+    }
     let mut peer_id = [0u8; 20];
     peer_id.copy_from_slice(&peer_response_bytes[48..68]);
-    Some(PeerHandshake { info_hash, peer_id })
+    if &peer_id == our_peer_id {
+        return Err(HandshakeError::SelfConnection);
+    }
+    Ok(PeerHandshake { peer_id })
 }
 
 #[cfg(test)]
 mod test {
     #[allow(unused_imports)]
     use super::*;
+    use crate::api::build_handshake;
 
     const TORRENT_FILE_NAME: &str = "Fedora-KDE-Live-x86_64-40.torrent";
     #[allow(unused_imports)]
@@ -157,8 +210,6 @@ mod test {
         assert!(torrent.torrent_file.info.piece_length > 0);
         assert_eq!(None, torrent.torrent_file.info.meta_version);
 
-        let mut files_found = false;
-        let mut length_found = false;
         match torrent.torrent_file.info.file.clone() {
             TorrentFileInfo::SingleFile { length } => info!("Length of SINGLE FILE: {}", length),
             TorrentFileInfo::MultipleFiles { files } => {
@@ -221,34 +272,10 @@ mod test {
     }
 
     #[test]
-    pub fn test_get_or_create_peer_id() {
-        //We ask for an id whn we do not have one
-        let torrent_file_name = "foom.torrent";
-        let mut cache: HashMap<String, String> = HashMap::new();
-        let id = get_or_create_peer_id(torrent_file_name, &mut cache).unwrap();
-        info!("Id we received back: {}", id);
-        //We get one back
-        assert_eq!(1, cache.len());
+    pub fn test_new_peer_id() {
+        let id = new_peer_id();
         assert_eq!(20, id.len());
         assert!(id.starts_with("-OX"));
-        //We also have it in the cache
-        let default = "wrongo".to_owned();
-        let cache_id = cache.get(torrent_file_name).unwrap_or(&default);
-        assert_eq!(id, cache_id.to_owned());
-
-        //we ask for an id when we have one
-        cache.insert(
-            "test.whatnot".to_owned(),
-            "cache rules everything around me".to_owned(),
-        );
-        //we get the one that was in the cache back
-        let new_id = get_or_create_peer_id("test.whatnot", &mut cache).unwrap();
-
-        //there is no other one in the cache
-        let second_torrent_name = "test.whatnot";
-        let get_or_created_peer_id = cache.get(second_torrent_name).unwrap_or(&default);
-        assert_eq!(&new_id, get_or_created_peer_id);
-        //
     }
 
     #[test]
@@ -258,21 +285,18 @@ mod test {
         info!("This is the bencoded {:?}", bencoded);
         assert_eq!(bencoded.piece_length, 262144);
         match bencoded.file {
-            TorrentFileInfo::SingleFile { length } => {
+            TorrentFileInfo::SingleFile { length: _ } => {
                 panic!("Test file is multi file only for now, more to come")
             }
             TorrentFileInfo::MultipleFiles { files } => {
                 assert_eq!(2, files.len());
-                let file_info = files
-                    .get(0)
-                    .ok_or_else(|| "Failed to get first element")
-                    .unwrap();
+                let file_info = files.first().ok_or("Failed to get first element").unwrap();
                 //check that it is either the checksum or the whole file instead
                 assert!(file_info.length == 2645645312 || file_info.length == 2582);
                 //only one item, the file name
                 assert_eq!(1, file_info.path.len());
 
-                let file_path = file_info.path.get(0).unwrap();
+                let file_path = file_info.path.first().unwrap();
                 assert!(
                     file_path == "Fedora-KDE-Live-x86_64-40-1.14.iso"
                         || file_path == "Fedora-Spins-40-1.14-x86_64-CHECKSUM"
@@ -280,4 +304,68 @@ mod test {
             }
         }
     }
+
+    #[test]
+    pub fn test_get_piece_metadata() {
+        let torrent = parse_torrent_file(TORRENT_FILE_NAME).unwrap();
+        let pieces = get_piece_metadata(&torrent.torrent_file);
+        let piece_length = torrent.torrent_file.info.piece_length;
+        let expected_pieces = torrent.size.div_ceil(piece_length);
+        assert_eq!(expected_pieces as usize, pieces.len());
+        //every piece is full sized except the last one
+        let total: u64 = pieces.iter().map(|p| p.length as u64).sum();
+        assert_eq!(torrent.size, total);
+        assert_eq!(piece_length as usize, pieces[0].length);
+        assert_eq!(
+            &torrent.torrent_file.info.pieces[0..20],
+            &pieces[0].sha1_hash[..]
+        );
+    }
+
+    const INFO_HASH: InfoHash = [7u8; 20];
+    const OUR_PEER_ID: PeerId = *b"-OX0-1-0-ourselves!!";
+    const THEIR_PEER_ID: PeerId = *b"-TR4050-someoneelse!";
+
+    #[test]
+    pub fn test_parse_peer_response_accepts_valid_handshake() {
+        let reply = build_handshake(&INFO_HASH, &THEIR_PEER_ID);
+        let handshake = parse_peer_response(&reply, &INFO_HASH, &OUR_PEER_ID).unwrap();
+        assert_eq!(THEIR_PEER_ID, handshake.peer_id);
+    }
+
+    #[test]
+    pub fn test_parse_peer_response_rejects_wrong_length() {
+        let reply = build_handshake(&INFO_HASH, &THEIR_PEER_ID);
+        let result = parse_peer_response(&reply[..67], &INFO_HASH, &OUR_PEER_ID);
+        assert_eq!(Err(HandshakeError::WrongLength(67)), result.map(|_| ()));
+    }
+
+    #[test]
+    pub fn test_parse_peer_response_rejects_wrong_protocol() {
+        //right length byte, wrong string: used to slip through
+        let mut reply = build_handshake(&INFO_HASH, &THEIR_PEER_ID);
+        reply[1..20].copy_from_slice(b"BitTorrent protocoL");
+        let result = parse_peer_response(&reply, &INFO_HASH, &OUR_PEER_ID);
+        assert_eq!(Err(HandshakeError::WrongProtocol), result.map(|_| ()));
+
+        //wrong length byte, right string
+        let mut reply = build_handshake(&INFO_HASH, &THEIR_PEER_ID);
+        reply[0] = 18;
+        let result = parse_peer_response(&reply, &INFO_HASH, &OUR_PEER_ID);
+        assert_eq!(Err(HandshakeError::WrongProtocol), result.map(|_| ()));
+    }
+
+    #[test]
+    pub fn test_parse_peer_response_rejects_other_torrent() {
+        let reply = build_handshake(&[8u8; 20], &THEIR_PEER_ID);
+        let result = parse_peer_response(&reply, &INFO_HASH, &OUR_PEER_ID);
+        assert_eq!(Err(HandshakeError::InfoHashMismatch), result.map(|_| ()));
+    }
+
+    #[test]
+    pub fn test_parse_peer_response_rejects_ourselves() {
+        let reply = build_handshake(&INFO_HASH, &OUR_PEER_ID);
+        let result = parse_peer_response(&reply, &INFO_HASH, &OUR_PEER_ID);
+        assert_eq!(Err(HandshakeError::SelfConnection), result.map(|_| ()));
+    }
 }
@@ -0,0 +1,227 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use fs2::FileExt;
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "torrentox";
+pub const DB_FILE: &str = "torrentox.db";
+const LOCK_FILE: &str = "torrentox.lock";
+const CONFIG_FILE: &str = "torrentox.toml";
+const LOG_FILE: &str = "torrentox.log";
+
+///Where torrentox keeps its db, reads its config and writes its log. From the XDG base
+///directories, https://specifications.freedesktop.org/basedir-spec/latest/, unless overridden
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    ///The db and its lock file
+    pub data_dir: PathBuf,
+    pub config_file: PathBuf,
+    pub log_file: PathBuf,
+}
+
+///What the command line or the environment asked for instead
+#[derive(Debug, Clone, Default)]
+pub struct PathOverrides {
+    pub data_dir: Option<PathBuf>,
+    pub config_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+}
+
+impl Paths {
+    ///Overrides first, then $XDG_DATA_HOME, $XDG_CONFIG_HOME and $XDG_STATE_HOME, then the
+    ///defaults the spec gives under $HOME. `env` looks up environment variables
+    pub fn resolve(overrides: PathOverrides, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let base = |xdg: &str, fallback: &str| -> Result<PathBuf> {
+            //the spec says relative ones are to be ignored
+            if let Some(dir) = env(xdg).map(PathBuf::from).filter(|d| d.is_absolute()) {
+                return Ok(dir.join(APP_DIR));
+            }
+            let home = env("HOME")
+                .filter(|h| !h.is_empty())
+                .ok_or_else(|| eyre!("Neither ${xdg} nor $HOME is set, pass the paths instead"))?;
+            Ok(Path::new(&home).join(fallback).join(APP_DIR))
+        };
+        let data_dir = match overrides.data_dir {
+            Some(dir) => dir,
+            None => base("XDG_DATA_HOME", ".local/share")?,
+        };
+        let config_file = match overrides.config_file {
+            Some(file) => file,
+            None => base("XDG_CONFIG_HOME", ".config")?.join(CONFIG_FILE),
+        };
+        let log_file = match overrides.log_file {
+            Some(file) => file,
+            None => base("XDG_STATE_HOME", ".local/state")?.join(LOG_FILE),
+        };
+        Ok(Self {
+            data_dir,
+            config_file,
+            log_file,
+        })
+    }
+
+    pub fn db_file(&self) -> PathBuf {
+        self.data_dir.join(DB_FILE)
+    }
+
+    ///Make the directories the db and the log go in. The config is only ever read
+    pub fn create_dirs(&self) -> Result<()> {
+        fs::create_dir_all(&self.data_dir)
+            .wrap_err_with(|| format!("Failed to create {}", self.data_dir.display()))?;
+        if let Some(dir) = self.log_file.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    ///Older versions kept the db wherever they were run from. If there is one in `dir` and none
+    ///in the data dir yet, it moves over, with its journal if a crash left one behind.
+    ///True if it moved
+    pub fn adopt_legacy_db(&self, dir: &Path) -> Result<bool> {
+        let legacy = dir.join(DB_FILE);
+        let db_file = self.db_file();
+        if !legacy.is_file() || same_file(&legacy, &db_file) {
+            return Ok(false);
+        }
+        if db_file.exists() {
+            warn!(
+                "Found {} but {} is already there, leaving the old one alone",
+                legacy.display(),
+                db_file.display()
+            );
+            return Ok(false);
+        }
+        for suffix in ["-journal", "-wal", "-shm", ""] {
+            let from = dir.join(format!("{DB_FILE}{suffix}"));
+            if from.exists() {
+                move_file(&from, &self.data_dir.join(format!("{DB_FILE}{suffix}")))?;
+            }
+        }
+        info!("Moved {} to {}", legacy.display(), db_file.display());
+        Ok(true)
+    }
+
+    ///Only one session gets to download with the db at a time. The lock goes when the
+    ///returned file is dropped, or the process dies. The commands don't take it, they only
+    ///leave requests in the db for the session to pick up
+    pub fn lock(&self) -> Result<File> {
+        let path = self.data_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("Failed to open the lock file {}", path.display()))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(eyre!(
+                "torrentox is already running on {} (pid {})",
+                self.data_dir.display(),
+                pid.trim()
+            ));
+        }
+        //for the error message of whoever comes next
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(file)
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+///A rename, or a copy when the data dir is on another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)
+        .wrap_err_with(|| format!("Failed to move {} to {}", from.display(), to.display()))?;
+    fs::remove_file(from).wrap_err_with(|| format!("Failed to remove {}", from.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve(vars: &[(&str, &str)], overrides: PathOverrides) -> Result<Paths> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Paths::resolve(overrides, |name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let paths = resolve(&[("HOME", "/home/ox")], PathOverrides::default()).unwrap();
+        assert_eq!(Path::new("/home/ox/.local/share/torrentox"), paths.data_dir);
+        assert_eq!(
+            Path::new("/home/ox/.config/torrentox/torrentox.toml"),
+            paths.config_file
+        );
+        assert_eq!(
+            Path::new("/home/ox/.local/state/torrentox/torrentox.log"),
+            paths.log_file
+        );
+
+        let vars = [
+            ("HOME", "/home/ox"),
+            ("XDG_DATA_HOME", "/data"),
+            ("XDG_CONFIG_HOME", "relative/is/ignored"),
+        ];
+        let overrides = PathOverrides {
+            log_file: Some(PathBuf::from("ox.log")),
+            ..PathOverrides::default()
+        };
+        let paths = resolve(&vars, overrides).unwrap();
+        assert_eq!(Path::new("/data/torrentox/torrentox.db"), paths.db_file());
+        assert_eq!(
+            Path::new("/home/ox/.config/torrentox/torrentox.toml"),
+            paths.config_file
+        );
+        assert_eq!(Path::new("ox.log"), paths.log_file);
+
+        assert!(resolve(&[], PathOverrides::default()).is_err());
+    }
+
+    #[test]
+    fn test_adopt_legacy_db_and_lock() {
+        let dir = std::env::temp_dir().join(format!("torrentox_paths_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let paths = Paths {
+            data_dir: dir.join("data"),
+            config_file: dir.join(CONFIG_FILE),
+            log_file: dir.join("state").join(LOG_FILE),
+        };
+        paths.create_dirs().unwrap();
+        assert!(!paths.adopt_legacy_db(&dir).unwrap());
+
+        fs::write(dir.join(DB_FILE), b"old db").unwrap();
+        assert!(paths.adopt_legacy_db(&dir).unwrap());
+        assert!(!dir.join(DB_FILE).exists());
+        assert_eq!(b"old db".to_vec(), fs::read(paths.db_file()).unwrap());
+
+        //one already in the data dir wins
+        fs::write(dir.join(DB_FILE), b"older db").unwrap();
+        assert!(!paths.adopt_legacy_db(&dir).unwrap());
+        assert_eq!(b"old db".to_vec(), fs::read(paths.db_file()).unwrap());
+
+        let lock = paths.lock().unwrap();
+        let error = paths.lock().unwrap_err().to_string();
+        assert!(error.contains(&std::process::id().to_string()));
+        drop(lock);
+        assert!(paths.lock().is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,161 @@
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use crate::model::{Peer, PeerId};
+
+///Peers we got through to before, dialled first when a torrent starts again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerCacheSettings {
+    ///Most cached peers dialled per torrent, 0 to not use the cache at all
+    pub max_peers: usize,
+    ///Peers not seen for this many days are forgotten
+    pub max_age_days: u64,
+    ///Peers we failed to get through to this many times in a row are forgotten
+    pub max_failures: u32,
+}
+
+impl Default for PeerCacheSettings {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            max_age_days: 7,
+            max_failures: 5,
+        }
+    }
+}
+
+///A peer of a torrent, as the db remembers it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownPeer {
+    pub ip: String,
+    pub port: u16,
+    ///Unix seconds
+    pub last_seen: i64,
+    ///Bytes, over every session
+    pub downloaded: u64,
+    pub uploaded: u64,
+    ///Failed dials since we last got through
+    pub failures: u32,
+    pub client: Option<String>,
+}
+
+impl KnownPeer {
+    pub fn peer(&self) -> Peer {
+        Peer {
+            ip: self.ip.clone(),
+            port: self.port,
+        }
+    }
+}
+
+///How a dial went, for the session to write to the db on its next tick
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerOutcome {
+    ///Handshaken, and this much moved before we were done with each other
+    Connected {
+        peer: Peer,
+        client: Option<String>,
+        downloaded: u64,
+        uploaded: u64,
+    },
+    ///Gave up on it
+    Failed { peer: Peer },
+}
+
+///Outcomes piling up between ticks. The peer tasks can't get at the db themselves
+#[derive(Debug, Default)]
+pub struct PeerLog {
+    outcomes: Mutex<Vec<PeerOutcome>>,
+}
+
+impl PeerLog {
+    pub fn push(&self, outcome: PeerOutcome) {
+        if let Ok(mut outcomes) = self.outcomes.lock() {
+            outcomes.push(outcome);
+        }
+    }
+
+    pub fn take(&self) -> Vec<PeerOutcome> {
+        self.outcomes
+            .lock()
+            .map(|mut o| std::mem::take(&mut *o))
+            .unwrap_or_default()
+    }
+}
+
+///Cached peers first, then whatever the tracker had that we didn't already have
+pub fn dial_order(known: &[KnownPeer], announced: Vec<Peer>) -> Vec<Peer> {
+    let mut peers: Vec<Peer> = known.iter().map(KnownPeer::peer).collect();
+    for peer in announced {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+    peers
+}
+
+///Who made the peer's client, from an Azureus style peer id like -qB4250-,
+///https://www.bittorrent.org/beps/bep_0020.html
+pub fn client_name(peer_id: &PeerId) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    let name = match code {
+        "OX" => "torrentox",
+        "qB" => "qBittorrent",
+        "TR" => "Transmission",
+        "DE" => "Deluge",
+        "UT" => "µTorrent",
+        "LT" | "lt" => "libtorrent",
+        "AZ" => "Vuze",
+        "BI" => "BiglyBT",
+        "KT" => "KTorrent",
+        _ => code,
+    };
+    Some(format!("{name} {version}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(port: u16) -> Peer {
+        Peer {
+            ip: "10.0.0.1".to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_dial_order() {
+        let known = vec![KnownPeer {
+            ip: "10.0.0.1".to_owned(),
+            port: 2,
+            ..KnownPeer::default()
+        }];
+        assert_eq!(
+            vec![peer(2), peer(1), peer(3)],
+            dial_order(&known, vec![peer(1), peer(2), peer(3)])
+        );
+    }
+
+    #[test]
+    fn test_client_name() {
+        let mut peer_id = *b"-qB4250-abcdefghijkl";
+        assert_eq!(Some("qBittorrent 4250".to_owned()), client_name(&peer_id));
+        peer_id[1..3].copy_from_slice(b"ZZ");
+        assert_eq!(Some("ZZ 4250".to_owned()), client_name(&peer_id));
+        assert_eq!(None, client_name(b"M7-2-2--abcdefghijkl"));
+    }
+
+    #[test]
+    fn test_peer_log() {
+        let log = PeerLog::default();
+        log.push(PeerOutcome::Failed { peer: peer(1) });
+        assert_eq!(1, log.take().len());
+        assert!(log.take().is_empty());
+    }
+}
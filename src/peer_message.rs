@@ -0,0 +1,182 @@
+use color_eyre::eyre::Result;
+use eyre::eyre;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+///Largest message we are willing to read, a 16KiB block plus change.
+///Anything bigger than this is a misbehaving peer
+const MAX_MESSAGE_LENGTH: u32 = 1 << 17;
+
+///Messages of the peer wire protocol, see https://www.bittorrent.org/beps/bep_0003.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    ///Something we do not speak (yet), we keep the id so we can log it
+    Unknown(u8),
+}
+
+impl PeerMessage {
+    ///Length prefixed bytes, ready to go on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => payload.push(0),
+            PeerMessage::Unchoke => payload.push(1),
+            PeerMessage::Interested => payload.push(2),
+            PeerMessage::NotInterested => payload.push(3),
+            PeerMessage::Have(index) => {
+                payload.push(4);
+                payload.extend_from_slice(&index.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bits) => {
+                payload.push(5);
+                payload.extend_from_slice(bits);
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(6);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.push(7);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(8);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            PeerMessage::Unknown(id) => payload.push(*id),
+        }
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    ///Turn a message body (everything after the length prefix) into a message
+    pub fn from_payload(payload: &[u8]) -> Result<PeerMessage> {
+        let Some((&id, body)) = payload.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let message = match id {
+            0 => PeerMessage::Choke,
+            1 => PeerMessage::Unchoke,
+            2 => PeerMessage::Interested,
+            3 => PeerMessage::NotInterested,
+            4 => PeerMessage::Have(read_u32(body, 0)?),
+            5 => PeerMessage::Bitfield(body.to_vec()),
+            6 => PeerMessage::Request {
+                index: read_u32(body, 0)?,
+                begin: read_u32(body, 4)?,
+                length: read_u32(body, 8)?,
+            },
+            7 => PeerMessage::Piece {
+                index: read_u32(body, 0)?,
+                begin: read_u32(body, 4)?,
+                block: body.get(8..).unwrap_or_default().to_vec(),
+            },
+            8 => PeerMessage::Cancel {
+                index: read_u32(body, 0)?,
+                begin: read_u32(body, 4)?,
+                length: read_u32(body, 8)?,
+            },
+            other => PeerMessage::Unknown(other),
+        };
+        Ok(message)
+    }
+}
+
+fn read_u32(body: &[u8], offset: usize) -> Result<u32> {
+    let bytes = body
+        .get(offset..offset + 4)
+        .ok_or_else(|| eyre!("Peer message too short, wanted 4 bytes at offset {offset}"))?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+///Read exactly one length prefixed message off the stream
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<PeerMessage> {
+    let length = stream.read_u32().await?;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(eyre!(
+            "Peer sent a message of {length} bytes, which is too big"
+        ));
+    }
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload).await?;
+    PeerMessage::from_payload(&payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let messages = vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Unchoke,
+            PeerMessage::Have(42),
+            PeerMessage::Bitfield(vec![0b1010_0000]),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 3,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let mut reader = bytes.as_slice();
+            let read_back = read_message(&mut reader).await.unwrap();
+            assert_eq!(message, read_back);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let bytes = (MAX_MESSAGE_LENGTH + 1).to_be_bytes();
+        let mut reader = bytes.as_slice();
+        assert!(read_message(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,661 @@
+use bitvec::prelude::*;
+use color_eyre::eyre::Result;
+use eyre::eyre;
+use log::{debug, warn};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::model::{FilePriority, PieceMetadata};
+
+///Size of a single block request, 16KiB, which is what every client out there expects
+pub const BLOCK_SIZE: u32 = 16384;
+///How many hash failures a peer may contribute to before we stop talking to it
+pub const DEFAULT_MAX_STRIKES: u32 = 3;
+///How many pieces ahead of the first missing one a sequential download works on
+pub const DEFAULT_READAHEAD: usize = 8;
+
+///What came of the hash check of a whole piece
+#[derive(Debug, PartialEq, Eq)]
+pub enum PieceOutcome {
+    ///All the blocks are in and the sha1 matches
+    Verified { index: u32, data: Vec<u8> },
+    ///All the blocks are in and the sha1 does NOT match, these peers sent us blocks of it
+    HashFailed {
+        index: u32,
+        contributors: Vec<String>,
+        wasted: u64,
+    },
+}
+
+///A piece that we have some, but not all, of the blocks for
+struct PieceInProgress {
+    data: Vec<u8>,
+    ///Who sent us each block, None if we do not have it yet
+    senders: Vec<Option<String>>,
+    ///Peer currently requesting blocks for this piece
+    owner: Option<String>,
+}
+
+impl PieceInProgress {
+    fn new(length: usize) -> Self {
+        let num_blocks = length.div_ceil(BLOCK_SIZE as usize);
+        Self {
+            data: vec![0u8; length],
+            senders: vec![None; num_blocks],
+            owner: None,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.senders.iter().all(|s| s.is_some())
+    }
+
+    ///Everyone who sent us at least one block, in the order we first heard from them
+    fn contributors(&self) -> Vec<String> {
+        let mut contributors: Vec<String> = Vec::new();
+        for sender in self.senders.iter().flatten() {
+            if !contributors.contains(sender) {
+                contributors.push(sender.clone());
+            }
+        }
+        contributors
+    }
+}
+
+///Download progress of one torrent, shared between all the peers we are talking to for it
+pub struct DownloadState {
+    pub pieces: Vec<PieceMetadata>,
+    ///Pieces we have verified
+    pub have: BitVec<u8, Msb0>,
+    in_progress: HashMap<u32, PieceInProgress>,
+    ///One per piece, from the files it covers. Skipped pieces are never requested
+    pub priorities: Vec<FilePriority>,
+    ///Pieces to get front to back, rather than rarest first
+    pub sequential: BitVec<u8, Msb0>,
+    ///How many of the first missing sequential pieces are up for grabs at once
+    pub readahead: usize,
+    ///How many of the peers we are talking to have each piece
+    availability: Vec<u32>,
+    ///Pieces someone is waiting on right now, they beat everything else
+    urgent: BTreeSet<u32>,
+    ///Bytes we downloaded and then threw away because the piece failed its hash check
+    pub wasted_bytes: u64,
+    ///Pieces verified while a recheck runs, what it finds on disk may be older than them
+    verified_during_check: Option<BTreeSet<u32>>,
+}
+
+impl DownloadState {
+    pub fn new(pieces: Vec<PieceMetadata>) -> Self {
+        let num_pieces = pieces.len();
+        Self {
+            pieces,
+            have: bitvec![u8, Msb0; 0; num_pieces],
+            in_progress: HashMap::new(),
+            priorities: vec![FilePriority::Normal; num_pieces],
+            sequential: bitvec![u8, Msb0; 0; num_pieces],
+            readahead: DEFAULT_READAHEAD,
+            availability: vec![0; num_pieces],
+            urgent: BTreeSet::new(),
+            wasted_bytes: 0,
+            verified_during_check: None,
+        }
+    }
+
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+    }
+
+    pub fn set_sequential(&mut self, sequential: BitVec<u8, Msb0>, readahead: usize) {
+        self.sequential = sequential;
+        self.readahead = readahead.max(1);
+    }
+
+    ///A peer told us which pieces it has
+    pub fn add_availability(&mut self, peer_has: &BitSlice<u8, Msb0>) {
+        for index in peer_has.iter_ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    ///A peer we were talking to is gone, or replaced its bitfield
+    pub fn remove_availability(&mut self, peer_has: &BitSlice<u8, Msb0>) {
+        for index in peer_has.iter_ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    ///Get these pieces before anything else, e.g. because someone is streaming them
+    pub fn make_urgent(&mut self, pieces: std::ops::Range<u32>) {
+        for index in pieces {
+            if self.have.get(index as usize).is_some_and(|b| !*b) {
+                self.urgent.insert(index);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_urgent(&self, index: u32) -> bool {
+        self.urgent.contains(&index)
+    }
+
+    pub fn priority(&self, index: usize) -> FilePriority {
+        self.priorities
+            .get(index)
+            .copied()
+            .unwrap_or(FilePriority::Normal)
+    }
+
+    ///Complete once we have every piece we want, skipped ones do not count
+    pub fn is_complete(&self) -> bool {
+        self.have
+            .iter_zeros()
+            .all(|i| self.priority(i) == FilePriority::Skip)
+    }
+
+    ///Pieces we still want and do not have yet
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.have
+            .iter_zeros()
+            .filter(|i| self.priority(*i) != FilePriority::Skip)
+    }
+
+    ///Pick the piece this peer should work on next.
+    ///Urgent pieces come first, then half finished pieces nobody is working on, then the
+    ///sequential pieces within the readahead window, then the rarest piece we do not have,
+    ///high priority before normal
+    pub fn pick_piece(&mut self, peer: &str, peer_has: &BitSlice<u8, Msb0>) -> Option<u32> {
+        let peer_has_piece = |index: u32| peer_has.get(index as usize).map(|b| *b).unwrap_or(false);
+        let urgent = self
+            .urgent
+            .iter()
+            .find(|i| {
+                peer_has_piece(**i)
+                    && self
+                        .in_progress
+                        .get(i)
+                        .is_none_or(|p| p.owner.is_none() && !p.is_complete())
+            })
+            .copied();
+        let orphan = self
+            .in_progress
+            .iter()
+            .filter(|(index, p)| {
+                p.owner.is_none()
+                    && !p.is_complete()
+                    && peer_has_piece(**index)
+                    && self.priority(**index as usize) != FilePriority::Skip
+            })
+            .map(|(index, _)| *index)
+            .min();
+        let available =
+            |i: &usize| !self.in_progress.contains_key(&(*i as u32)) && peer_has_piece(*i as u32);
+        let index = urgent.or(orphan).or_else(|| {
+            let in_window = self
+                .missing()
+                .filter(|i| self.sequential[*i])
+                .take(self.readahead)
+                .find(available);
+            in_window
+                .or_else(|| {
+                    self.missing()
+                        .filter(available)
+                        .min_by_key(|i| (Reverse(self.priority(*i)), self.availability[*i], *i))
+                })
+                .map(|i| i as u32)
+        })?;
+        let length = self.pieces.get(index as usize)?.length;
+        let piece = self
+            .in_progress
+            .entry(index)
+            .or_insert_with(|| PieceInProgress::new(length));
+        piece.owner = Some(peer.to_owned());
+        Some(index)
+    }
+
+    ///The (begin, length) of each block of the piece we still need
+    pub fn missing_blocks(&self, index: u32) -> Vec<(u32, u32)> {
+        let (Some(piece), Some(meta)) = (
+            self.in_progress.get(&index),
+            self.pieces.get(index as usize),
+        ) else {
+            return Vec::new();
+        };
+        piece
+            .senders
+            .iter()
+            .enumerate()
+            .filter(|(_, sender)| sender.is_none())
+            .map(|(block, _)| {
+                let begin = block as u32 * BLOCK_SIZE;
+                let length = BLOCK_SIZE.min(meta.length as u32 - begin);
+                (begin, length)
+            })
+            .collect()
+    }
+
+    ///Store a block, remembering who sent it. Hands back the whole piece once the last
+    ///block is in, to be hashed without holding up everyone else, and then given to finish_piece
+    pub fn store_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+        from: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        if index as usize >= self.pieces.len() {
+            return Err(eyre!("Peer {from} sent block for unknown piece {index}"));
+        }
+        if self.have[index as usize] {
+            debug!("Already have piece {index}, dropping block from {from}");
+            return Ok(None);
+        }
+        //a late block, say for a piece that failed its hash check meanwhile. Not the peer's fault
+        let Some(piece) = self.in_progress.get_mut(&index) else {
+            debug!("Piece {index} is not in progress, dropping block from {from}");
+            return Ok(None);
+        };
+        if piece.is_complete() {
+            debug!("Piece {index} is being checked already, dropping block from {from}");
+            return Ok(None);
+        }
+        let end = begin as usize + block.len();
+        if !begin.is_multiple_of(BLOCK_SIZE) || end > piece.data.len() {
+            return Err(eyre!(
+                "Peer {from} sent a misaligned block for piece {index}: begin {begin}, length {}",
+                block.len()
+            ));
+        }
+        piece.data[begin as usize..end].copy_from_slice(block);
+        piece.senders[(begin / BLOCK_SIZE) as usize] = Some(from.to_owned());
+        //the piece stays in progress while it is hashed, so nobody picks it up again
+        Ok(piece.is_complete().then(|| std::mem::take(&mut piece.data)))
+    }
+
+    pub fn expected_hash(&self, index: u32) -> Option<[u8; 20]> {
+        self.pieces.get(index as usize).map(|p| p.sha1_hash)
+    }
+
+    ///Record how the hash check of a piece from store_block went
+    pub fn finish_piece(
+        &mut self,
+        index: u32,
+        data: Vec<u8>,
+        hash_ok: bool,
+    ) -> Result<PieceOutcome> {
+        let piece = self
+            .in_progress
+            .remove(&index)
+            .ok_or_else(|| eyre!("Piece {index} vanished while verifying"))?;
+        if hash_ok {
+            self.have.set(index as usize, true);
+            self.urgent.remove(&index);
+            if let Some(verified) = &mut self.verified_during_check {
+                verified.insert(index);
+            }
+            Ok(PieceOutcome::Verified { index, data })
+        } else {
+            let wasted = data.len() as u64;
+            self.wasted_bytes += wasted;
+            let contributors = piece.contributors();
+            warn!("Piece {index} failed its hash check, sent by {contributors:?}");
+            Ok(PieceOutcome::HashFailed {
+                index,
+                contributors,
+                wasted,
+            })
+        }
+    }
+
+    ///What a recheck found on disk, replacing whatever we thought we had
+    pub fn set_have(&mut self, verified: &[u32]) {
+        self.have.fill(false);
+        for index in verified {
+            if (*index as usize) < self.have.len() {
+                self.have.set(*index as usize, true);
+                self.in_progress.remove(index);
+                self.urgent.remove(index);
+            }
+        }
+    }
+
+    ///A recheck is starting, hold on to the pieces that get verified until it is done
+    pub fn start_check(&mut self) {
+        self.verified_during_check = Some(BTreeSet::new());
+    }
+
+    ///What the recheck found on disk, plus whatever got verified while it ran.
+    ///None if it was cancelled, leaving things as they are
+    pub fn finish_check(&mut self, found: Option<&[u32]>) {
+        let during = self.verified_during_check.take().unwrap_or_default();
+        if let Some(found) = found {
+            let verified: Vec<u32> = found.iter().copied().chain(during).collect();
+            self.set_have(&verified);
+        }
+    }
+
+    ///The pieces a past session had verified, from the bitfield it saved
+    pub fn restore_have(&mut self, bitfield: &[u8]) {
+        let saved = BitVec::<u8, Msb0>::from_slice(bitfield);
+        let verified: Vec<u32> = saved.iter_ones().map(|i| i as u32).collect();
+        self.set_have(&verified);
+    }
+
+    ///Give back whatever this peer was working on, so someone else can finish it
+    pub fn release_peer(&mut self, peer: &str) {
+        self.in_progress
+            .values_mut()
+            .filter(|p| p.owner.as_deref() == Some(peer))
+            .for_each(|p| p.owner = None);
+    }
+}
+
+///Peers we refuse to talk to for the rest of the session.
+///Strikes are counted per ip, since a peer that gets dropped tends to come back on another port
+pub struct BanList {
+    strikes: HashMap<String, u32>,
+    banned: HashSet<String>,
+    pub max_strikes: u32,
+}
+
+impl BanList {
+    pub fn new(max_strikes: u32) -> Self {
+        Self {
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            max_strikes,
+        }
+    }
+
+    pub fn ban(&mut self, ip: &str) {
+        self.banned.insert(ip.to_owned());
+    }
+
+    ///Peer addresses are "ip:port", bans are by ip
+    pub fn is_banned(&self, peer: &str) -> bool {
+        self.banned.contains(ip_of(peer))
+    }
+
+    ///Give every contributor to a failed piece a strike.
+    ///Returns the ips that went over the limit with this failure
+    pub fn record_hash_failure(&mut self, contributors: &[String]) -> Vec<String> {
+        let mut newly_banned = Vec::new();
+        for contributor in contributors {
+            let ip = ip_of(contributor);
+            let strikes = self.strikes.entry(ip.to_owned()).or_insert(0);
+            *strikes += 1;
+            if *strikes >= self.max_strikes && self.banned.insert(ip.to_owned()) {
+                warn!("Banning {ip} after {strikes} hash failures");
+                newly_banned.push(ip.to_owned());
+            }
+        }
+        newly_banned
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &String> {
+        self.banned.iter()
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_STRIKES)
+    }
+}
+
+fn ip_of(peer: &str) -> &str {
+    peer.rsplit_once(':').map(|(ip, _)| ip).unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hashing::piece_matches;
+    use sha1::{Digest, Sha1};
+
+    fn single_piece_state(data: &[u8]) -> DownloadState {
+        let mut sha1_hash = [0u8; 20];
+        sha1_hash.copy_from_slice(&Sha1::digest(data));
+        DownloadState::new(vec![PieceMetadata {
+            index: 0,
+            length: data.len(),
+            sha1_hash,
+        }])
+    }
+
+    ///What the peer loop does with a whole piece, minus the disk threads
+    fn check_piece(state: &mut DownloadState, index: u32, data: Vec<u8>) -> PieceOutcome {
+        let hash_ok = piece_matches(&data, &state.expected_hash(index).unwrap());
+        state.finish_piece(index, data, hash_ok).unwrap()
+    }
+
+    #[test]
+    fn test_good_piece_is_verified() {
+        let data = vec![7u8; BLOCK_SIZE as usize + 10];
+        let mut state = single_piece_state(&data);
+        let all = bitvec![u8, Msb0; 1; 1];
+        assert_eq!(Some(0), state.pick_piece("1.1.1.1:6881", &all));
+        assert_eq!(
+            vec![(0, BLOCK_SIZE), (BLOCK_SIZE, 10)],
+            state.missing_blocks(0)
+        );
+        let whole = state
+            .store_block(0, 0, &data[..BLOCK_SIZE as usize], "1.1.1.1:6881")
+            .unwrap();
+        assert_eq!(None, whole);
+        let whole = state
+            .store_block(0, BLOCK_SIZE, &data[BLOCK_SIZE as usize..], "1.1.1.1:6881")
+            .unwrap();
+        assert_eq!(Some(data.clone()), whole);
+        assert!(matches!(
+            check_piece(&mut state, 0, whole.unwrap()),
+            PieceOutcome::Verified { index: 0, .. }
+        ));
+        assert!(state.is_complete());
+        assert_eq!(0, state.wasted_bytes);
+    }
+
+    #[test]
+    fn test_piece_being_hashed_is_left_alone() {
+        let data = vec![7u8; 10];
+        let mut state = single_piece_state(&data);
+        let all = bitvec![u8, Msb0; 1; 1];
+        state.pick_piece("1.1.1.1:6881", &all);
+        let whole = state.store_block(0, 0, &data, "1.1.1.1:6881").unwrap();
+        assert_eq!(Some(data.clone()), whole);
+        //the peer hashing it goes away, nobody else should start it over meanwhile
+        state.release_peer("1.1.1.1:6881");
+        assert_eq!(None, state.pick_piece("2.2.2.2:51413", &all));
+        assert_eq!(
+            None,
+            state.store_block(0, 0, &data, "2.2.2.2:51413").unwrap()
+        );
+        let outcome = state.finish_piece(0, data, true).unwrap();
+        assert!(matches!(outcome, PieceOutcome::Verified { index: 0, .. }));
+        assert!(state.is_complete());
+    }
+
+    #[test]
+    fn test_bad_piece_is_attributed_to_every_sender() {
+        let data = vec![7u8; BLOCK_SIZE as usize * 2];
+        let mut state = single_piece_state(&data);
+        let all = bitvec![u8, Msb0; 1; 1];
+        state.pick_piece("1.1.1.1:6881", &all);
+        state
+            .store_block(0, 0, &data[..BLOCK_SIZE as usize], "1.1.1.1:6881")
+            .unwrap();
+        //first peer goes away, second one finishes the piece with garbage
+        state.release_peer("1.1.1.1:6881");
+        assert_eq!(Some(0), state.pick_piece("2.2.2.2:51413", &all));
+        let garbage = vec![0u8; BLOCK_SIZE as usize];
+        let whole = state
+            .store_block(0, BLOCK_SIZE, &garbage, "2.2.2.2:51413")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            PieceOutcome::HashFailed {
+                index: 0,
+                contributors: vec!["1.1.1.1:6881".to_owned(), "2.2.2.2:51413".to_owned()],
+                wasted: data.len() as u64,
+            },
+            check_piece(&mut state, 0, whole)
+        );
+        assert_eq!(data.len() as u64, state.wasted_bytes);
+        assert!(!state.is_complete());
+    }
+
+    #[test]
+    fn test_late_block_is_dropped() {
+        let data = vec![7u8; BLOCK_SIZE as usize * 2];
+        let mut state = single_piece_state(&data);
+        let all = bitvec![u8, Msb0; 1; 1];
+        state.pick_piece("1.1.1.1:6881", &all);
+        state
+            .store_block(0, 0, &data[..BLOCK_SIZE as usize], "1.1.1.1:6881")
+            .unwrap();
+        let garbage = vec![0u8; BLOCK_SIZE as usize];
+        let whole = state
+            .store_block(0, BLOCK_SIZE, &garbage, "2.2.2.2:51413")
+            .unwrap()
+            .unwrap();
+        check_piece(&mut state, 0, whole);
+        //the first peer's second request crossed with the failed check
+        assert_eq!(
+            None,
+            state
+                .store_block(0, BLOCK_SIZE, &data[BLOCK_SIZE as usize..], "1.1.1.1:6881")
+                .unwrap()
+        );
+        assert!(state.store_block(1, 0, &data, "1.1.1.1:6881").is_err());
+    }
+
+    #[test]
+    fn test_picker_follows_priorities() {
+        let pieces = (0..4)
+            .map(|index| PieceMetadata {
+                index,
+                length: 1,
+                sha1_hash: [0u8; 20],
+            })
+            .collect();
+        let mut state = DownloadState::new(pieces);
+        state.set_priorities(vec![
+            FilePriority::Skip,
+            FilePriority::Normal,
+            FilePriority::High,
+            FilePriority::Skip,
+        ]);
+        let all = bitvec![u8, Msb0; 1; 4];
+        assert_eq!(Some(2), state.pick_piece("1.1.1.1:6881", &all));
+        assert_eq!(Some(1), state.pick_piece("2.2.2.2:6881", &all));
+        //the skipped ones are never picked
+        assert_eq!(None, state.pick_piece("3.3.3.3:6881", &all));
+        state.have.set(1, true);
+        state.have.set(2, true);
+        assert!(state.is_complete());
+    }
+
+    fn tiny_pieces(count: u32) -> DownloadState {
+        let pieces = (0..count)
+            .map(|index| PieceMetadata {
+                index,
+                length: 1,
+                sha1_hash: [0u8; 20],
+            })
+            .collect();
+        DownloadState::new(pieces)
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut state = tiny_pieces(3);
+        state.add_availability(bits![u8, Msb0; 1, 1, 1]);
+        state.add_availability(bits![u8, Msb0; 1, 0, 1]);
+        state.add_have(0);
+        let all = bitvec![u8, Msb0; 1; 3];
+        //piece 1 has one peer, piece 2 two and piece 0 three
+        assert_eq!(Some(1), state.pick_piece("1.1.1.1:6881", &all));
+        assert_eq!(Some(2), state.pick_piece("2.2.2.2:6881", &all));
+        state.remove_availability(bits![u8, Msb0; 1, 1, 1]);
+        assert_eq!(2, state.availability[0]);
+    }
+
+    #[test]
+    fn test_sequential_within_readahead() {
+        let mut state = tiny_pieces(6);
+        state.set_sequential(bitvec![u8, Msb0; 1, 1, 1, 1, 0, 0], 2);
+        //piece 5 is the rarest, but the window comes first
+        state.add_availability(bits![u8, Msb0; 1, 1, 1, 1, 1, 0]);
+        let all = bitvec![u8, Msb0; 1; 6];
+        assert_eq!(Some(0), state.pick_piece("1.1.1.1:6881", &all));
+        assert_eq!(Some(1), state.pick_piece("2.2.2.2:6881", &all));
+        //window is full, the rest goes rarest first
+        assert_eq!(Some(5), state.pick_piece("3.3.3.3:6881", &all));
+        state.have.set(0, true);
+        state.in_progress.remove(&0);
+        //the window slides along, piece 2 is in it now
+        assert_eq!(Some(2), state.pick_piece("4.4.4.4:6881", &all));
+        //a peer without the window's pieces still gets something useful
+        let no_window = bitvec![u8, Msb0; 0, 0, 0, 1, 1, 0];
+        assert_eq!(Some(3), state.pick_piece("5.5.5.5:6881", &no_window));
+    }
+
+    #[test]
+    fn test_urgent_pieces_first() {
+        let mut state = tiny_pieces(4);
+        state.set_priorities(vec![FilePriority::High; 4]);
+        state.make_urgent(2..4);
+        let all = bitvec![u8, Msb0; 1; 4];
+        assert_eq!(Some(2), state.pick_piece("1.1.1.1:6881", &all));
+        assert_eq!(Some(3), state.pick_piece("2.2.2.2:6881", &all));
+        assert_eq!(Some(0), state.pick_piece("3.3.3.3:6881", &all));
+    }
+
+    #[test]
+    fn test_ban_after_repeated_failures() {
+        let mut bans = BanList::new(2);
+        let offender = vec!["6.6.6.6:6881".to_owned()];
+        assert!(bans.record_hash_failure(&offender).is_empty());
+        assert!(!bans.is_banned("6.6.6.6:6881"));
+        assert_eq!(vec!["6.6.6.6"], bans.record_hash_failure(&offender));
+        //other port, same ip, still banned
+        assert!(bans.is_banned("6.6.6.6:1337"));
+        //already banned, not reported a second time
+        assert!(bans.record_hash_failure(&offender).is_empty());
+    }
+
+    #[test]
+    fn test_pieces_verified_during_a_recheck_are_kept() {
+        let data = vec![7u8; 10];
+        let mut sha1_hash = [0u8; 20];
+        sha1_hash.copy_from_slice(&Sha1::digest(&data));
+        let piece = |index| PieceMetadata {
+            index,
+            length: data.len(),
+            sha1_hash,
+        };
+        let mut state = DownloadState::new(vec![piece(0), piece(1)]);
+        state.set_have(&[0]);
+        state.start_check();
+        let all = bitvec![u8, Msb0; 1; 2];
+        assert_eq!(Some(1), state.pick_piece("1.1.1.1:6881", &all));
+        let whole = state.store_block(1, 0, &data, "1.1.1.1:6881").unwrap();
+        state.finish_piece(1, whole.unwrap(), true).unwrap();
+        //the recheck had looked at the disk before piece 1 got there, and piece 0 was gone
+        state.finish_check(Some(&[]));
+        assert_eq!(bitvec![u8, Msb0; 0, 1], state.have);
+        //a cancelled one changes nothing
+        state.start_check();
+        state.finish_check(None);
+        assert_eq!(bitvec![u8, Msb0; 0, 1], state.have);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::model::InfoHash;
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+
+///How many torrents get to be active at once. 0 for no limit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueLimits {
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    ///A download that moved nothing for this many minutes stops counting as active,
+    ///so the next one in the queue gets a go. 0 to never give up on a download
+    pub stall_minutes: u64,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            stall_minutes: 5,
+        }
+    }
+}
+
+///Where to move a torrent in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+impl QueueMove {
+    ///The new index of the torrent at `index`, in a queue of `len`
+    pub fn apply(&self, index: usize, len: usize) -> usize {
+        match self {
+            QueueMove::Up => index.saturating_sub(1),
+            QueueMove::Down => (index + 1).min(len.saturating_sub(1)),
+            QueueMove::Top => 0,
+            QueueMove::Bottom => len.saturating_sub(1),
+        }
+    }
+}
+
+///A torrent as far as the queue is concerned
+#[derive(Debug, Clone, Default)]
+pub struct QueueEntry {
+    pub info_hash: InfoHash,
+    pub complete: bool,
+    ///Started, rather than waiting in the queue
+    pub active: bool,
+    ///Active, but not getting anywhere
+    pub stalled: bool,
+}
+
+///What the queue wants done
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct QueueChanges {
+    pub start: Vec<InfoHash>,
+    ///Seeds pushed back into the queue, to make room
+    pub requeue: Vec<InfoHash>,
+}
+
+impl QueueLimits {
+    ///Work out what to start and what to push back, given the entries in queue order
+    pub fn plan(&self, entries: &[QueueEntry]) -> QueueChanges {
+        let counts = |complete: bool| {
+            entries
+                .iter()
+                .filter(|e| e.active && !e.stalled && e.complete == complete)
+                .count()
+        };
+        let mut downloads = counts(false);
+        let mut seeds = counts(true);
+        let has_room = |count: usize, max: usize| max == 0 || count < max;
+        let mut changes = QueueChanges::default();
+
+        //finished downloads turn into seeds, which may be one too many
+        let mut over = if self.max_active_seeds == 0 {
+            0
+        } else {
+            seeds.saturating_sub(self.max_active_seeds)
+        };
+        for entry in entries.iter().rev() {
+            if over == 0 {
+                break;
+            }
+            if entry.active && !entry.stalled && entry.complete {
+                changes.requeue.push(entry.info_hash);
+                seeds -= 1;
+                over -= 1;
+            }
+        }
+
+        for entry in entries.iter().filter(|e| !e.active) {
+            if entry.complete && has_room(seeds, self.max_active_seeds) {
+                seeds += 1;
+                changes.start.push(entry.info_hash);
+            } else if !entry.complete && has_room(downloads, self.max_active_downloads) {
+                downloads += 1;
+                changes.start.push(entry.info_hash);
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    ///Stands in for a torrent's info hash, the first letter of its name will do
+    fn hash(name: &str) -> InfoHash {
+        [name.as_bytes()[0]; 20]
+    }
+
+    fn entry(name: &str, complete: bool, active: bool) -> QueueEntry {
+        QueueEntry {
+            info_hash: hash(name),
+            complete,
+            active,
+            stalled: false,
+        }
+    }
+
+    fn limits(downloads: usize, seeds: usize) -> QueueLimits {
+        QueueLimits {
+            max_active_downloads: downloads,
+            max_active_seeds: seeds,
+            ..QueueLimits::default()
+        }
+    }
+
+    #[test]
+    fn test_starts_in_queue_order() {
+        let entries = vec![
+            entry("a", false, false),
+            entry("b", false, false),
+            entry("c", true, false),
+            entry("d", false, false),
+        ];
+        let changes = limits(2, 1).plan(&entries);
+        assert_eq!(vec![hash("a"), hash("b"), hash("c")], changes.start);
+        assert!(changes.requeue.is_empty());
+    }
+
+    #[test]
+    fn test_stalled_download_makes_room() {
+        let mut stalled = entry("a", false, true);
+        stalled.stalled = true;
+        let entries = vec![stalled, entry("b", false, true), entry("c", false, false)];
+        assert_eq!(vec![hash("c")], limits(2, 1).plan(&entries).start);
+        assert!(limits(1, 1).plan(&entries).start.is_empty());
+    }
+
+    #[test]
+    fn test_extra_seeds_go_back_in_the_queue() {
+        let entries = vec![
+            entry("a", true, true),
+            entry("b", true, true),
+            entry("c", true, true),
+        ];
+        let changes = limits(1, 2).plan(&entries);
+        assert_eq!(vec![hash("c")], changes.requeue);
+        assert!(changes.start.is_empty());
+    }
+
+    #[test]
+    fn test_zero_is_unlimited() {
+        let entries: Vec<QueueEntry> = (0..10)
+            .map(|i| entry(&i.to_string(), false, false))
+            .collect();
+        assert_eq!(10, limits(0, 0).plan(&entries).start.len());
+    }
+
+    #[test]
+    fn test_queue_moves() {
+        assert_eq!(0, QueueMove::Up.apply(0, 3));
+        assert_eq!(2, QueueMove::Down.apply(2, 3));
+        assert_eq!(1, QueueMove::Down.apply(0, 3));
+        assert_eq!(2, QueueMove::Bottom.apply(0, 3));
+        assert_eq!(0, QueueMove::Top.apply(2, 3));
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+///Classic token bucket. Tokens are bytes, they drip in at `rate` bytes a second,
+///up to one second's worth. Taking more than there is puts the bucket in debt,
+///and the caller waits until the debt is paid off
+#[derive(Debug)]
+pub struct TokenBucket {
+    ///Bytes per second, None for no limit at all
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+
+    ///Take the bytes, returning how long to wait before actually using them
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let Some(rate) = self.rate.filter(|r| *r > 0) else {
+            return Duration::ZERO;
+        };
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+///A token bucket that can be shared between tasks and changed while they use it
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(TokenBucket::new(rate)),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().map(|b| b.rate()).unwrap_or(None)
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.set_rate(rate);
+        }
+    }
+
+    ///Wait until we are allowed to move this many bytes
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self
+            .bucket
+            .lock()
+            .map(|mut b| b.take(bytes, Instant::now()))
+            .unwrap_or(Duration::ZERO);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+///Upload and download limit, for either one torrent or everything
+#[derive(Debug)]
+pub struct TransferLimiter {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl TransferLimiter {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+}
+
+///Socket I/O for a peer has to get past both its torrent's limit and the global one
+pub struct Throttle<'a> {
+    pub global: &'a TransferLimiter,
+    pub torrent: &'a TransferLimiter,
+}
+
+impl Throttle<'_> {
+    pub async fn download(&self, bytes: usize) {
+        self.torrent.download.acquire(bytes).await;
+        self.global.download.acquire(bytes).await;
+    }
+
+    pub async fn upload(&self, bytes: usize) {
+        self.torrent.upload.acquire(bytes).await;
+        self.global.upload.acquire(bytes).await;
+    }
+}
+
+///Limits are given to us in KiB/s, with 0 meaning no limit
+pub fn kib_to_rate(kib_per_second: Option<u64>) -> Option<u64> {
+    kib_per_second.filter(|k| *k > 0).map(|k| k * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let mut bucket = TokenBucket::new(None);
+        assert_eq!(Duration::ZERO, bucket.take(1 << 30, Instant::now()));
+    }
+
+    #[test]
+    fn test_debt_is_paid_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000));
+        //a full second's worth is there from the start
+        assert_eq!(Duration::ZERO, bucket.take(1000, start));
+        //the next 500 bytes cost half a second
+        assert_eq!(Duration::from_millis(500), bucket.take(500, start));
+        //after a second the debt is paid and there is 500 to spare
+        let later = start + Duration::from_secs(1);
+        assert_eq!(Duration::ZERO, bucket.take(500, later));
+    }
+
+    #[test]
+    fn test_rate_can_change_while_in_use() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000));
+        bucket.take(1000, start);
+        bucket.set_rate(None);
+        assert_eq!(Duration::ZERO, bucket.take(1 << 20, start));
+        assert_eq!(None, bucket.rate());
+    }
+
+    #[test]
+    fn test_kib_to_rate() {
+        assert_eq!(None, kib_to_rate(None));
+        assert_eq!(None, kib_to_rate(Some(0)));
+        assert_eq!(Some(2048), kib_to_rate(Some(2)));
+    }
+}
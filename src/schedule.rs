@@ -0,0 +1,118 @@
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Weekday};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Display;
+
+///Which set of global limits is in force
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedProfile {
+    Normal,
+    Alternative,
+}
+
+impl Display for SpeedProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpeedProfile::Normal => write!(f, "normal speed"),
+            SpeedProfile::Alternative => write!(f, "alternative speed"),
+        }
+    }
+}
+
+///Alternative limits, and when they apply, e.g. weekdays from 09:00 to 18:00
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AltSpeedSchedule {
+    ///KiB/s, 0 or missing for unlimited
+    pub download: Option<u64>,
+    ///KiB/s, 0 or missing for unlimited
+    pub upload: Option<u64>,
+    ///Days the window starts on, e.g. ["Mon", "Tue"]. Empty means every day
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    ///Start of the window, local time
+    pub start: NaiveTime,
+    ///End of the window, local time. Before `start` means the window runs past midnight
+    pub end: NaiveTime,
+}
+
+impl AltSpeedSchedule {
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            starts_on(now.weekday()) && time >= self.start && time < self.end
+        } else if time >= self.start {
+            //before midnight, on the day the window started
+            starts_on(now.weekday())
+        } else if time < self.end {
+            //after midnight, the window started the day before
+            starts_on((now - Duration::days(1)).weekday())
+        } else {
+            false
+        }
+    }
+
+    pub fn profile_at(&self, now: NaiveDateTime) -> SpeedProfile {
+        if self.is_active(now) {
+            SpeedProfile::Alternative
+        } else {
+            SpeedProfile::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        //the 7th of October 2024 was a Monday
+        NaiveDate::from_ymd_opt(2024, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn office_hours() -> AltSpeedSchedule {
+        AltSpeedSchedule {
+            download: Some(100),
+            upload: Some(20),
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_office_hours() {
+        let schedule = office_hours();
+        assert!(schedule.is_active(at(7, 9, 0)));
+        assert!(schedule.is_active(at(11, 17, 59)));
+        assert!(!schedule.is_active(at(7, 8, 59)));
+        assert!(!schedule.is_active(at(7, 18, 0)));
+        //saturday
+        assert_eq!(SpeedProfile::Normal, schedule.profile_at(at(12, 12, 0)));
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let schedule = AltSpeedSchedule {
+            days: vec![Weekday::Fri],
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            ..office_hours()
+        };
+        //friday night, and the small hours of saturday
+        assert!(schedule.is_active(at(11, 23, 0)));
+        assert!(schedule.is_active(at(12, 5, 0)));
+        //but not the small hours of friday
+        assert!(!schedule.is_active(at(11, 5, 0)));
+        assert!(!schedule.is_active(at(12, 12, 0)));
+    }
+}
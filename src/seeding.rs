@@ -0,0 +1,187 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::Duration;
+
+///What to do with a torrent once it has seeded enough
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedingAction {
+    ///Tell the tracker we are gone and drop it from the session
+    #[default]
+    Stop,
+    ///Tell the tracker we are gone, but keep it in the session
+    Pause,
+}
+
+impl SeedingAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SeedingAction::Stop => "stop",
+            SeedingAction::Pause => "pause",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<SeedingAction> {
+        match action {
+            "stop" => Some(SeedingAction::Stop),
+            "pause" => Some(SeedingAction::Pause),
+            _ => None,
+        }
+    }
+}
+
+///When we have seeded enough. Every goal is optional, the first one reached wins
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeedingPolicy {
+    ///Uploaded divided by downloaded
+    pub ratio: Option<f64>,
+    ///Minutes spent seeding
+    pub seeding_minutes: Option<u64>,
+    ///Minutes without uploading or downloading anything
+    pub idle_minutes: Option<u64>,
+    pub action: SeedingAction,
+}
+
+///Where a torrent is at, as far as seeding goes
+#[derive(Debug, Clone, Default)]
+pub struct SeedingProgress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub size: u64,
+    pub seeding_time: Duration,
+    pub idle_time: Duration,
+}
+
+impl SeedingProgress {
+    ///A torrent we never downloaded (we had it all from the start) counts its size as downloaded
+    pub fn ratio(&self) -> f64 {
+        let base = self.downloaded.max(self.size);
+        if base == 0 {
+            return 0.0;
+        }
+        self.uploaded as f64 / base as f64
+    }
+}
+
+///Which of the goals was reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedingGoal {
+    Ratio(f64),
+    SeedingTime(Duration),
+    IdleTime(Duration),
+}
+
+impl Display for SeedingGoal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedingGoal::Ratio(ratio) => write!(f, "share ratio {ratio:.2}"),
+            SeedingGoal::SeedingTime(time) => write!(f, "seeded for {}m", time.as_secs() / 60),
+            SeedingGoal::IdleTime(time) => write!(f, "idle for {}m", time.as_secs() / 60),
+        }
+    }
+}
+
+impl SeedingPolicy {
+    pub fn goal_reached(&self, progress: &SeedingProgress) -> Option<SeedingGoal> {
+        let ratio = progress.ratio();
+        if self.ratio.is_some_and(|goal| ratio >= goal) {
+            return Some(SeedingGoal::Ratio(ratio));
+        }
+        if self
+            .seeding_minutes
+            .is_some_and(|goal| progress.seeding_time >= Duration::from_secs(goal * 60))
+        {
+            return Some(SeedingGoal::SeedingTime(progress.seeding_time));
+        }
+        if self
+            .idle_minutes
+            .is_some_and(|goal| progress.idle_time >= Duration::from_secs(goal * 60))
+        {
+            return Some(SeedingGoal::IdleTime(progress.idle_time));
+        }
+        None
+    }
+
+    ///Per torrent settings win, whatever they leave out comes from the default
+    pub fn or_default(
+        ratio: Option<f64>,
+        seeding_minutes: Option<u64>,
+        idle_minutes: Option<u64>,
+        action: Option<SeedingAction>,
+        default: &SeedingPolicy,
+    ) -> SeedingPolicy {
+        SeedingPolicy {
+            ratio: ratio.or(default.ratio),
+            seeding_minutes: seeding_minutes.or(default.seeding_minutes),
+            idle_minutes: idle_minutes.or(default.idle_minutes),
+            action: action.unwrap_or(default.action),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_no_goals_never_reached() {
+        let progress = SeedingProgress {
+            uploaded: 1 << 40,
+            ..SeedingProgress::default()
+        };
+        assert_eq!(None, SeedingPolicy::default().goal_reached(&progress));
+    }
+
+    #[test]
+    fn test_ratio_goal() {
+        let policy = SeedingPolicy {
+            ratio: Some(2.0),
+            ..SeedingPolicy::default()
+        };
+        let mut progress = SeedingProgress {
+            uploaded: 150,
+            downloaded: 100,
+            size: 100,
+            ..SeedingProgress::default()
+        };
+        assert_eq!(None, policy.goal_reached(&progress));
+        progress.uploaded = 200;
+        assert_eq!(
+            Some(SeedingGoal::Ratio(2.0)),
+            policy.goal_reached(&progress)
+        );
+    }
+
+    #[test]
+    fn test_time_goals() {
+        let policy = SeedingPolicy {
+            seeding_minutes: Some(60),
+            idle_minutes: Some(10),
+            action: SeedingAction::Pause,
+            ..SeedingPolicy::default()
+        };
+        let progress = SeedingProgress {
+            idle_time: Duration::from_secs(600),
+            ..SeedingProgress::default()
+        };
+        assert_eq!(
+            Some(SeedingGoal::IdleTime(Duration::from_secs(600))),
+            policy.goal_reached(&progress)
+        );
+    }
+
+    #[test]
+    fn test_per_torrent_overrides_default() {
+        let default = SeedingPolicy {
+            ratio: Some(1.0),
+            idle_minutes: Some(30),
+            ..SeedingPolicy::default()
+        };
+        let policy =
+            SeedingPolicy::or_default(Some(3.0), None, None, Some(SeedingAction::Pause), &default);
+        assert_eq!(Some(3.0), policy.ratio);
+        assert_eq!(Some(30), policy.idle_minutes);
+        assert_eq!(SeedingAction::Pause, policy.action);
+    }
+}
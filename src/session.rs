@@ -0,0 +1,1078 @@
+use chrono::{Local, Utc};
+use color_eyre::eyre::{eyre, Result};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::{Id, JoinError, JoinSet};
+use tokio::time::interval;
+
+use crate::api::{announce_regular, announce_started, announce_stopped};
+use crate::config::Config;
+use crate::connection_manager::{ActiveTorrent, ConnectionManager};
+use crate::database::{
+    clear_pending_move, clear_requested_state, downsample_history, list_known_peers, list_queue,
+    list_tracker_status, prune_known_peers, request_recheck, save_have, save_location,
+    save_peer_connected, save_peer_failed, save_sample, save_state, save_torrent_progress,
+    select_global_rate_limits, select_pending_move, select_recheck_requested,
+    select_requested_state, select_seeding_policy, select_state, select_torrent_rate_limits,
+    DbConnection,
+};
+use crate::hashing::HashProgress;
+use crate::history::TransferSample;
+use crate::model::InfoHash;
+use crate::peer_cache::{dial_order, PeerOutcome};
+use crate::queue::QueueEntry;
+use crate::rate_limit::kib_to_rate;
+use crate::schedule::SpeedProfile;
+use crate::seeding::{SeedingAction, SeedingProgress};
+use crate::state::TorrentState;
+use crate::storage::Location;
+
+///How often the session looks up from the peers to see what else needs doing
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+///A move of a torrent's files, running on the blocking threads
+struct PendingMove {
+    to: Location,
+    ///Asked for from the command line, rather than a complete torrent leaving the incomplete dir
+    requested: bool,
+    ///Started again once the files are in place
+    was_running: bool,
+}
+
+///The torrents we are working on, and the things that have to happen while the peers are busy
+pub struct Session {
+    pub db: DbConnection,
+    pub torrents: Vec<Arc<ActiveTorrent>>,
+    manager: ConnectionManager,
+    config: Config,
+    ///None until the first tick works it out
+    speed_profile: Option<SpeedProfile>,
+    ///Where each torrent is at, only ever changed through set_state
+    states: HashMap<InfoHash, TorrentState>,
+    ///Every peer task, handing back the info hash of its torrent when done
+    tasks: JoinSet<InfoHash>,
+    ///Torrents being rechecked, they sit out of the queue until it is done
+    checking: HashMap<InfoHash, Arc<HashProgress>>,
+    ///What the rechecked torrents were doing before, to go back to after
+    before_check: HashMap<InfoHash, TorrentState>,
+    rechecks: JoinSet<Result<Vec<u32>>>,
+    ///Which torrent each recheck is for, so one that falls over can still be put right
+    recheck_tasks: HashMap<Id, InfoHash>,
+    ///Torrents whose files are on the move, they sit out of the queue and rechecks meanwhile
+    moving: HashMap<InfoHash, PendingMove>,
+    moves: JoinSet<Result<()>>,
+    ///Which torrent each move is for, so one that falls over can still be put right
+    move_tasks: HashMap<Id, InfoHash>,
+    ///How many of the tasks belong to each torrent
+    peer_tasks: HashMap<InfoHash, usize>,
+    client: reqwest::Client,
+    last_tick: Instant,
+    ///When the history was last sampled
+    last_sample: Option<Instant>,
+    ///The (downloaded, uploaded) totals then, to work the rates out from
+    sampled_totals: HashMap<InfoHash, (u64, u64)>,
+}
+
+impl Session {
+    pub fn new(
+        db: DbConnection,
+        torrents: Vec<Arc<ActiveTorrent>>,
+        manager: ConnectionManager,
+        config: Config,
+    ) -> Result<Self> {
+        //each torrent picks up where it was when the last session ended
+        let mut states = HashMap::new();
+        for torrent in &torrents {
+            let (state, _) = select_state(&torrent.info_hash, &db)?;
+            let state = state.on_restart();
+            if state != TorrentState::Queued {
+                info!("{} is {state}, resume it to get it going", torrent.name);
+            }
+            save_state(&torrent.info_hash, state, None, &db)?;
+            states.insert(torrent.info_hash, state);
+        }
+        Ok(Self {
+            db,
+            torrents,
+            manager,
+            config,
+            speed_profile: None,
+            states,
+            tasks: JoinSet::new(),
+            checking: HashMap::new(),
+            before_check: HashMap::new(),
+            rechecks: JoinSet::new(),
+            recheck_tasks: HashMap::new(),
+            moving: HashMap::new(),
+            moves: JoinSet::new(),
+            move_tasks: HashMap::new(),
+            peer_tasks: HashMap::new(),
+            client: reqwest::Client::new(),
+            last_tick: Instant::now(),
+            last_sample: None,
+            sampled_totals: HashMap::new(),
+        })
+    }
+
+    pub fn state(&self, info_hash: &InfoHash) -> TorrentState {
+        self.states.get(info_hash).copied().unwrap_or_default()
+    }
+
+    ///The one place a torrent changes state. Anything the transitions don't allow is
+    ///refused, so a paused torrent can't sneak back into the swarm
+    fn set_state(
+        &mut self,
+        torrent: &ActiveTorrent,
+        state: TorrentState,
+        error: Option<&str>,
+    ) -> Result<bool> {
+        let (name, current) = (&torrent.name, self.state(&torrent.info_hash));
+        if !current.can_become(state) {
+            warn!("{name} can't go from {current} to {state}");
+            return Ok(false);
+        }
+        if current != state {
+            debug!("{name}: {current} -> {state}");
+        }
+        self.states.insert(torrent.info_hash, state);
+        save_state(&torrent.info_hash, state, error, &self.db)?;
+        Ok(true)
+    }
+
+    ///Start torrents as the queue allows, ticking along for as long as any torrent is queued
+    ///or running, peers or not. Pausing or stopping every torrent ends the session
+    pub async fn run(&mut self) -> Result<()> {
+        let mut ticker = interval(TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(joined) = self.tasks.join_next() => self.peer_task_done(joined),
+                Some(joined) = self.rechecks.join_next_with_id() => self.recheck_done(joined).await?,
+                Some(joined) = self.moves.join_next_with_id() => self.move_done(joined).await?,
+                _ = ticker.tick() => self.tick().await?,
+            }
+            if self.tasks.is_empty() {
+                //a torrent without peers is stalled, the queue may have something better
+                self.update_queue().await?;
+            }
+            if self.is_done() {
+                self.flush_disks().await;
+                self.save_written_pieces()?;
+                self.move_finished();
+                while let Some(joined) = self.moves.join_next_with_id().await {
+                    self.move_done(joined).await?;
+                }
+                self.save_known_peers()?;
+                return Ok(());
+            }
+        }
+    }
+
+    ///Every torrent is put aside, and done with its peers, rechecks and moves
+    fn is_done(&self) -> bool {
+        self.tasks.is_empty()
+            && self.rechecks.is_empty()
+            && self.moves.is_empty()
+            && self.torrents.iter().all(|t| {
+                matches!(
+                    self.state(&t.info_hash),
+                    TorrentState::Paused | TorrentState::Errored | TorrentState::Stopped
+                )
+            })
+    }
+
+    fn peer_task_done(&mut self, joined: Result<InfoHash, tokio::task::JoinError>) {
+        match joined {
+            Ok(info_hash) => {
+                if let Some(count) = self.peer_tasks.get_mut(&info_hash) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+            Err(e) => warn!("Peer task fell over: {e}"),
+        }
+    }
+
+    ///Active, but nobody to talk to, or nothing coming in for a while
+    fn is_stalled(&self, torrent: &ActiveTorrent) -> bool {
+        let stall_minutes = self.config.queue.stall_minutes;
+        self.peer_tasks
+            .get(&torrent.info_hash)
+            .copied()
+            .unwrap_or(0)
+            == 0
+            || (!torrent.is_complete()
+                && stall_minutes > 0
+                && torrent.stats.idle_time() >= Duration::from_secs(stall_minutes * 60))
+    }
+
+    ///Start what the queue has room for, and push extra seeds back into it.
+    ///The order comes from the db, so it can be changed while we run
+    async fn update_queue(&mut self) -> Result<()> {
+        let order = list_queue(&self.db)?;
+        let position = |info_hash: &InfoHash| {
+            order
+                .iter()
+                .position(|(hash, _)| hash == info_hash)
+                .unwrap_or(usize::MAX)
+        };
+        let mut torrents: Vec<Arc<ActiveTorrent>> = self
+            .torrents
+            .iter()
+            .filter(|t| {
+                let state = self.state(&t.info_hash);
+                (state == TorrentState::Queued || state.is_running())
+                    && !self.moving.contains_key(&t.info_hash)
+            })
+            .cloned()
+            .collect();
+        torrents.sort_by_key(|t| position(&t.info_hash));
+        let entries: Vec<QueueEntry> = torrents
+            .iter()
+            .map(|t| QueueEntry {
+                info_hash: t.info_hash,
+                complete: t.is_complete(),
+                active: self.state(&t.info_hash).is_running(),
+                stalled: self.is_stalled(t),
+            })
+            .collect();
+        let changes = self.config.queue.plan(&entries);
+
+        for torrent in &torrents {
+            if changes.requeue.contains(&torrent.info_hash) {
+                info!("Too many seeds, {} goes back in the queue", torrent.name);
+                self.halt(torrent).await;
+                self.set_state(torrent, TorrentState::Queued, None)?;
+            }
+        }
+        for torrent in &torrents {
+            if changes.start.contains(&torrent.info_hash) {
+                self.start(torrent.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Announce the torrent and dial its peers
+    async fn start(&mut self, torrent: Arc<ActiveTorrent>) -> Result<()> {
+        let running = if torrent.is_complete() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        if !self.state(&torrent.info_hash).can_become(running) {
+            return Ok(());
+        }
+        if let Err(e) = torrent
+            .storage
+            .allocate(self.config.storage.allocation, torrent.size)
+        {
+            //errored, so the queue doesn't keep trying it
+            error!("Not starting {}: {e}", torrent.name);
+            self.set_state(&torrent, TorrentState::Errored, Some(&e.to_string()))?;
+            return Ok(());
+        }
+        info!("Starting {}", torrent.name);
+        torrent.stopped.send_replace(false);
+        self.set_state(&torrent, running, None)?;
+        let announced = match announce_started(&self.client, &torrent, &self.db).await {
+            Ok(peers) => peers,
+            Err(e) => {
+                warn!("Could not announce {}: {e}", torrent.name);
+                Vec::new()
+            }
+        };
+        //the ones we got through to before go first, the tracker may well be down
+        let max_known = self.config.peer_cache.max_peers;
+        let known = if max_known > 0 {
+            list_known_peers(&torrent.info_hash, max_known, &self.db)?
+        } else {
+            Vec::new()
+        };
+        let peers = dial_order(&known, announced);
+        *self.peer_tasks.entry(torrent.info_hash).or_default() += peers.len();
+        self.manager.dial(torrent, peers, &mut self.tasks);
+        Ok(())
+    }
+
+    ///Hang up on every peer of the torrent and leave the swarm
+    async fn halt(&self, torrent: &ActiveTorrent) {
+        torrent.stopped.send_replace(true);
+        if let Err(e) = announce_stopped(&self.client, torrent, &self.db).await {
+            warn!("Could not tell the tracker {} stopped: {e}", torrent.name);
+        }
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        let elapsed = self.last_tick.elapsed();
+        self.last_tick = Instant::now();
+        self.apply_speed_schedule();
+        self.reload_rate_limits()?;
+        self.save_progress(elapsed)?;
+        self.record_history()?;
+        self.save_known_peers()?;
+        self.flush_disks().await;
+        self.save_written_pieces()?;
+        self.update_running_states()?;
+        self.apply_state_requests().await?;
+        self.move_requested().await?;
+        self.update_rechecks().await?;
+        self.move_finished();
+        self.enforce_seeding_policies().await?;
+        self.reannounce().await?;
+        self.update_queue().await?;
+        self.status_lines()
+            .iter()
+            .for_each(|line| println!("{line}"));
+        Ok(())
+    }
+
+    ///Announce again the running torrents whose tracker wants to hear from us by now.
+    ///A torrent that ran out of peers dials the ones it gets back
+    async fn reannounce(&mut self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        for torrent in self.torrents.clone() {
+            if !self.state(&torrent.info_hash).is_running() {
+                continue;
+            }
+            let due = list_tracker_status(&torrent.info_hash, &self.db)?
+                .iter()
+                .find(|s| s.url == torrent.announce_url)
+                .and_then(|s| s.next_announce)
+                .is_some_and(|next| next <= now);
+            if !due {
+                continue;
+            }
+            let peers = match announce_regular(&self.client, &torrent, &self.db).await {
+                Ok(peers) => peers,
+                Err(e) => {
+                    warn!("Could not announce {}: {e}", torrent.name);
+                    continue;
+                }
+            };
+            if self
+                .peer_tasks
+                .get(&torrent.info_hash)
+                .copied()
+                .unwrap_or(0)
+                == 0
+                && !peers.is_empty()
+            {
+                *self.peer_tasks.entry(torrent.info_hash).or_default() += peers.len();
+                self.manager.dial(torrent, peers, &mut self.tasks);
+            }
+        }
+        Ok(())
+    }
+
+    ///Moves asked for from the command line. The peers of the torrent are sent away while
+    ///its files move, and what we have is kept, so nothing needs checking again after
+    async fn move_requested(&mut self) -> Result<()> {
+        for torrent in self.torrents.clone() {
+            //the recheck reads the files, a move would fail those reads. It waits its turn
+            if self.checking.contains_key(&torrent.info_hash)
+                || self.moving.contains_key(&torrent.info_hash)
+            {
+                continue;
+            }
+            let Some(dir) = select_pending_move(&torrent.info_hash, &self.db)? else {
+                continue;
+            };
+            let running = self.state(&torrent.info_hash).is_running();
+            if running {
+                self.halt(&torrent).await;
+                self.set_state(&torrent, TorrentState::Queued, None)?;
+            }
+            if let Err(e) = torrent.disk.flush().await {
+                warn!("Could not flush {} before moving it: {e}", torrent.name);
+            }
+            info!("Moving {} to {}", torrent.name, dir.display());
+            self.spawn_move(&torrent, Location::complete(&dir), true, running);
+        }
+        Ok(())
+    }
+
+    ///A copy across filesystems takes a while, the other torrents carry on meanwhile
+    fn spawn_move(
+        &mut self,
+        torrent: &ActiveTorrent,
+        to: Location,
+        requested: bool,
+        was_running: bool,
+    ) {
+        let storage = torrent.storage.clone();
+        let location = to.clone();
+        let handle = self.moves.spawn_blocking(move || storage.move_to(location));
+        self.move_tasks.insert(handle.id(), torrent.info_hash);
+        self.moving.insert(
+            torrent.info_hash,
+            PendingMove {
+                to,
+                requested,
+                was_running,
+            },
+        );
+    }
+
+    ///The db learns where the files are now. If the move failed, or fell over altogether,
+    ///the old location stays saved. A requested move is done with either way
+    async fn move_done(&mut self, joined: Result<(Id, Result<()>), JoinError>) -> Result<()> {
+        let (id, result) = match joined {
+            Ok((id, result)) => (id, result),
+            Err(e) => (e.id(), Err(eyre!("the move fell over, {e}"))),
+        };
+        let Some(info_hash) = self.move_tasks.remove(&id) else {
+            return Ok(());
+        };
+        let Some(pending) = self.moving.remove(&info_hash) else {
+            return Ok(());
+        };
+        let Some(torrent) = self
+            .torrents
+            .iter()
+            .find(|t| t.info_hash == info_hash)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        match result {
+            Ok(()) => save_location(&info_hash, &pending.to, &self.db)?,
+            Err(e) => error!("Could not move {}: {e}", torrent.name),
+        }
+        if pending.requested {
+            clear_pending_move(&info_hash, &self.db)?;
+        }
+        if pending.was_running {
+            self.start(torrent).await?;
+        }
+        Ok(())
+    }
+
+    ///Rechecks asked for from the command line, and the ones called off
+    async fn update_rechecks(&mut self) -> Result<()> {
+        for torrent in self.torrents.clone() {
+            let requested = select_recheck_requested(&torrent.info_hash, &self.db)?;
+            match self.checking.get(&torrent.info_hash) {
+                Some(progress) if !requested => progress.cancel(),
+                //the files are not where the recheck would look, it waits for the move
+                None if requested && !self.moving.contains_key(&torrent.info_hash) => {
+                    self.start_recheck(torrent).await?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    ///Hash the torrent's files in the background, its peers are sent away meanwhile
+    async fn start_recheck(&mut self, torrent: Arc<ActiveTorrent>) -> Result<()> {
+        let before = self.state(&torrent.info_hash);
+        if before.is_running() {
+            self.halt(&torrent).await;
+        }
+        if !self.set_state(&torrent, TorrentState::Checking, None)? {
+            return Ok(());
+        }
+        self.before_check.insert(torrent.info_hash, before);
+        info!("Rechecking {}", torrent.name);
+        if let Ok(mut download) = torrent.download.lock() {
+            download.start_check();
+        }
+        let pieces = torrent.pieces_to_check();
+        let progress = Arc::new(HashProgress::default());
+        self.checking.insert(torrent.info_hash, progress.clone());
+        let info_hash = torrent.info_hash;
+        let handle = self
+            .rechecks
+            .spawn(async move { torrent.disk.recheck(pieces, progress).await });
+        self.recheck_tasks.insert(handle.id(), info_hash);
+        Ok(())
+    }
+
+    ///What the recheck found is what we have now. A cancelled one changes nothing.
+    ///Either way the torrent goes back to what it was doing, running ones through the queue.
+    ///One that fell over leaves the pieces as they were and errors the torrent
+    async fn recheck_done(
+        &mut self,
+        joined: Result<(Id, Result<Vec<u32>>), JoinError>,
+    ) -> Result<()> {
+        let (id, result) = match joined {
+            Ok((id, result)) => (id, Ok(result)),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let Some(info_hash) = self.recheck_tasks.remove(&id) else {
+            return Ok(());
+        };
+        self.checking.remove(&info_hash);
+        request_recheck(&info_hash, false, &self.db)?;
+        let before = self.before_check.remove(&info_hash).unwrap_or_default();
+        let Some(torrent) = self
+            .torrents
+            .iter()
+            .find(|t| t.info_hash == info_hash)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        let name = &torrent.name;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Recheck of {name} fell over: {e}");
+                if let Ok(mut download) = torrent.download.lock() {
+                    download.finish_check(None);
+                }
+                let error = format!("the recheck fell over, {e}");
+                self.set_state(&torrent, TorrentState::Errored, Some(&error))?;
+                return Ok(());
+            }
+        };
+        let found = match &result {
+            Ok(verified) => {
+                info!("{name} has {} pieces on disk", verified.len());
+                Some(verified.as_slice())
+            }
+            Err(e) => {
+                warn!("Recheck of {name} stopped: {e}");
+                None
+            }
+        };
+        if let Ok(mut download) = torrent.download.lock() {
+            download.finish_check(found);
+        }
+        if before.is_running() {
+            self.set_state(&torrent, TorrentState::Queued, None)?;
+            self.start(torrent).await?;
+        } else {
+            self.set_state(&torrent, before, None)?;
+        }
+        Ok(())
+    }
+
+    ///Running torrents that finished start seeding, and seeds that lost pieces,
+    ///say to a recheck, go back to downloading
+    fn update_running_states(&mut self) -> Result<()> {
+        for torrent in self.torrents.clone() {
+            let state = self.state(&torrent.info_hash);
+            if !state.is_running() {
+                continue;
+            }
+            if torrent.is_complete() && state == TorrentState::Downloading {
+                info!("{} is complete", torrent.name);
+                self.set_state(&torrent, TorrentState::Seeding, None)?;
+            } else if !torrent.is_complete() && state == TorrentState::Seeding {
+                self.set_state(&torrent, TorrentState::Downloading, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Pauses, resumes and stops asked for from the command line.
+    ///A resumed torrent goes back in the queue, which starts it when there is room
+    async fn apply_state_requests(&mut self) -> Result<()> {
+        for torrent in self.torrents.clone() {
+            let Some(requested) = select_requested_state(&torrent.info_hash, &self.db)? else {
+                continue;
+            };
+            clear_requested_state(&torrent.info_hash, &self.db)?;
+            if let Some(progress) = self.checking.get(&torrent.info_hash) {
+                //the check finishes off by itself, then the request applies
+                progress.cancel();
+                self.before_check.insert(torrent.info_hash, requested);
+                continue;
+            }
+            let state = self.state(&torrent.info_hash);
+            if state.is_running() {
+                if requested == TorrentState::Queued {
+                    //already going
+                    continue;
+                }
+                self.halt(&torrent).await;
+            }
+            info!("{} is now {requested}, as asked", torrent.name);
+            self.set_state(&torrent, requested, None)?;
+        }
+        Ok(())
+    }
+
+    ///Whatever the write caches held back goes to disk now.
+    ///Anything that fails stays cached, for the next go
+    async fn flush_disks(&self) {
+        for torrent in &self.torrents {
+            if let Err(e) = torrent.disk.flush().await {
+                warn!("Could not write out {}: {e}", torrent.name);
+            }
+        }
+    }
+
+    ///The pieces that made it to disk, for the next session to start from. Anything still in
+    ///the write cache, say because its flush failed, is left out and downloaded again after a crash
+    fn save_written_pieces(&self) -> Result<()> {
+        for torrent in &self.torrents {
+            //if we can't tell what is cached, the last saved bitfield is the safer one
+            let cached = match torrent.disk.cached_pieces() {
+                Ok(cached) => cached,
+                Err(e) => {
+                    warn!("Not saving the pieces of {}: {e}", torrent.name);
+                    continue;
+                }
+            };
+            let Ok(download) = torrent.download.lock() else {
+                continue;
+            };
+            let mut written = download.have.clone();
+            for index in cached {
+                if (index as usize) < written.len() {
+                    written.set(index as usize, false);
+                }
+            }
+            drop(download);
+            save_have(&torrent.info_hash, written.as_raw_slice(), &self.db)?;
+        }
+        Ok(())
+    }
+
+    ///Complete torrents still sitting in the incomplete dir go to the download dir,
+    ///and the db learns where to find them once they are there.
+    ///Anything moved somewhere else by hand is already complete where it is, and stays put
+    fn move_finished(&mut self) {
+        for torrent in self.torrents.clone() {
+            if !torrent.is_complete()
+                || torrent.storage.location().complete
+                || self.moving.contains_key(&torrent.info_hash)
+                || self.checking.contains_key(&torrent.info_hash)
+            {
+                continue;
+            }
+            info!(
+                "Moving {} to {}",
+                torrent.name,
+                torrent.download_dir.display()
+            );
+            self.spawn_move(
+                &torrent,
+                Location::complete(&torrent.download_dir),
+                false,
+                false,
+            );
+        }
+    }
+
+    ///Work out whether the normal or the alternative limits apply now, reload_rate_limits sets them
+    fn apply_speed_schedule(&mut self) {
+        let profile = self
+            .config
+            .alt_speed
+            .as_ref()
+            .map_or(SpeedProfile::Normal, |alt| {
+                alt.profile_at(Local::now().naive_local())
+            });
+        if self.speed_profile != Some(profile) {
+            info!("Switching to {profile}");
+            self.speed_profile = Some(profile);
+        }
+    }
+
+    ///One line for the session, one per torrent
+    pub fn status_lines(&self) -> Vec<String> {
+        let profile = self.speed_profile.unwrap_or(SpeedProfile::Normal);
+        let mut lines = vec![format!("[{profile}]")];
+        for torrent in &self.torrents {
+            let state = self.state(&torrent.info_hash);
+            let state = if let Some(progress) = self.checking.get(&torrent.info_hash) {
+                format!("{state} {:.0}%", progress.percent())
+            } else if self.moving.contains_key(&torrent.info_hash) {
+                format!("{state}, moving")
+            } else if state.is_running() && self.is_stalled(torrent) {
+                format!("{state}, stalled")
+            } else {
+                state.to_string()
+            };
+            if let Ok(download) = torrent.download.lock() {
+                //only once a bad piece has come in, most torrents never see one
+                let wasted = match download.wasted_bytes {
+                    0 => String::new(),
+                    bytes => format!(", {bytes} bytes wasted on bad pieces"),
+                };
+                lines.push(format!(
+                    "  {}: {state}, {}/{} pieces, ratio {:.2}{wasted}",
+                    torrent.name,
+                    download.have.count_ones(),
+                    download.pieces.len(),
+                    seeding_progress(torrent).ratio()
+                ));
+            }
+            let now = Utc::now().timestamp();
+            for tracker in list_tracker_status(&torrent.info_hash, &self.db).unwrap_or_default() {
+                lines.push(format!("    {}", tracker.describe(now)));
+            }
+        }
+        lines
+    }
+
+    ///Every sample_seconds, note down each torrent's totals, peers and rates since the last
+    ///sample, then tidy up the old history. The first sample of a session has no rates
+    fn record_history(&mut self) -> Result<()> {
+        let settings = &self.config.history;
+        if settings.sample_seconds == 0 {
+            return Ok(());
+        }
+        let since_last = self.last_sample.map(|at| at.elapsed());
+        if since_last.is_some_and(|d| d < Duration::from_secs(settings.sample_seconds)) {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp();
+        for torrent in &self.torrents {
+            let (downloaded, uploaded) = (torrent.stats.downloaded(), torrent.stats.uploaded());
+            let rate = |total: u64, before: u64| match since_last {
+                Some(d) if d.as_secs() > 0 => total.saturating_sub(before) / d.as_secs(),
+                _ => 0,
+            };
+            let (down_before, up_before) = self
+                .sampled_totals
+                .get(&torrent.info_hash)
+                .copied()
+                .unwrap_or((downloaded, uploaded));
+            let sample = TransferSample {
+                info_hash: torrent.info_hash,
+                name: torrent.name.clone(),
+                sampled_at: now,
+                downloaded,
+                uploaded,
+                peers: self
+                    .peer_tasks
+                    .get(&torrent.info_hash)
+                    .copied()
+                    .unwrap_or(0) as u32,
+                download_rate: rate(downloaded, down_before),
+                upload_rate: rate(uploaded, up_before),
+                samples: 1,
+            };
+            save_sample(&sample, &self.db)?;
+            self.sampled_totals
+                .insert(torrent.info_hash, (downloaded, uploaded));
+        }
+        self.last_sample = Some(Instant::now());
+        let full_resolution = settings.full_resolution_hours as i64 * 60 * 60;
+        let drop_before =
+            (settings.keep_days > 0).then(|| now - settings.keep_days as i64 * 24 * 60 * 60);
+        downsample_history(
+            now - full_resolution,
+            settings.downsample_minutes as i64 * 60,
+            drop_before,
+            &self.db,
+        )
+    }
+
+    ///Write down how the dials went since the last tick, and forget peers
+    ///that have been gone too long or keep failing us
+    fn save_known_peers(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        for torrent in &self.torrents {
+            for outcome in torrent.peer_log.take() {
+                match outcome {
+                    PeerOutcome::Connected {
+                        peer,
+                        client,
+                        downloaded,
+                        uploaded,
+                    } => save_peer_connected(
+                        &torrent.info_hash,
+                        &peer,
+                        client.as_deref(),
+                        downloaded,
+                        uploaded,
+                        now,
+                        &self.db,
+                    )?,
+                    PeerOutcome::Failed { peer } => {
+                        save_peer_failed(&torrent.info_hash, &peer, &self.db)?
+                    }
+                }
+            }
+        }
+        let settings = &self.config.peer_cache;
+        let pruned = prune_known_peers(
+            now - settings.max_age_days as i64 * 24 * 60 * 60,
+            settings.max_failures,
+            &self.db,
+        )?;
+        if pruned > 0 {
+            debug!("Forgot {pruned} known peers");
+        }
+        Ok(())
+    }
+
+    ///Count up seeding time and write the running totals to the db
+    fn save_progress(&self, elapsed: Duration) -> Result<()> {
+        for torrent in &self.torrents {
+            let stats = &torrent.stats;
+            if torrent.is_complete() && !torrent.is_stopped() {
+                stats.add_seeding_time(elapsed);
+            }
+            save_torrent_progress(
+                &torrent.info_hash,
+                stats.downloaded(),
+                stats.uploaded(),
+                stats.seeding_time(),
+                &self.db,
+            )?;
+        }
+        Ok(())
+    }
+
+    ///Stop or pause every seeding torrent that has reached one of its goals
+    async fn enforce_seeding_policies(&mut self) -> Result<()> {
+        let mut done: Vec<(Arc<ActiveTorrent>, SeedingAction)> = Vec::new();
+        for torrent in &self.torrents {
+            if !torrent.is_complete() || torrent.is_stopped() {
+                continue;
+            }
+            let policy = select_seeding_policy(&torrent.info_hash, &self.config.seeding, &self.db)?;
+            if let Some(goal) = policy.goal_reached(&seeding_progress(torrent)) {
+                info!(
+                    "{} reached its seeding goal, {goal}, going to {}",
+                    torrent.name,
+                    policy.action.as_str()
+                );
+                done.push((torrent.clone(), policy.action));
+            }
+        }
+        for (torrent, action) in done {
+            self.halt(&torrent).await;
+            match action {
+                SeedingAction::Stop => {
+                    self.set_state(&torrent, TorrentState::Stopped, None)?;
+                    self.torrents.retain(|t| t.info_hash != torrent.info_hash);
+                }
+                SeedingAction::Pause => {
+                    self.set_state(&torrent, TorrentState::Paused, None)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///Rate limits live in the db, so they can be changed while we run
+    fn reload_rate_limits(&self) -> Result<()> {
+        //the alternative profile has its own, otherwise the global-limit command beats the config
+        let (download, upload) = match (&self.speed_profile, &self.config.alt_speed) {
+            (Some(SpeedProfile::Alternative), Some(alt)) => (alt.download, alt.upload),
+            _ => {
+                let (download, upload) = select_global_rate_limits(&self.db)?;
+                (
+                    download.or(self.config.limits.download),
+                    upload.or(self.config.limits.upload),
+                )
+            }
+        };
+        let (download, upload) = (kib_to_rate(download), kib_to_rate(upload));
+        let global = self.manager.rate_limits();
+        if global.download.rate() != download || global.upload.rate() != upload {
+            info!("Limits across all torrents: down {download:?} up {upload:?} bytes/s");
+        }
+        global.download.set_rate(download);
+        global.upload.set_rate(upload);
+        for torrent in &self.torrents {
+            match select_torrent_rate_limits(&torrent.info_hash, &self.db) {
+                Ok((download, upload)) => {
+                    if torrent.rate_limits.download.rate() != download
+                        || torrent.rate_limits.upload.rate() != upload
+                    {
+                        debug!(
+                            "New limits for {}: down {:?} up {:?}",
+                            torrent.name, download, upload
+                        );
+                    }
+                    torrent.rate_limits.download.set_rate(download);
+                    torrent.rate_limits.upload.set_rate(upload);
+                }
+                Err(e) => warn!("Could not load the rate limits of {}: {e}", torrent.name),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn seeding_progress(torrent: &ActiveTorrent) -> SeedingProgress {
+    SeedingProgress {
+        uploaded: torrent.stats.uploaded(),
+        downloaded: torrent.stats.downloaded(),
+        size: torrent.size,
+        seeding_time: torrent.stats.seeding_time(),
+        idle_time: torrent.stats.idle_time(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection_manager::test::{test_manager, test_torrent};
+    use crate::database::test::init_test_conn;
+    use crate::database::{request_move, request_state, save_torrent_file, select_location};
+    use crate::disk::{DiskPool, DiskSettings, TorrentDisk};
+    use crate::model::{FilePriority, PieceMetadata};
+    use crate::parser::parse_torrent_file;
+    use crate::piece::DownloadState;
+    use crate::storage::{SavedLocation, Storage, StorageFile};
+    use sha1::{Digest, Sha1};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Mutex, RwLock};
+
+    ///A session with one torrent, two pieces of it on disk in `dir`/from.
+    ///The db knows it as the Fedora torrent, its tracker is nowhere to be found
+    fn test_session(dir: &Path) -> (Session, Arc<ActiveTorrent>) {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("from")).unwrap();
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        fs::write(dir.join("from").join("a"), data).unwrap();
+        let pieces = data
+            .chunks(4)
+            .enumerate()
+            .map(|(index, piece)| {
+                let mut sha1_hash = [0u8; 20];
+                sha1_hash.copy_from_slice(&Sha1::digest(piece));
+                PieceMetadata {
+                    index: index as u32,
+                    length: piece.len(),
+                    sha1_hash,
+                }
+            })
+            .collect();
+        let storage = Arc::new(Storage {
+            files: vec![StorageFile {
+                path: PathBuf::from("a"),
+                length: 8,
+                offset: 0,
+                priority: FilePriority::Normal,
+                sequential: false,
+            }],
+            piece_length: 4,
+            location: RwLock::new(Location::complete(&dir.join("from"))),
+        });
+        let db = init_test_conn();
+        let fedora = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        save_torrent_file(&fedora, &db).unwrap();
+        let torrent = Arc::new(ActiveTorrent {
+            name: fedora.info_name(),
+            info_hash: fedora.torrent_file.info_hash,
+            announce_url: "http://127.0.0.1:1/announce".to_owned(),
+            size: 8,
+            download: Arc::new(Mutex::new(DownloadState::new(pieces))),
+            disk: TorrentDisk::new(
+                storage.clone(),
+                Arc::new(DiskPool::new(&DiskSettings::default()).unwrap()),
+            ),
+            storage,
+            ..test_torrent()
+        });
+        let session =
+            Session::new(db, vec![torrent.clone()], test_manager(), Config::default()).unwrap();
+        (session, torrent)
+    }
+
+    #[tokio::test]
+    async fn test_move_waits_for_the_recheck() {
+        let dir = std::env::temp_dir().join("torrentox_session_move_test");
+        let (mut session, torrent) = test_session(&dir);
+        let info_hash = torrent.info_hash;
+        request_recheck(&info_hash, true, &session.db).unwrap();
+        session.update_rechecks().await.unwrap();
+        request_move(&info_hash, &dir.join("to"), &session.db).unwrap();
+        session.move_requested().await.unwrap();
+        //nothing moved from under the recheck, and the move is still wanted
+        assert!(dir.join("from").join("a").exists());
+        assert!(select_pending_move(&info_hash, &session.db)
+            .unwrap()
+            .is_some());
+
+        let joined = session.rechecks.join_next_with_id().await.unwrap();
+        session.recheck_done(joined).await.unwrap();
+        assert_eq!(2, torrent.download.lock().unwrap().have.count_ones());
+        session.move_requested().await.unwrap();
+        let joined = session.moves.join_next_with_id().await.unwrap();
+        session.move_done(joined).await.unwrap();
+        assert!(dir.join("to").join("a").exists());
+        assert_eq!(None, select_pending_move(&info_hash, &session.db).unwrap());
+        assert_eq!(2, torrent.download.lock().unwrap().have.count_ones());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_runs_without_peers() {
+        let dir = std::env::temp_dir().join("torrentox_session_no_peers_test");
+        let (mut session, torrent) = test_session(&dir);
+        let info_hash = torrent.info_hash;
+        //the tracker is down and nobody is known, still nothing to give up on
+        let running = tokio::time::timeout(Duration::from_millis(500), session.run()).await;
+        assert!(running.is_err());
+        assert_eq!(TorrentState::Downloading, session.state(&info_hash));
+        assert_eq!(0, session.peer_tasks.get(&info_hash).copied().unwrap_or(0));
+
+        request_state(&info_hash, TorrentState::Paused, &session.db).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), session.run())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(TorrentState::Paused, session.state(&info_hash));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_move_that_falls_over() {
+        let dir = std::env::temp_dir().join("torrentox_session_move_panic_test");
+        let (mut session, torrent) = test_session(&dir);
+        let info_hash = torrent.info_hash;
+        save_location(&info_hash, &torrent.storage.location(), &session.db).unwrap();
+        request_move(&info_hash, &dir.join("to"), &session.db).unwrap();
+        //stands in for a move that panics halfway
+        let handle = session.moves.spawn_blocking(|| panic!("out of cheese"));
+        session.move_tasks.insert(handle.id(), info_hash);
+        session.moving.insert(
+            info_hash,
+            PendingMove {
+                to: Location::complete(&dir.join("to")),
+                requested: true,
+                was_running: false,
+            },
+        );
+        let joined = session.moves.join_next_with_id().await.unwrap();
+        assert!(joined.is_err());
+        session.move_done(joined).await.unwrap();
+        assert!(session.moving.is_empty());
+        assert_eq!(
+            Some(SavedLocation::Known(Location::complete(&dir.join("from")))),
+            select_location(&info_hash, &session.db).unwrap()
+        );
+        assert_eq!(None, select_pending_move(&info_hash, &session.db).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recheck_that_falls_over() {
+        let dir = std::env::temp_dir().join("torrentox_session_recheck_panic_test");
+        let (mut session, torrent) = test_session(&dir);
+        let info_hash = torrent.info_hash;
+        let have = torrent.download.lock().unwrap().have.clone();
+        request_recheck(&info_hash, true, &session.db).unwrap();
+        session.states.insert(info_hash, TorrentState::Checking);
+        session.before_check.insert(info_hash, TorrentState::Paused);
+        session
+            .checking
+            .insert(info_hash, Arc::new(HashProgress::default()));
+        torrent.download.lock().unwrap().start_check();
+        //stands in for a recheck that panics halfway
+        let handle = session.rechecks.spawn_blocking(|| panic!("out of cheese"));
+        session.recheck_tasks.insert(handle.id(), info_hash);
+        let joined = session.rechecks.join_next_with_id().await.unwrap();
+        assert!(joined.is_err());
+        session.recheck_done(joined).await.unwrap();
+        assert!(session.checking.is_empty());
+        assert!(session.before_check.is_empty());
+        assert!(!select_recheck_requested(&info_hash, &session.db).unwrap());
+        assert_eq!(TorrentState::Errored, session.state(&info_hash));
+        assert_eq!(have, torrent.download.lock().unwrap().have);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::Display;
+
+///Where a torrent is at. Kept in the db, so the next session carries on from there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TorrentState {
+    ///Waiting for the queue to let it through
+    #[default]
+    Queued,
+    ///Hashing what is on disk
+    Checking,
+    Downloading,
+    Seeding,
+    ///Put aside by hand or by its seeding policy, until resumed
+    Paused,
+    ///Something went wrong starting it, the last error says what
+    Errored,
+    ///Done with, it isn't loaded again unless resumed
+    Stopped,
+}
+
+impl TorrentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TorrentState::Queued => "queued",
+            TorrentState::Checking => "checking",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Paused => "paused",
+            TorrentState::Errored => "errored",
+            TorrentState::Stopped => "stopped",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<TorrentState> {
+        match state {
+            "queued" => Some(TorrentState::Queued),
+            "checking" => Some(TorrentState::Checking),
+            "downloading" => Some(TorrentState::Downloading),
+            "seeding" => Some(TorrentState::Seeding),
+            "paused" => Some(TorrentState::Paused),
+            "errored" => Some(TorrentState::Errored),
+            "stopped" => Some(TorrentState::Stopped),
+            _ => None,
+        }
+    }
+
+    ///Announced and talking to peers
+    pub fn is_running(&self) -> bool {
+        matches!(self, TorrentState::Downloading | TorrentState::Seeding)
+    }
+
+    ///Only the queue starts torrents, so anything put aside goes back through it.
+    ///Staying put is always fine
+    pub fn can_become(&self, next: TorrentState) -> bool {
+        use TorrentState::*;
+        match (self, next) {
+            (from, to) if *from == to => true,
+            (Queued | Checking | Downloading | Seeding, _) => true,
+            (Paused | Errored | Stopped, Queued | Checking | Paused | Stopped) => true,
+            (Paused | Errored | Stopped, Downloading | Seeding | Errored) => false,
+        }
+    }
+
+    ///What a torrent comes back as when torrentox starts again. Whatever was running
+    ///lines up in the queue, a check that was cut short starts over from its request
+    pub fn on_restart(&self) -> TorrentState {
+        match self {
+            TorrentState::Paused | TorrentState::Errored | TorrentState::Stopped => *self,
+            _ => TorrentState::Queued,
+        }
+    }
+}
+
+impl Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use TorrentState::*;
+
+    #[test]
+    fn test_transitions() {
+        assert!(Queued.can_become(Downloading));
+        assert!(Downloading.can_become(Seeding));
+        assert!(Seeding.can_become(Queued));
+        assert!(Queued.can_become(Errored));
+        assert!(Paused.can_become(Queued));
+        assert!(Errored.can_become(Checking));
+        assert!(Errored.can_become(Errored));
+        assert!(!Paused.can_become(Downloading));
+        assert!(!Stopped.can_become(Seeding));
+        assert!(!Paused.can_become(Errored));
+        for state in [
+            Queued,
+            Checking,
+            Downloading,
+            Seeding,
+            Paused,
+            Errored,
+            Stopped,
+        ] {
+            assert_eq!(Some(state), TorrentState::parse(state.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_on_restart() {
+        assert_eq!(Queued, Seeding.on_restart());
+        assert_eq!(Queued, Checking.on_restart());
+        assert_eq!(Paused, Paused.on_restart());
+        assert_eq!(Errored, Errored.on_restart());
+        assert_eq!(Stopped, Stopped.on_restart());
+    }
+}
@@ -0,0 +1,645 @@
+use bitvec::prelude::*;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use fs2::FileExt;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, TryLockError};
+
+use crate::model::{FilePriority, TorrentFile, TorrentFileInfo};
+
+///How files get their space on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocationMode {
+    ///Files grow as pieces come in, holes and all
+    #[default]
+    Sparse,
+    ///Claim every byte up front, so a full disk shows up at the start rather than halfway through
+    Full,
+}
+
+///Added to the names of files that are still downloading, when asked for
+pub const PART_SUFFIX: &str = ".part";
+
+///Where the files of a torrent are right now
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub dir: PathBuf,
+    ///Files are named with PART_SUFFIX on the end
+    pub part_suffix: bool,
+    ///The files are where they stay, nothing moves them once the torrent finishes
+    pub complete: bool,
+}
+
+impl Location {
+    ///Where files go once they are done
+    pub fn complete(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            part_suffix: false,
+            complete: true,
+        }
+    }
+
+    ///Where files download to, to be moved once they are done
+    pub fn incomplete(dir: &Path, part_suffix: bool) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            part_suffix,
+            complete: false,
+        }
+    }
+
+    pub fn path(&self, file: &StorageFile) -> PathBuf {
+        let path = self.dir.join(&file.path);
+        if !self.part_suffix {
+            return path;
+        }
+        let mut name = path.into_os_string();
+        name.push(PART_SUFFIX);
+        PathBuf::from(name)
+    }
+}
+
+///Where the db last saw the files of a torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SavedLocation {
+    Known(Location),
+    ///Saved before we kept track of whether the torrent was complete, only the dir is known
+    Legacy(PathBuf),
+}
+
+///One file of the torrent as it lives on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageFile {
+    ///Relative to the location of the torrent
+    pub path: PathBuf,
+    pub length: u64,
+    ///Where this file starts if all files of the torrent were laid end to end
+    pub offset: u64,
+    ///Skipped files never touch the disk
+    pub priority: FilePriority,
+    pub sequential: bool,
+}
+
+///Maps pieces onto the files of a torrent
+#[derive(Debug, Default)]
+pub struct Storage {
+    pub files: Vec<StorageFile>,
+    pub piece_length: u64,
+    ///Any reading or writing holds on to this, so moving the files waits for it to finish
+    pub location: RwLock<Location>,
+}
+
+impl Storage {
+    ///Single file torrents go straight into the location dir,
+    ///multi file torrents get a directory named after the torrent
+    pub fn new(torrent_file: &TorrentFile, location: Location) -> Self {
+        let name = torrent_file
+            .info
+            .name
+            .clone()
+            .unwrap_or("Unknown to foom".to_string());
+        let files = match &torrent_file.info.file {
+            TorrentFileInfo::SingleFile { length } => vec![StorageFile {
+                path: PathBuf::from(name),
+                length: *length as u64,
+                offset: 0,
+                priority: FilePriority::Normal,
+                sequential: false,
+            }],
+            TorrentFileInfo::MultipleFiles { files } => {
+                let root = PathBuf::from(name);
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        let file = StorageFile {
+                            path: f.path.iter().fold(root.clone(), |p, part| p.join(part)),
+                            length: f.length as u64,
+                            offset,
+                            priority: FilePriority::Normal,
+                            sequential: false,
+                        };
+                        offset += f.length as u64;
+                        file
+                    })
+                    .collect()
+            }
+        };
+        Self {
+            files,
+            piece_length: torrent_file.info.piece_length,
+            location: RwLock::new(location),
+        }
+    }
+
+    pub fn location(&self) -> Location {
+        self.location.read().map(|l| l.clone()).unwrap_or_default()
+    }
+
+    ///Fails rather than waits while the files are being moved, so a peer doesn't hold up
+    ///the whole runtime for the length of a copy
+    fn lock_location(&self) -> Result<RwLockReadGuard<'_, Location>> {
+        self.location.try_read().map_err(|e| match e {
+            TryLockError::WouldBlock => eyre!("The files are being moved"),
+            TryLockError::Poisoned(_) => eyre!("Storage location lock poisoned"),
+        })
+    }
+
+    ///Move whatever is on disk to a new location, all or nothing. Every file is renamed, or
+    ///copied across filesystems under a temporary name, before any original goes. If one of
+    ///them fails, the ones done so far go back and the torrent stays where it was
+    pub fn move_to(&self, to: Location) -> Result<()> {
+        let mut location = self
+            .location
+            .write()
+            .map_err(|_| eyre!("Storage location lock poisoned"))?;
+        if *location == to {
+            return Ok(());
+        }
+        let mut staged = Vec::new();
+        for file in self.wanted_files() {
+            let from = location.path(file);
+            if !from.exists() {
+                continue;
+            }
+            match StagedMove::start(&from, &to.path(file)) {
+                Ok(moved) => staged.push(moved),
+                Err(e) => {
+                    staged.iter().rev().for_each(StagedMove::undo);
+                    return Err(e);
+                }
+            }
+        }
+        for (done, moved) in staged.iter().enumerate() {
+            if let Err(e) = moved.place() {
+                staged[..done].iter().rev().for_each(StagedMove::unplace);
+                staged.iter().rev().for_each(StagedMove::undo);
+                return Err(e);
+            }
+        }
+        //everything is in its new home, the copied originals can go
+        for moved in &staged {
+            moved.finish();
+            //tidy up the torrent's own directories, stopping at the first one still in use
+            for dir in moved.from().ancestors().skip(1) {
+                if dir == location.dir || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        info!(
+            "Moved {} files from {} to {}",
+            self.files.len(),
+            location.dir.display(),
+            to.dir.display()
+        );
+        *location = to;
+        Ok(())
+    }
+
+    ///Priorities come in the same order as the files, any left out stay normal
+    pub fn set_priorities(&mut self, priorities: &[FilePriority]) {
+        self.files
+            .iter_mut()
+            .zip(priorities)
+            .for_each(|(file, priority)| file.priority = *priority);
+    }
+
+    ///Flags come in the same order as the files, any left out stay off
+    pub fn set_sequential(&mut self, sequential: &[bool]) {
+        self.files
+            .iter_mut()
+            .zip(sequential)
+            .for_each(|(file, sequential)| file.sequential = *sequential);
+    }
+
+    ///Pieces that touch a file we want front to back
+    pub fn sequential_pieces(&self, num_pieces: usize) -> BitVec<u8, Msb0> {
+        (0..num_pieces as u64)
+            .map(|index| {
+                self.spans(index * self.piece_length, self.piece_length)
+                    .iter()
+                    .any(|(file, _, _)| file.sequential && file.priority != FilePriority::Skip)
+            })
+            .collect()
+    }
+
+    ///A piece is as wanted as the most wanted file it touches
+    pub fn piece_priorities(&self, num_pieces: usize) -> Vec<FilePriority> {
+        (0..num_pieces as u64)
+            .map(|index| {
+                self.spans(index * self.piece_length, self.piece_length)
+                    .iter()
+                    .map(|(file, _, _)| file.priority)
+                    .max()
+                    .unwrap_or(FilePriority::Skip)
+            })
+            .collect()
+    }
+
+    ///False for a piece that spills over into a skipped file, we only kept part of it
+    pub fn is_whole_on_disk(&self, index: u32) -> bool {
+        self.spans(index as u64 * self.piece_length, self.piece_length)
+            .iter()
+            .all(|(file, _, _)| file.priority != FilePriority::Skip)
+    }
+
+    ///The slices of each file a byte range of the torrent touches, as (file, offset in file, length)
+    fn spans(&self, start: u64, length: u64) -> Vec<(&StorageFile, u64, u64)> {
+        let end = start + length;
+        self.files
+            .iter()
+            .filter(|f| f.offset < end && f.offset + f.length > start)
+            .map(|f| {
+                let from = start.max(f.offset);
+                let to = end.min(f.offset + f.length);
+                (f, from - f.offset, to - from)
+            })
+            .collect()
+    }
+
+    ///Get the disk ready before the torrent starts. `size` is the whole torrent, from
+    ///`parser::get_size`, skipped files and whatever is already on disk don't count against it
+    pub fn allocate(&self, mode: AllocationMode, size: u64) -> Result<()> {
+        let location = self.lock_location()?;
+        self.check_free_space(&location, size)?;
+        if mode == AllocationMode::Sparse {
+            return Ok(());
+        }
+        for file in self.wanted_files() {
+            let path = location.path(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            if handle.allocated_size()? < file.length {
+                handle.allocate(file.length)?;
+                info!("Allocated {} bytes for {}", file.length, path.display());
+            }
+        }
+        Ok(())
+    }
+
+    fn check_free_space(&self, location: &Location, size: u64) -> Result<()> {
+        let skipped: u64 = self
+            .files
+            .iter()
+            .filter(|f| f.priority == FilePriority::Skip)
+            .map(|f| f.length)
+            .sum();
+        //sparse files take up less than their length, so go by the blocks really in use
+        let on_disk: u64 = self
+            .wanted_files()
+            .filter_map(|f| fs::File::open(location.path(f)).ok())
+            .filter_map(|f| f.allocated_size().ok())
+            .sum();
+        let needed = size.saturating_sub(skipped).saturating_sub(on_disk);
+        let dir = self.existing_dir(location);
+        let available = fs2::available_space(&dir)?;
+        if available < needed {
+            return Err(eyre!(
+                "Not enough space in {}: {} more bytes needed ({needed} to go, {available} free)",
+                dir.display(),
+                needed - available,
+            ));
+        }
+        Ok(())
+    }
+
+    fn wanted_files(&self) -> impl Iterator<Item = &StorageFile> {
+        self.files
+            .iter()
+            .filter(|f| f.priority != FilePriority::Skip)
+    }
+
+    ///Closest directory to the files that is there already, the rest gets created on the first write
+    fn existing_dir(&self, location: &Location) -> PathBuf {
+        self.files
+            .first()
+            .map(|f| location.path(f))
+            .and_then(|path| {
+                path.ancestors()
+                    .skip(1)
+                    .find(|p| p.is_dir())
+                    .map(Path::to_path_buf)
+            })
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    ///Write a run of bytes starting anywhere in the torrent, a run of pieces in one go
+    pub fn write(&self, start: u64, data: &[u8]) -> Result<()> {
+        let location = self.lock_location()?;
+        let mut written = 0usize;
+        for (file, file_offset, length) in self.spans(start, data.len() as u64) {
+            if file.priority == FilePriority::Skip {
+                written += length as usize;
+                continue;
+            }
+            let path = location.path(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.write_all(&data[written..written + length as usize])?;
+            written += length as usize;
+        }
+        Ok(())
+    }
+
+    ///Every piece is piece_length long, apart from the last which gets whatever is left
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let total: u64 = self.files.iter().map(|f| f.length).sum();
+        let start = index as u64 * self.piece_length;
+        self.piece_length.min(total.saturating_sub(start))
+    }
+
+    ///Read a range of a piece back from disk
+    pub fn read(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let location = self.lock_location()?;
+        let start = index as u64 * self.piece_length + begin as u64;
+        let mut data = Vec::with_capacity(length as usize);
+        for (file, file_offset, span_length) in self.spans(start, length as u64) {
+            let mut handle = fs::File::open(location.path(file))?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            let mut buf = vec![0u8; span_length as usize];
+            handle.read_exact(&mut buf)?;
+            data.extend_from_slice(&buf);
+        }
+        Ok(data)
+    }
+}
+
+///One file of a move, done in steps so the whole move can be taken back
+enum StagedMove {
+    ///Renamed already, renaming it back undoes it
+    Renamed { from: PathBuf, to: PathBuf },
+    ///Copied next to its new home under a temporary name, the original is untouched until finish
+    Copied {
+        from: PathBuf,
+        temporary: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl StagedMove {
+    ///Rename if we can, copy under a temporary name if we are changing filesystems
+    fn start(from: &Path, to: &Path) -> Result<Self> {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::rename(from, to).is_ok() {
+            return Ok(StagedMove::Renamed {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+            });
+        }
+        let mut temporary = to.as_os_str().to_owned();
+        temporary.push(".moving");
+        let temporary = PathBuf::from(temporary);
+        if let Err(e) = fs::copy(from, &temporary) {
+            let _ = fs::remove_file(&temporary);
+            return Err(e.into());
+        }
+        debug!("Copied {} over to {}", from.display(), temporary.display());
+        Ok(StagedMove::Copied {
+            from: from.to_path_buf(),
+            temporary,
+            to: to.to_path_buf(),
+        })
+    }
+
+    fn from(&self) -> &Path {
+        match self {
+            StagedMove::Renamed { from, .. } | StagedMove::Copied { from, .. } => from,
+        }
+    }
+
+    ///Give a copy its real name
+    fn place(&self) -> Result<()> {
+        if let StagedMove::Copied { temporary, to, .. } = self {
+            fs::rename(temporary, to)?;
+        }
+        Ok(())
+    }
+
+    fn unplace(&self) {
+        if let StagedMove::Copied { temporary, to, .. } = self {
+            if let Err(e) = fs::rename(to, temporary) {
+                warn!("Could not take back {}: {e}", to.display());
+            }
+        }
+    }
+
+    ///Put things back as they were before start
+    fn undo(&self) {
+        let result = match self {
+            StagedMove::Renamed { from, to } => fs::rename(to, from),
+            StagedMove::Copied { temporary, .. } => fs::remove_file(temporary),
+        };
+        if let Err(e) = result {
+            warn!("Could not undo the move of {}: {e}", self.from().display());
+        }
+    }
+
+    ///Drop the original of a copy. Failing that only leaves a stray file behind
+    fn finish(&self) {
+        if let StagedMove::Copied { from, .. } = self {
+            if let Err(e) = fs::remove_file(from) {
+                warn!("Could not remove {} after copying it: {e}", from.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse_torrent_file;
+
+    #[test]
+    fn test_spans_cross_file_boundary() {
+        let torrent = parse_torrent_file("Fedora-KDE-Live-x86_64-40.torrent").unwrap();
+        let storage = Storage::new(
+            &torrent.torrent_file,
+            Location::complete(Path::new("/tmp/ox")),
+        );
+        assert_eq!(2, storage.files.len());
+        let first = &storage.files[0];
+        let spans = storage.spans(first.length - 10, 20);
+        assert_eq!(2, spans.len());
+        assert_eq!((first.length - 10, 10), (spans[0].1, spans[0].2));
+        assert_eq!((0, 10), (spans[1].1, spans[1].2));
+    }
+
+    #[test]
+    fn test_write_and_read_piece() {
+        let dir = std::env::temp_dir().join("torrentox_storage_test");
+        let _ = fs::remove_dir_all(&dir);
+        let storage = Storage {
+            files: vec![
+                StorageFile {
+                    path: dir.join("a"),
+                    length: 6,
+                    offset: 0,
+                    priority: FilePriority::Normal,
+                    sequential: false,
+                },
+                StorageFile {
+                    path: dir.join("b"),
+                    length: 6,
+                    offset: 6,
+                    priority: FilePriority::Normal,
+                    sequential: false,
+                },
+            ],
+            piece_length: 4,
+            ..Storage::default()
+        };
+        storage.write(storage.piece_length, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(vec![3, 4], fs::read(dir.join("b")).unwrap());
+        assert_eq!(vec![2, 3], storage.read(1, 1, 2).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skipped_files_stay_off_disk() {
+        let dir = std::env::temp_dir().join("torrentox_skip_test");
+        let _ = fs::remove_dir_all(&dir);
+        let file = |name: &str, offset: u64| StorageFile {
+            path: dir.join(name),
+            length: 6,
+            offset,
+            priority: FilePriority::Normal,
+            sequential: false,
+        };
+        let mut storage = Storage {
+            files: vec![file("a", 0), file("b", 6), file("c", 12)],
+            piece_length: 4,
+            ..Storage::default()
+        };
+        storage.set_priorities(&[FilePriority::Skip, FilePriority::High]);
+        //piece 0 is all a, piece 1 straddles a and b
+        assert_eq!(
+            vec![
+                FilePriority::Skip,
+                FilePriority::High,
+                FilePriority::High,
+                FilePriority::Normal,
+                FilePriority::Normal,
+            ],
+            storage.piece_priorities(5)
+        );
+        assert!(!storage.is_whole_on_disk(1));
+        assert!(storage.is_whole_on_disk(2));
+        storage.set_sequential(&[true, false, true]);
+        assert_eq!(bits![u8, Msb0; 0, 0, 0, 1, 1], storage.sequential_pieces(5));
+
+        storage.write(storage.piece_length, &[1, 2, 3, 4]).unwrap();
+        assert!(!dir.join("a").exists());
+        assert_eq!(vec![3, 4], fs::read(dir.join("b")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_full_allocation() {
+        let dir = std::env::temp_dir().join("torrentox_allocation_test");
+        let _ = fs::remove_dir_all(&dir);
+        let file = |name: &str, offset: u64| StorageFile {
+            path: dir.join("torrent").join(name),
+            length: 5000,
+            offset,
+            priority: FilePriority::Normal,
+            sequential: false,
+        };
+        let mut storage = Storage {
+            files: vec![file("a", 0), file("b", 5000)],
+            piece_length: 4096,
+            ..Storage::default()
+        };
+        storage.set_priorities(&[FilePriority::Normal, FilePriority::Skip]);
+        storage.allocate(AllocationMode::Sparse, 10000).unwrap();
+        assert!(!dir.exists());
+
+        storage.allocate(AllocationMode::Full, 10000).unwrap();
+        assert_eq!(5000, fs::metadata(dir.join("torrent/a")).unwrap().len());
+        assert!(!dir.join("torrent/b").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_when_complete() {
+        let dir = std::env::temp_dir().join("torrentox_move_test");
+        let _ = fs::remove_dir_all(&dir);
+        let incomplete = Location::incomplete(&dir.join("incomplete"), true);
+        let file = |name: &str, offset: u64| StorageFile {
+            path: PathBuf::from("torrent").join(name),
+            length: 4,
+            offset,
+            priority: FilePriority::Normal,
+            sequential: false,
+        };
+        let storage = Storage {
+            files: vec![file("a", 0), file("b", 4)],
+            piece_length: 4,
+            location: RwLock::new(incomplete),
+        };
+        storage.write(0, &[1, 2, 3, 4]).unwrap();
+        storage.write(storage.piece_length, &[5, 6, 7, 8]).unwrap();
+        assert!(dir.join("incomplete/torrent/a.part").exists());
+
+        let complete = Location::complete(&dir.join("complete"));
+        storage.move_to(complete.clone()).unwrap();
+        assert_eq!(complete, storage.location());
+        assert_eq!(
+            vec![5, 6, 7, 8],
+            fs::read(dir.join("complete/torrent/b")).unwrap()
+        );
+        assert!(!dir.join("incomplete/torrent").exists());
+        assert!(dir.join("incomplete").exists());
+        assert_eq!(vec![2, 3], storage.read(0, 1, 2).unwrap());
+        {
+            let _moving = storage.location.write().unwrap();
+            assert!(storage.read(0, 1, 2).is_err());
+            assert!(storage.write(0, &[1, 2, 3, 4]).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_not_enough_space() {
+        let dir = std::env::temp_dir().join("torrentox_no_space_test");
+        let storage = Storage {
+            files: vec![StorageFile {
+                path: dir.join("huge"),
+                length: u64::MAX,
+                offset: 0,
+                priority: FilePriority::Normal,
+                sequential: false,
+            }],
+            piece_length: 4096,
+            ..Storage::default()
+        };
+        let err = storage
+            .allocate(AllocationMode::Full, u64::MAX)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&std::env::temp_dir().display().to_string()));
+        assert!(err.contains("more bytes needed"));
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,421 @@
+use color_eyre::eyre::{eyre, Result};
+use log::{debug, info};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::connection_manager::ActiveTorrent;
+use crate::model::{info_hash_hex, FilePriority};
+use crate::storage::StorageFile;
+
+///Most header lines we put up with before giving up on a request
+const MAX_HEADERS: usize = 64;
+
+///What we care about in an HTTP request
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub range: Option<String>,
+}
+
+///Serves the files of the torrents over plain HTTP, as they download.
+///`/` lists everything, `/<info hash>/<file number>` is the file itself, Range requests and all.
+///The torrent's name works in place of the info hash, unless two torrents share it
+pub struct StreamServer {
+    torrents: Vec<Arc<ActiveTorrent>>,
+}
+
+impl StreamServer {
+    pub fn new(torrents: Vec<Arc<ActiveTorrent>>) -> Self {
+        Self { torrents }
+    }
+
+    ///Accept connections forever, one task per connection
+    pub async fn run(self, listener: TcpListener) -> Result<()> {
+        info!("Streaming on http://{}", listener.local_addr()?);
+        let server = Arc::new(self);
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(socket).await {
+                    debug!("Done streaming to {addr}: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, socket: TcpStream) -> Result<()> {
+        let mut socket = BufReader::new(socket);
+        let request = read_request(&mut socket).await?;
+        debug!("Stream request {:?}", request);
+        let socket = socket.get_mut();
+        if request.method != "GET" && request.method != "HEAD" {
+            return respond(socket, "405 Method Not Allowed", "Only GET and HEAD\n").await;
+        }
+        if request.path == "/" {
+            return respond(socket, "200 OK", &self.index()).await;
+        }
+        let Some((torrent, file_index)) = self.find(&request.path) else {
+            return respond(socket, "404 Not Found", "No such torrent or file\n").await;
+        };
+        let file = &torrent.storage.files[file_index];
+        if file.priority == FilePriority::Skip {
+            return respond(socket, "409 Conflict", "That file is skipped\n").await;
+        }
+        if torrent.is_stopped() && !torrent.is_complete() {
+            //queued or paused, whatever is missing is not coming any time soon
+            return respond(
+                socket,
+                "503 Service Unavailable",
+                "Torrent is not running\n",
+            )
+            .await;
+        }
+        let (status, start, end) = match &request.range {
+            None => ("200 OK", 0, file.length),
+            Some(range) => match parse_range(range, file.length) {
+                Some((start, end)) => ("206 Partial Content", start, end),
+                None => {
+                    let header = format!(
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        file.length
+                    );
+                    socket.write_all(header.as_bytes()).await?;
+                    return Ok(());
+                }
+            },
+        };
+        let mut header = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+            content_type(file),
+            end - start
+        );
+        if request.range.is_some() {
+            header.push_str(&format!(
+                "Content-Range: bytes {start}-{}/{}\r\n",
+                end.saturating_sub(1),
+                file.length
+            ));
+        }
+        header.push_str("\r\n");
+        socket.write_all(header.as_bytes()).await?;
+        if request.method == "HEAD" {
+            return Ok(());
+        }
+        stream_range(socket, &torrent, file.offset + start, file.offset + end).await
+    }
+
+    ///The torrent and the index of the file a path points at
+    fn find(&self, path: &str) -> Option<(Arc<ActiveTorrent>, usize)> {
+        let mut parts = path.trim_start_matches('/').splitn(2, '/');
+        let name = urlencoding::decode(parts.next()?).ok()?;
+        let number: usize = parts.next()?.parse().ok()?;
+        let torrent = match self
+            .torrents
+            .iter()
+            .find(|t| info_hash_hex(&t.info_hash) == name)
+        {
+            Some(torrent) => torrent,
+            None => {
+                let mut named = self.torrents.iter().filter(|t| t.name == name);
+                let torrent = named.next()?;
+                named.next().is_none().then_some(torrent)?
+            }
+        };
+        let index = number.checked_sub(1)?;
+        (index < torrent.storage.files.len()).then(|| (torrent.clone(), index))
+    }
+
+    fn index(&self) -> String {
+        let mut listing = String::new();
+        for torrent in &self.torrents {
+            listing.push_str(&format!("{}\n", torrent.name));
+            for (index, file) in torrent.storage.files.iter().enumerate() {
+                listing.push_str(&format!(
+                    "  /{}/{} {} ({} bytes)\n",
+                    info_hash_hex(&torrent.info_hash),
+                    index + 1,
+                    file.path.display(),
+                    file.length
+                ));
+            }
+        }
+        listing
+    }
+}
+
+///Send the torrent's bytes from start up to end, piece by piece, waiting for each piece to verify
+async fn stream_range(
+    socket: &mut TcpStream,
+    torrent: &ActiveTorrent,
+    start: u64,
+    end: u64,
+) -> Result<()> {
+    let piece_length = torrent.storage.piece_length;
+    let mut position = start;
+    while position < end {
+        let index = (position / piece_length) as u32;
+        wait_for_piece(torrent, index).await?;
+        let piece_end = (index as u64 + 1) * piece_length;
+        let length = piece_end.min(end) - position;
+        let begin = position - index as u64 * piece_length;
+        let data = torrent
+            .disk
+            .read(index, begin as u32, length as u32)
+            .await?;
+        socket.write_all(&data).await?;
+        position += length;
+    }
+    Ok(())
+}
+
+///Block until we have the piece, pushing it, and the readahead after it, to the front of the line
+async fn wait_for_piece(torrent: &ActiveTorrent, index: u32) -> Result<()> {
+    let mut stopped = torrent.stopped.subscribe();
+    loop {
+        let verified = torrent.verified.notified();
+        tokio::pin!(verified);
+        //registered before we look, so a piece verified in between still wakes us
+        verified.as_mut().enable();
+        {
+            let mut download = torrent
+                .download
+                .lock()
+                .map_err(|e| eyre!("Shared download state poisoned: {}", e))?;
+            if download.have.get(index as usize).is_some_and(|b| *b) {
+                return Ok(());
+            }
+            let readahead = download.readahead as u32;
+            let num_pieces = download.pieces.len() as u32;
+            download.make_urgent(index..(index + readahead).min(num_pieces));
+        }
+        if torrent.is_stopped() {
+            return Err(eyre!(
+                "{} is not running, piece {index} is not coming",
+                torrent.name
+            ));
+        }
+        tokio::select! {
+            _ = verified => {}
+            _ = stopped.wait_for(|s| *s) => {}
+        }
+    }
+}
+
+async fn read_request(socket: &mut BufReader<TcpStream>) -> Result<Request> {
+    let mut line = String::new();
+    socket.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(eyre!("Bad request line {line:?}"));
+    };
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        range: None,
+    };
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if socket.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            return Ok(request);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                request.range = Some(value.trim().to_owned());
+            }
+        }
+    }
+    Err(eyre!("Too many headers"))
+}
+
+///A single `bytes=` range, as [start, end). None if we cannot, or will not, satisfy it
+pub fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = header.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        //one range at a time, multipart responses are more than a media player needs
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size)
+        }
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.saturating_add(1).min(size))
+        }
+    };
+    (start < end).then_some((start, end))
+}
+
+fn content_type(file: &StorageFile) -> &'static str {
+    let extension = file
+        .path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" | "nfo" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disk::{DiskPool, DiskSettings, TorrentDisk};
+    use crate::model::{PieceMetadata, TransferStats};
+    use crate::peer_cache::PeerLog;
+    use crate::piece::DownloadState;
+    use crate::rate_limit::TransferLimiter;
+    use crate::storage::Storage;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::{watch, Notify};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Some((0, 100)), parse_range("bytes=0-", 100));
+        assert_eq!(Some((10, 20)), parse_range("bytes=10-19", 100));
+        assert_eq!(Some((90, 100)), parse_range("bytes=-10", 100));
+        //an end past the file is cut short
+        assert_eq!(Some((50, 100)), parse_range("bytes=50-1000", 100));
+        assert_eq!(
+            Some((0, 100)),
+            parse_range("bytes=0-18446744073709551615", 100)
+        );
+        assert_eq!(None, parse_range("bytes=100-", 100));
+        assert_eq!(None, parse_range("bytes=0-1,5-6", 100));
+        assert_eq!(None, parse_range("items=0-1", 100));
+    }
+
+    ///Two files of 6 bytes, in pieces of 4, none of them downloaded yet
+    fn streaming_torrent(dir: &std::path::Path) -> Arc<ActiveTorrent> {
+        let file = |name: &str, offset: u64| StorageFile {
+            path: dir.join(name),
+            length: 6,
+            offset,
+            priority: FilePriority::Normal,
+            sequential: false,
+        };
+        let pieces = (0..3)
+            .map(|index| PieceMetadata {
+                index,
+                length: 4,
+                sha1_hash: [0u8; 20],
+            })
+            .collect();
+        let storage = Arc::new(Storage {
+            files: vec![file("a.mkv", 0), file("b.mkv", 6)],
+            piece_length: 4,
+            ..Storage::default()
+        });
+        Arc::new(ActiveTorrent {
+            name: "movie night".to_owned(),
+            announce_url: "http://127.0.0.1/announce".to_owned(),
+            info_hash: [1u8; 20],
+            peer_id: [2u8; 20],
+            key: "key".to_owned(),
+            peer_log: PeerLog::default(),
+            size: 12,
+            download: Arc::new(Mutex::new(DownloadState::new(pieces))),
+            disk: TorrentDisk::new(
+                storage.clone(),
+                Arc::new(DiskPool::new(&DiskSettings::default()).unwrap()),
+            ),
+            storage,
+            download_dir: PathBuf::new(),
+            rate_limits: Arc::new(TransferLimiter::unlimited()),
+            stats: TransferStats::new(0, 0, Duration::ZERO),
+            stopped: watch::Sender::new(false),
+            verified: Notify::new(),
+        })
+    }
+
+    fn verify(torrent: &ActiveTorrent, index: u32, data: &[u8]) {
+        torrent
+            .storage
+            .write(index as u64 * torrent.storage.piece_length, data)
+            .unwrap();
+        torrent
+            .download
+            .lock()
+            .unwrap()
+            .have
+            .set(index as usize, true);
+        torrent.verified.notify_waiters();
+    }
+
+    #[tokio::test]
+    async fn test_range_waits_for_pieces() {
+        let dir = std::env::temp_dir().join("torrentox_stream_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let torrent = streaming_torrent(&dir);
+        verify(&torrent, 1, &[4, 5, 6, 7]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(StreamServer::new(vec![torrent.clone()]).run(listener));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        //bytes 2 to 5 of b.mkv are bytes 8 to 11 of the torrent, all in piece 2
+        client
+            .write_all(b"GET /movie%20night/2 HTTP/1.1\r\nRange: bytes=2-5\r\n\r\n")
+            .await
+            .unwrap();
+        //nothing comes until piece 2 is in, and by then it is urgent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(torrent.download.lock().unwrap().is_urgent(2));
+        verify(&torrent, 2, &[8, 9, 10, 11]);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let (head, body) = response.split_at(response.len() - 4);
+        let head = String::from_utf8_lossy(head);
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content"));
+        assert!(head.contains("Content-Range: bytes 2-5/6"));
+        assert!(head.contains("Content-Type: video/x-matroska"));
+        assert_eq!(&[8, 9, 10, 11], body);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_file_is_not_found() {
+        let dir = std::env::temp_dir().join("torrentox_stream_404_test");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(StreamServer::new(vec![streaming_torrent(&dir)]).run(listener));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /movie%20night/3 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,83 @@
+///How a tracker of a torrent has been doing, as of its last announce
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackerStatus {
+    pub url: String,
+    ///Unix seconds, None if we never announced to it
+    pub last_announce: Option<i64>,
+    ///What went wrong last time, None if it answered
+    pub last_error: Option<String>,
+    ///Peers it gave us last time
+    pub peers: Option<u32>,
+    ///When it wants to hear from us again, None once we have left the swarm
+    pub next_announce: Option<i64>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+impl TrackerStatus {
+    ///One line for the CLI and the status screen, times relative to now
+    pub fn describe(&self, now: i64) -> String {
+        let Some(last_announce) = self.last_announce else {
+            return format!("{}: never announced", self.url);
+        };
+        let result = match &self.last_error {
+            Some(error) => format!("error: {error}"),
+            None => format!("ok, {} peers", self.peers.unwrap_or(0)),
+        };
+        let swarm = match (self.seeders, self.leechers) {
+            (Some(seeders), Some(leechers)) => format!(", {seeders} seeders, {leechers} leechers"),
+            _ => String::new(),
+        };
+        let next = match self.next_announce {
+            Some(next) => format!(", next in {}", ago_or_in(next - now)),
+            None => String::new(),
+        };
+        format!(
+            "{}: {result}{swarm}, {} ago{next}",
+            self.url,
+            ago_or_in(now - last_announce)
+        )
+    }
+}
+
+fn ago_or_in(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    if seconds < 120 {
+        format!("{seconds}s")
+    } else {
+        format!("{}m", seconds / 60)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let mut status = TrackerStatus {
+            url: "http://tracker/announce".to_owned(),
+            ..TrackerStatus::default()
+        };
+        assert_eq!(
+            "http://tracker/announce: never announced",
+            status.describe(0)
+        );
+        status.last_announce = Some(100);
+        status.peers = Some(30);
+        status.seeders = Some(10);
+        status.leechers = Some(2);
+        status.next_announce = Some(1900);
+        assert_eq!(
+            "http://tracker/announce: ok, 30 peers, 10 seeders, 2 leechers, 5s ago, next in 29m",
+            status.describe(105)
+        );
+        status.last_error = Some("Timed out".to_owned());
+        status.next_announce = None;
+        status.seeders = None;
+        assert_eq!(
+            "http://tracker/announce: error: Timed out, 5s ago",
+            status.describe(105)
+        );
+    }
+}